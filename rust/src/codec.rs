@@ -0,0 +1,139 @@
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use anyhow::{Result, Error};
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+use zstd::stream::{decode_all, Decoder, Encoder};
+use zstd::DEFAULT_COMPRESSION_LEVEL;
+
+
+/*==============================================================
+=                          ZSTD CODEC                          =
+==============================================================*/
+
+/// A zstd dictionary, prepared once and then shared by every compression thread
+struct ZstdDict {
+    id: Option<u32>,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+
+/// How output bytes get (de)compressed: plain zstd, or zstd with a trained dictionary (--zstd-dict).
+/// Dictionary-compressed outputs need the same dictionary to decompress, e.g. `zstd -D <dict> -d`.
+/// With checksum, every frame carries a checksum of its content (--zstd-checksum) that decompressors verify.
+/// With workers > 0, big frames are compressed by that many zstd worker threads (--zstd-workers).
+/// Or, with gzip (--gzip-per-record), gzip members take the place of zstd frames
+pub(crate) struct Codec {
+    dict: Option<ZstdDict>,
+    checksum: bool,
+    workers: u32,
+    gzip: bool,
+}
+
+
+// Below this, spinning up zstd's worker threads for a frame costs more than it saves
+const MULTITHREAD_MIN_BYTES: usize = 4 << 20;
+
+impl Codec {
+    pub fn load(dict_path: Option<&Path>, checksum: bool, workers: u32) -> Result<Self, Error> {
+        let dict = match dict_path {
+            Some(path) => {
+                let bytes = std::fs::read(path)?;
+                Some(ZstdDict {
+                    id: zstd::zstd_safe::get_dict_id(&bytes).map(|id| id.get()),
+                    encoder: EncoderDictionary::copy(&bytes, DEFAULT_COMPRESSION_LEVEL),
+                    decoder: DecoderDictionary::copy(&bytes),
+                })
+            },
+            None => None,
+        };
+        Ok(Codec { dict, checksum, workers, gzip: false })
+    }
+
+    pub fn gzip() -> Self {
+        Codec { dict: None, checksum: false, workers: 0, gzip: true }
+    }
+
+    /// What outputs made with this codec are named with
+    pub fn extension(&self) -> &'static str {
+        if self.gzip { "jsonl.gz" } else { "jsonl.zstd" }
+    }
+
+    /// The id zstd stamps into every frame made with our dictionary (None without one, or for raw-content dicts)
+    pub fn dict_id(&self) -> Option<u32> {
+        self.dict.as_ref().and_then(|dict| dict.id)
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.gzip {
+            // One member. Concatenated members are a valid (multi-member) gzip stream, like concatenated zstd frames
+            let mut encoder = GzEncoder::new(Vec::with_capacity(bytes.len() / 2), Compression::default());
+            encoder.write_all(bytes)?;
+            return Ok(encoder.finish()?);
+        }
+        // One frame, with the content size in its header since we know it up front
+        let mut encoder = self.plain_encoder(Vec::with_capacity(bytes.len() / 2))?;
+        if self.workers > 0 && bytes.len() >= MULTITHREAD_MIN_BYTES {
+            encoder.multithread(self.workers)?;
+        }
+        encoder.set_pledged_src_size(Some(bytes.len() as u64))?;
+        encoder.write_all(bytes)?;
+        Ok(encoder.finish()?)
+    }
+
+    pub fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.gzip {
+            let mut decompressed = Vec::new();
+            MultiGzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+            return Ok(decompressed);
+        }
+        match &self.dict {
+            None => Ok(decode_all(bytes)?),
+            Some(dict) => {
+                let mut decompressed = Vec::new();
+                Decoder::with_prepared_dictionary(bytes, &dict.decoder)?.read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            },
+        }
+    }
+
+    pub fn stream_decoder<'a, R: Read + 'a>(&'a self, reader: R) -> Result<Box<dyn Read + 'a>, Error> {
+        if self.gzip {
+            return Ok(Box::new(MultiGzDecoder::new(BufReader::new(reader))));
+        }
+        match &self.dict {
+            None => Ok(Box::new(Decoder::new(reader)?)),
+            Some(dict) => Ok(Box::new(Decoder::with_prepared_dictionary(BufReader::new(reader), &dict.decoder)?)),
+        }
+    }
+
+    pub fn stream_encoder<W: Write>(&self, writer: W) -> Result<Encoder<'_, W>, Error> {
+        // For --single-file, where the one frame is as big as the whole output
+        if self.gzip {
+            return Err(Error::msg("--single-file can't stream gzip members"));
+        }
+        let mut encoder = self.plain_encoder(writer)?;
+        if self.workers > 0 {
+            encoder.multithread(self.workers)?;
+        }
+        Ok(encoder)
+    }
+
+    fn plain_encoder<W: Write>(&self, writer: W) -> Result<Encoder<'_, W>, Error> {
+        let mut encoder = match &self.dict {
+            None => Encoder::new(writer, DEFAULT_COMPRESSION_LEVEL)?,
+            Some(dict) => Encoder::with_prepared_dictionary(writer, &dict.encoder)?,
+        };
+        encoder.include_checksum(self.checksum)?;
+        Ok(encoder)
+    }
+}
+
+
+pub(crate) fn train_dict(samples: &[Vec<u8>], max_dict_bytes: usize) -> Result<Vec<u8>, Error> {
+    // zstd's own trainer (ZDICT). Wants lots of small samples; errors if there's too little data
+    Ok(zstd::dict::from_samples(samples, max_dict_bytes)?)
+}
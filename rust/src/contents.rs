@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+use anyhow::{Result, Error};
+use regex::Regex;
+
+
+/*==============================================================
+=                      TRUNCATING CONTENTS                     =
+==============================================================*/
+
+/// Parsed form of `--truncate-contents` and `--min-contents-length`: a number with a unit suffix, e.g. `100kb`
+/// or `50000chars`. Byte units are b/kb/mb (powers of 1024)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContentsLength {
+    Bytes(usize),
+    Chars(usize),
+}
+
+impl FromStr for ContentsLength {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let s = s.trim().to_lowercase();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let bad_length = || Error::msg(format!("Bad contents length {:?}, expected e.g. 100kb or 50000chars", s));
+        let number: usize = number.parse().map_err(|_| bad_length())?;
+        match unit {
+            "b" => Ok(ContentsLength::Bytes(number)),
+            "kb" => Ok(ContentsLength::Bytes(number.checked_mul(1024).ok_or_else(bad_length)?)),
+            "mb" => Ok(ContentsLength::Bytes(number.checked_mul(1024 * 1024).ok_or_else(bad_length)?)),
+            "chars" => Ok(ContentsLength::Chars(number)),
+            _ => Err(Error::msg(format!("Unknown contents length unit {:?}, expected one of b, kb, mb, chars", unit))),
+        }
+    }
+}
+
+
+impl ContentsLength {
+    /// Whether contents is shorter than this, in this length's unit (only counting chars up to the length)
+    pub fn longer_than(&self, contents: &str) -> bool {
+        match *self {
+            ContentsLength::Bytes(bytes) => contents.len() < bytes,
+            ContentsLength::Chars(chars) => contents.chars().take(chars).count() < chars,
+        }
+    }
+}
+
+
+pub(crate) fn truncate_contents(contents: &mut String, limit: ContentsLength) {
+    // Cuts contents down to at most limit, always on a char boundary (so a byte limit may keep a few bytes less)
+    let cut = match limit {
+        ContentsLength::Bytes(max_bytes) => {
+            if contents.len() <= max_bytes {
+                return;
+            }
+            (0..=max_bytes).rev().find(|i| contents.is_char_boundary(*i)).unwrap_or(0)
+        },
+        ContentsLength::Chars(max_chars) => match contents.char_indices().nth(max_chars) {
+            Some((cut, _)) => cut,
+            None => return,
+        },
+    };
+    contents.truncate(cut);
+}
+
+
+
+/*==============================================================
+=                      REPLACING CONTENTS                      =
+==============================================================*/
+
+/// Parsed form of `--contents-replace`: `<regex>=><replacement>`, split at the first `=>`. The replacement can
+/// refer to capture groups as `$1` or `${name}`, like `Regex::replace_all`
+#[derive(Debug, Clone)]
+pub(crate) struct ContentsReplace {
+    regex: Regex,
+    replacement: String,
+}
+
+impl FromStr for ContentsReplace {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (pattern, replacement) = s.split_once("=>")
+            .ok_or_else(|| Error::msg(format!("Bad contents replacement {:?}, expected <regex>=><replacement>", s)))?;
+        let regex = Regex::new(pattern)
+            .map_err(|e| Error::msg(format!("Bad contents replacement regex {:?}: {}", pattern, e)))?;
+        Ok(ContentsReplace { regex, replacement: replacement.to_string() })
+    }
+}
+
+
+pub(crate) fn replace_contents(contents: String, replacements: &[ContentsReplace]) -> (String, bool) {
+    // Applies each replacement in turn, returning the new contents and whether they differ from the old
+    let mut replaced = Cow::Borrowed(contents.as_str());
+    for replacement in replacements {
+        if let Cow::Owned(new) = replacement.regex.replace_all(&replaced, replacement.replacement.as_str()) {
+            replaced = Cow::Owned(new);
+        }
+    }
+    match replaced {
+        Cow::Owned(new) if new != contents => (new, true),
+        _ => (contents, false),
+    }
+}
+
+
+
+/*==============================================================
+=                       BINARY DETECTION                       =
+==============================================================*/
+
+const BINARY_SNIFF_BYTES: usize = 8000; // same window git uses to decide if a file is binary
+const BINARY_CONTROL_FRACTION: f64 = 0.3;
+
+
+pub(crate) fn looks_binary(contents: &str) -> bool {
+    /* Text-vs-binary heuristic over the first BINARY_SNIFF_BYTES of the decoded contents:
+    binary if there's a NUL, or if more than BINARY_CONTROL_FRACTION of the chars are control chars
+    (other than the usual whitespace ones)
+    */
+    let mut end = contents.len().min(BINARY_SNIFF_BYTES);
+    while !contents.is_char_boundary(end) {
+        end -= 1;
+    }
+    let sniff = &contents[..end];
+    let mut num_chars = 0;
+    let mut num_control = 0;
+    for c in sniff.chars() {
+        if c == '\0' {
+            return true;
+        }
+        num_chars += 1;
+        if c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x0c' | '\x0b') {
+            num_control += 1;
+        }
+    }
+    num_chars > 0 && num_control as f64 / num_chars as f64 > BINARY_CONTROL_FRACTION
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_truncation_backs_off_to_a_char_boundary() {
+        // "€" is 3 bytes and "😀" 4, so cutting at 2 or 5 bytes lands inside one of them
+        let mut contents = "a€😀b".to_string();
+        truncate_contents(&mut contents, ContentsLength::Bytes(2));
+        assert_eq!(contents, "a");
+        let mut contents = "a€😀b".to_string();
+        truncate_contents(&mut contents, ContentsLength::Bytes(5));
+        assert_eq!(contents, "a€");
+        let mut contents = "a€😀b".to_string();
+        truncate_contents(&mut contents, ContentsLength::Bytes(8));
+        assert_eq!(contents, "a€😀");
+    }
+
+    #[test]
+    fn char_truncation_counts_chars() {
+        let mut contents = "a€😀b".to_string();
+        truncate_contents(&mut contents, ContentsLength::Chars(3));
+        assert_eq!(contents, "a€😀");
+        truncate_contents(&mut contents, ContentsLength::Chars(3));
+        assert_eq!(contents, "a€😀");
+    }
+
+    #[test]
+    fn contents_lengths_parse_and_reject_overflow() {
+        assert_eq!("100kb".parse::<ContentsLength>().unwrap(), ContentsLength::Bytes(100 * 1024));
+        assert_eq!("2MB".parse::<ContentsLength>().unwrap(), ContentsLength::Bytes(2 * 1024 * 1024));
+        assert_eq!("50000chars".parse::<ContentsLength>().unwrap(), ContentsLength::Chars(50000));
+        let too_big = format!("{}mb", usize::MAX / 1024);
+        let err = too_big.parse::<ContentsLength>().unwrap_err();
+        assert!(err.to_string().contains("Bad contents length"), "{}", err);
+        assert!(format!("{}kb", usize::MAX).parse::<ContentsLength>().is_err());
+    }
+}
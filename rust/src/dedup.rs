@@ -0,0 +1,125 @@
+use clap::ValueEnum;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Result, Error};
+use twox_hash::XxHash3_64;
+
+
+/*==============================================================
+=                        DEDUPLICATING ROWS                    =
+==============================================================*/
+
+/// What --dedup-by considers two rows the same by
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DedupBy {
+    BlobId,
+    Contents,
+}
+
+
+pub(crate) fn dedup_key(bytes: &[u8]) -> u64 {
+    // Unseeded (not --seed'ed), so the keys in a --dedup-state file mean the same thing to every invocation
+    XxHash3_64::oneshot(bytes)
+}
+
+
+/// The 64-bit xxh3 keys of every row output so far. This is an exact set of hashes rather than a bloom filter:
+/// 8 bytes a key on disk (a few times that in memory), and a false positive needs two different keys to collide
+/// in all 64 bits, which for n keys happens with probability about n^2 / 2^65 (~3% for a billion rows, and then
+/// only one row is wrongly dropped). A bloom filter would cut memory to ~1-2 bytes a key, but drop ~1% of rows
+/// as false positives at that size.
+///
+/// Keys are staged while a file is written and only committed once all of its outputs are, so the rows of a
+/// file that fails (and so is never written) don't count as seen by the files after it.
+///
+/// The --dedup-state file is the keys as raw little-endian u64s, with only the committed keys new to this run
+/// appended when it finishes, so a batch of invocations can share one file. A torn last record (from a killed
+/// append) is ignored on load
+pub(crate) struct DedupSet {
+    seen: HashSet<u64>,
+    new_keys: Vec<u64>, // committed, but not yet appended to the --dedup-state file
+    staged: HashSet<u64>,
+    staged_keys: Vec<u64>, // staged, in the order they were seen
+}
+
+impl DedupSet {
+    pub fn load(state_path: Option<&Path>) -> Result<Self, Error> {
+        let mut seen = HashSet::new();
+        if let Some(state_path) = state_path.filter(|path| path.exists()) {
+            let bytes = std::fs::read(state_path)?;
+            seen.reserve(bytes.len() / 8);
+            seen.extend(bytes.chunks_exact(8).map(|key| u64::from_le_bytes(key.try_into().unwrap())));
+        }
+        Ok(DedupSet { seen, new_keys: Vec::new(), staged: HashSet::new(), staged_keys: Vec::new() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// True the first time key is seen (committed or staged), false for every duplicate after that
+    pub fn insert(&mut self, key: u64) -> bool {
+        let is_new = !self.seen.contains(&key) && self.staged.insert(key);
+        if is_new {
+            self.staged_keys.push(key);
+        }
+        is_new
+    }
+
+    /// Once a file is done: commit its staged keys if its outputs were all written, otherwise forget them
+    pub fn settle(&mut self, file_written: bool) {
+        self.staged.clear();
+        let staged_keys = std::mem::take(&mut self.staged_keys);
+        if file_written {
+            self.seen.extend(&staged_keys);
+            self.new_keys.extend(staged_keys);
+        }
+    }
+
+    pub fn append_new_keys(&mut self, state_path: &Path) -> Result<usize, Error> {
+        // First drop any torn record, so the appended keys start on a key boundary
+        if let Ok(metadata) = std::fs::metadata(state_path) {
+            let len = metadata.len();
+            if len % 8 != 0 {
+                File::options().write(true).open(state_path)?.set_len(len - len % 8)?;
+            }
+        }
+        let mut state_file = BufWriter::new(OpenOptions::new().create(true).append(true).open(state_path)?);
+        for key in &self.new_keys {
+            state_file.write_all(&key.to_le_bytes())?;
+        }
+        state_file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(std::mem::take(&mut self.new_keys).len())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_written_files_keys_are_committed() {
+        let state_path = std::env::temp_dir().join(format!("packager-test-{}-dedup-state", std::process::id()));
+        let _ = std::fs::remove_file(&state_path);
+        let mut dedup = DedupSet::load(Some(&state_path)).unwrap();
+
+        // A file that fails: its keys dedup its own later rows, then are forgotten
+        assert!(dedup.insert(1));
+        assert!(!dedup.insert(1));
+        dedup.settle(false);
+        assert!(dedup.insert(1));
+        assert!(dedup.insert(2));
+        dedup.settle(true);
+        assert!(!dedup.insert(1));
+        dedup.settle(false);
+
+        assert_eq!(dedup.append_new_keys(&state_path).unwrap(), 2);
+        let reloaded = DedupSet::load(Some(&state_path)).unwrap();
+        assert_eq!(reloaded.len(), 2);
+        std::fs::remove_file(state_path).unwrap();
+    }
+}
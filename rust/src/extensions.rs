@@ -0,0 +1,114 @@
+use serde_json::Value as JsonValue;
+
+
+/*==============================================================
+=                     LANGUAGE BY EXTENSION                    =
+==============================================================*/
+
+/// Built-in table for --extensions-map: file extension (lowercased, without the dot), or whole file name for
+/// extensionless files, to the languages it's written in, named like the-stack-v2's language directories.
+/// The first language listed is the canonical one; the rest are other languages that share the extension
+const EXTENSION_LANGUAGES: &[(&str, &[&str])] = &[
+    ("c", &["C"]),
+    ("h", &["C", "C++", "Objective-C"]),
+    ("cc", &["C++"]),
+    ("cpp", &["C++"]),
+    ("cxx", &["C++"]),
+    ("hh", &["C++"]),
+    ("hpp", &["C++"]),
+    ("hxx", &["C++"]),
+    ("cs", &["C#"]),
+    ("m", &["Objective-C", "MATLAB"]),
+    ("mm", &["Objective-C++"]),
+    ("java", &["Java"]),
+    ("kt", &["Kotlin"]),
+    ("kts", &["Kotlin"]),
+    ("scala", &["Scala"]),
+    ("groovy", &["Groovy"]),
+    ("clj", &["Clojure"]),
+    ("go", &["Go"]),
+    ("rs", &["Rust"]),
+    ("swift", &["Swift"]),
+    ("py", &["Python"]),
+    ("pyi", &["Python"]),
+    ("pyx", &["Cython"]),
+    ("ipynb", &["Jupyter Notebook"]),
+    ("rb", &["Ruby"]),
+    ("php", &["PHP"]),
+    ("pl", &["Perl", "Prolog"]),
+    ("pm", &["Perl"]),
+    ("lua", &["Lua"]),
+    ("r", &["R"]),
+    ("jl", &["Julia"]),
+    ("js", &["JavaScript"]),
+    ("mjs", &["JavaScript"]),
+    ("cjs", &["JavaScript"]),
+    ("jsx", &["JavaScript"]),
+    ("ts", &["TypeScript"]),
+    ("tsx", &["TypeScript", "TSX"]),
+    ("vue", &["Vue"]),
+    ("dart", &["Dart"]),
+    ("hs", &["Haskell"]),
+    ("ml", &["OCaml"]),
+    ("mli", &["OCaml"]),
+    ("fs", &["F#"]),
+    ("ex", &["Elixir"]),
+    ("exs", &["Elixir"]),
+    ("erl", &["Erlang"]),
+    ("elm", &["Elm"]),
+    ("zig", &["Zig"]),
+    ("nim", &["Nim"]),
+    ("d", &["D"]),
+    ("f90", &["Fortran"]),
+    ("f", &["Fortran"]),
+    ("pas", &["Pascal"]),
+    ("vb", &["Visual Basic .NET"]),
+    ("asm", &["Assembly"]),
+    ("s", &["Assembly"]),
+    ("sh", &["Shell"]),
+    ("bash", &["Shell"]),
+    ("zsh", &["Shell"]),
+    ("ps1", &["PowerShell"]),
+    ("bat", &["Batchfile"]),
+    ("sql", &["SQL"]),
+    ("html", &["HTML"]),
+    ("htm", &["HTML"]),
+    ("css", &["CSS"]),
+    ("scss", &["SCSS"]),
+    ("less", &["Less"]),
+    ("json", &["JSON"]),
+    ("yaml", &["YAML"]),
+    ("yml", &["YAML"]),
+    ("toml", &["TOML"]),
+    ("xml", &["XML"]),
+    ("md", &["Markdown"]),
+    ("rst", &["reStructuredText"]),
+    ("tex", &["TeX"]),
+    ("cmake", &["CMake"]),
+    ("makefile", &["Makefile"]),
+    ("dockerfile", &["Dockerfile"]),
+];
+
+
+fn extension_languages(path: &str) -> Option<&'static [&'static str]> {
+    let file_name = path.rsplit('/').next().unwrap_or(path).to_lowercase();
+    let key = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => extension.to_string(),
+        _ => file_name, // extensionless (or a dotfile): look up the whole name, e.g. Makefile
+    };
+    EXTENSION_LANGUAGES.iter().find(|(extension, _)| *extension == key).map(|(_, languages)| *languages)
+}
+
+
+pub(crate) fn add_ext_language(row: &mut JsonValue, shard_language: &str) {
+    /* --extensions-map: sets ext_language to the canonical language for the row's path extension (null if the table
+    doesn't know it), and, when it does know it, ext_language_mismatch to whether the shard's language is none of the
+    languages that use that extension (compared case-insensitively)
+    */
+    let languages = row["path"].as_str().and_then(extension_languages);
+    row["ext_language"] = languages.map_or(JsonValue::Null, |languages| JsonValue::from(languages[0]));
+    if let Some(languages) = languages {
+        let mismatch = !languages.iter().any(|language| language.eq_ignore_ascii_case(shard_language));
+        row["ext_language_mismatch"] = JsonValue::Bool(mismatch);
+    }
+}
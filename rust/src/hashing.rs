@@ -0,0 +1,245 @@
+use clap::ValueEnum;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Error};
+use twox_hash::XxHash3_64;
+
+
+/*==============================================================
+=                        CONTENT HASHES                        =
+==============================================================*/
+
+/// Which hash --hash-contents embeds (always hex encoded)
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HashAlgorithm {
+    Sha256,
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    pub fn hash_hex(&self, bytes: &[u8]) -> String {
+        match self {
+            HashAlgorithm::Sha256 => to_hex(&sha256(bytes)),
+            HashAlgorithm::Xxh3 => format!("{:016x}", XxHash3_64::oneshot(bytes)),
+        }
+    }
+}
+
+
+pub(crate) fn in_hash_sample(key: &str, seed: u64, fraction: f64) -> bool {
+    // Deterministic sampling by key: xxh3 rather than rows::stable_hash, whose (FNV) high bits are
+    // too lumpy over near-identical keys like blob_ids to threshold on
+    (XxHash3_64::oneshot_with_seed(seed, key.as_bytes()) as f64 / u64::MAX as f64) < fraction
+}
+
+
+/// Which checksum sidecar --emit-checksums writes next to each output. The sidecar is named <output>.<algorithm>
+/// and laid out like sha256sum's output (`<hex>  <file name>`), so `sha256sum -c` can check it too
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChecksumAlgorithm {
+    Sha256,
+    Crc32,
+}
+
+impl ChecksumAlgorithm {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Crc32 => "crc32",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Crc32].into_iter().find(|algorithm| algorithm.extension() == extension)
+    }
+
+    pub fn sidecar_loc(&self, path: &Path) -> PathBuf {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(self.extension());
+        PathBuf::from(sidecar)
+    }
+
+    pub fn checksum_file(&self, path: &Path) -> Result<String, Error> {
+        // Streams the file, so this is fine for big --single-file outputs
+        let mut file = File::open(path)?;
+        let mut buffer = vec![0u8; 1 << 16];
+        let mut sha256 = Sha256::new();
+        let mut crc32 = crc32fast::Hasher::new();
+        loop {
+            let num_read = file.read(&mut buffer)?;
+            if num_read == 0 {
+                break;
+            }
+            match self {
+                ChecksumAlgorithm::Sha256 => sha256.update(&buffer[..num_read]),
+                ChecksumAlgorithm::Crc32 => crc32.update(&buffer[..num_read]),
+            }
+        }
+        Ok(match self {
+            ChecksumAlgorithm::Sha256 => to_hex(&sha256.finalize()),
+            ChecksumAlgorithm::Crc32 => format!("{:08x}", crc32.finalize()),
+        })
+    }
+}
+
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+
+/*==============================================================
+=                            SHA256                            =
+==============================================================*/
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+
+pub(crate) fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+
+/// Plain FIPS 180-4 SHA-256, fed incrementally (so big files can be hashed without holding them in memory)
+pub(crate) struct Sha256 {
+    state: [u32; 8],
+    block: Vec<u8>,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Sha256 {
+            state: [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19],
+            block: Vec::with_capacity(64),
+            total_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let take = (64 - self.block.len()).min(bytes.len());
+            self.block.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if self.block.len() == 64 {
+                let block: [u8; 64] = self.block[..].try_into().unwrap();
+                self.compress(&block);
+                self.block.clear();
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        // Pads with 0x80, zeros, then the message length in bits, to a multiple of 64 bytes
+        let bit_len = self.total_len * 8;
+        let mut padding = vec![0x80u8];
+        while (self.block.len() + padding.len()) % 64 != 56 {
+            padding.push(0);
+        }
+        padding.extend_from_slice(&bit_len.to_be_bytes());
+        let total_len = self.total_len;
+        self.update(&padding);
+        self.total_len = total_len;
+
+        let mut digest = [0u8; 32];
+        for (i, word) in self.state.iter().enumerate() {
+            digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (k, wi) in SHA256_K.iter().zip(w.iter()) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(*k).wrapping_add(*wi);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_matches_fips_vectors() {
+        assert_eq!(to_hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(to_hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(to_hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(to_hex(&sha256(&vec![b'a'; 1_000_000])), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn sha256_pads_across_block_boundaries() {
+        // 55 bytes is the longest message whose padding fits in one block; 56 spills into a second
+        for (len, expected) in [
+            (55, "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318"),
+            (56, "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a"),
+            (63, "7d3e74a05d7db15bce4ad9ec0658ea98e3f06eeecf16b4c6fff2da457ddc2f34"),
+            (64, "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb"),
+            (65, "635361c48bb9eab14198e76ea8ab7f1a41685d6ad62aa9146d301d4f17eb0ae0"),
+        ] {
+            assert_eq!(to_hex(&sha256(&vec![b'a'; len])), expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn sha256_incremental_updates_match_one_shot() {
+        let message: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let expected = sha256(&message);
+        for chunk_len in [1, 7, 55, 56, 63, 64, 65, 999] {
+            let mut hasher = Sha256::new();
+            for chunk in message.chunks(chunk_len) {
+                hasher.update(chunk);
+            }
+            hasher.update(b"");
+            assert_eq!(hasher.finalize(), expected, "chunks of {}", chunk_len);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use anyhow::{Result, Error};
+
+
+/*==============================================================
+=                      MINIMAL HTTP CLIENT                     =
+==============================================================*/
+
+/// Just enough HTTP/1.1 for GETs against a plain http:// blob mirror: one connection per request
+/// (Connection: close), identity encoding, and Content-Length, chunked, or read-to-close bodies.
+/// There's no TLS here, so https:// URLs are rejected up front
+pub(crate) struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+
+struct HttpUrl<'a> {
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+
+fn parse_url(url: &str) -> Result<HttpUrl<'_>, Error> {
+    let rest = url.strip_prefix("http://")
+        .ok_or_else(|| Error::msg(format!("Only http:// URLs are supported (no TLS in this build), got {:?}", url)))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| Error::msg(format!("Bad port in {:?}", url)))?),
+        None => (authority, 80),
+    };
+    Ok(HttpUrl { host, port, path })
+}
+
+
+pub(crate) fn check_url(url: &str) -> Result<(), Error> {
+    parse_url(url).map(|_| ())
+}
+
+
+fn read_body_bytes(reader: &mut impl Read, len: usize, body: &mut Vec<u8>) -> Result<(), Error> {
+    // Appends len more bytes of body. The buffer grows as bytes actually arrive, not by what the server claims
+    let start = body.len();
+    if reader.take(len as u64).read_to_end(body)? < len {
+        return Err(Error::msg(format!("HTTP body ended after {} of {} bytes", body.len() - start, len)));
+    }
+    Ok(())
+}
+
+
+pub(crate) fn http_get(url: &str, timeout: Duration) -> Result<HttpResponse, Error> {
+    let url = parse_url(url)?;
+    let addr = (url.host, url.port).to_socket_addrs()?.next()
+        .ok_or_else(|| Error::msg(format!("Couldn't resolve {}", url.host)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: identity\r\nConnection: close\r\n\r\n", url.path, url.host)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status: u16 = status_line.split_whitespace().nth(1).and_then(|code| code.parse().ok())
+        .ok_or_else(|| Error::msg(format!("Bad HTTP status line {:?}", status_line.trim_end())))?;
+
+    let mut content_length: Option<usize> = None;
+    let mut chunked = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().ok();
+            } else if name.eq_ignore_ascii_case("transfer-encoding") && value.eq_ignore_ascii_case("chunked") {
+                chunked = true;
+            }
+        }
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line)?;
+            let size_hex = size_line.trim_end().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size_hex, 16)
+                .map_err(|_| Error::msg(format!("Bad chunk size {:?}", size_line.trim_end())))?;
+            if size == 0 {
+                break;
+            }
+            read_body_bytes(&mut reader, size, &mut body)?;
+            let mut crlf = [0u8; 2];
+            reader.read_exact(&mut crlf)?;
+        }
+    } else if let Some(content_length) = content_length {
+        read_body_bytes(&mut reader, content_length, &mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+    Ok(HttpResponse { status, body })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn serve_once(response: &'static [u8]) -> String {
+        // A one-shot server that sends response (whatever the request) and hangs up, and its URL
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/blob", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            stream.write_all(response).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn reads_content_length_and_chunked_bodies() {
+        let url = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+        assert_eq!(http_get(&url, Duration::from_secs(5)).unwrap().body, b"hello");
+        let url = serve_once(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n");
+        assert_eq!(http_get(&url, Duration::from_secs(5)).unwrap().body, b"hello");
+    }
+
+    #[test]
+    fn oversized_lengths_are_short_reads_not_allocations() {
+        let url = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\nhello");
+        let err = http_get(&url, Duration::from_secs(5)).err().unwrap();
+        assert!(err.to_string().contains("ended after 5 of"), "{}", err);
+        let url = serve_once(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffff\r\nhello");
+        let err = http_get(&url, Duration::from_secs(5)).err().unwrap();
+        assert!(err.to_string().contains("ended after 5 of"), "{}", err);
+    }
+}
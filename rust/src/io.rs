@@ -1,35 +1,127 @@
   // You'll need crc32fast = "1.3" in Cargo.toml
 use std::fmt;
-use std::io::{BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
 use std::fs;
 use flate2::Compression;
 use std::io::Read;
-use flate2::read::GzDecoder;
+use flate2::read::MultiGzDecoder;
 use flate2::write::GzEncoder;
-use std::path::PathBuf;
-use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::ffi::OsStrExt;
+use std::time::Duration;
 use anyhow::{Result, Error};
 use rayon::prelude::*;
 use encoding_rs::*;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use chrono::NaiveTime;
 
 use arrow::{
-    array::{Array, BooleanArray, Int64Array, ListArray, StringArray, TimestampNanosecondArray},
-    datatypes::DataType,
+    array::{Array, BooleanArray, FixedSizeBinaryArray, Float32Array, Float64Array, Int64Array, ListArray, MapArray, PrimitiveArray, StringArray, StructArray, TimestampNanosecondArray},
+    array::{Time32MillisecondArray, Time32SecondArray, Time64MicrosecondArray, Time64NanosecondArray},
+    datatypes::{ArrowTemporalType, DataType, Field, Schema, SchemaRef, TimeUnit},
 };
-use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::array_value_to_string;
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::file::metadata::ParquetMetaData;
+use parquet::file::reader::ChunkReader;
+use parquet::arrow::{ArrowWriter, ProjectionMask};
+use bytes::Bytes;
+use crate::logging::log_warn;
+use crate::hashing::to_hex;
+use base64::prelude::*;
+use clap::ValueEnum;
 
 use serde_json::{json, Value as JsonValue};
-use oem_cp::decode_string_complete_table;
-use oem_cp::code_table::{DECODING_TABLE_CP855, DECODING_TABLE_CP852, DECODING_TABLE_CP866};
+use oem_cp::{decode_string_complete_table, encode_string_checked};
+use oem_cp::code_table::{DECODING_TABLE_CP437, DECODING_TABLE_CP855, DECODING_TABLE_CP852, DECODING_TABLE_CP866};
+use oem_cp::code_table::{ENCODING_TABLE_CP437, ENCODING_TABLE_CP855, ENCODING_TABLE_CP852, ENCODING_TABLE_CP866};
 
 
 /*====================================================================
 =                      READ PARQUET INTO LIST OF JSONS               =
 ====================================================================*/
 
+/// How binary columns are written into the (text-only) json
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BinaryEncoding {
+    Hex,
+    Base64,
+}
+
+
+/// How time-of-day (Time32/Time64) columns are written into the json
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimeFormat {
+    /// The raw integer, in the column's own unit (seconds, millis, micros or nanos since midnight)
+    Raw,
+    /// An ISO 8601 time string, HH:MM:SS with as many fractional digits as the unit has
+    Iso,
+}
+
+
+/// Everything about how a parquet gets read in (as opposed to what's done with its rows)
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ParquetReadOptions {
+    pub batch_size: usize,
+    pub use_mmap: bool,
+    pub binary_encoding: BinaryEncoding,
+    pub time_format: TimeFormat,
+    pub float_precision: Option<u32>,
+    pub unsupported_column_fallback: Option<UnsupportedColumnFallback>,
+    pub add_row_group_index: bool,
+}
+
+
+/// What to do with a value of a column type convert_column_to_json doesn't know.
+/// Without a fallback, the column is left out of the row
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnsupportedColumnFallback {
+    /// The value as arrow displays it, as a json string
+    DebugString,
+}
+
+
+fn warn_unsupported_column(data_type: &DataType) {
+    // Once per data type per run, since this would otherwise fire for every single value
+    static WARNED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    let data_type = data_type.to_string();
+    if WARNED.get_or_init(Default::default).lock().unwrap().insert(data_type.clone()) {
+        log_warn!(json!({"data_type": data_type}), "Unsupported column type {}, writing its values as strings", data_type);
+    }
+}
+
+
+fn time_value<T: ArrowTemporalType>(array: &PrimitiveArray<T>, row_idx: usize, fraction: &'static str) -> (i64, Option<NaiveTime>, &'static str)
+where
+    i64: From<T::Native>,
+{
+    // A (non-null) time-of-day value as its raw integer and as a NaiveTime (None if out of range), plus how to format it
+    (i64::from(array.value(row_idx)), array.value_as_time(row_idx), fraction)
+}
+
+
+fn round_float(value: f64, precision: Option<u32>) -> f64 {
+    // Rounds to precision decimals (half away from zero), so serde_json prints at most that many
+    match precision {
+        Some(precision) => {
+            let scale = 10f64.powi(precision as i32);
+            (value * scale).round() / scale
+        },
+        None => value,
+    }
+}
+
+
 fn convert_column_to_json(
     column: &arrow::array::ArrayRef,
     row_idx: usize,
+    read_options: &ParquetReadOptions,
 ) -> Result<JsonValue, Error> {
     match column.data_type() {
         DataType::Utf8 => {
@@ -54,6 +146,20 @@ fn convert_column_to_json(
                 json!(array.value(row_idx))
             })
         }
+        DataType::Float64 | DataType::Float32 => {
+            let value = match column.as_any().downcast_ref::<Float64Array>() {
+                Some(array) => array.is_valid(row_idx).then(|| array.value(row_idx)),
+                None => {
+                    let array = column.as_any().downcast_ref::<Float32Array>().expect("Invalid float array");
+                    array.is_valid(row_idx).then(|| array.value(row_idx) as f64)
+                },
+            };
+            // NaN/inf aren't valid json, so they (like nulls) come out as null
+            Ok(match value {
+                Some(value) => json!(round_float(value, read_options.float_precision)),
+                None => JsonValue::Null,
+            })
+        }
         DataType::Boolean => {
             let array = column
                 .as_any()
@@ -65,7 +171,7 @@ fn convert_column_to_json(
                 json!(array.value(row_idx))
             })
         }
-        DataType::List(field) if field.data_type() == &DataType::Utf8 => {
+        DataType::List(_) => {
             let array = column
                 .as_any()
                 .downcast_ref::<ListArray>()
@@ -75,23 +181,30 @@ fn convert_column_to_json(
                 return Ok(JsonValue::Null);
             }
             
+            // Each element goes through this same conversion, whatever the child type (strings, ints, structs, ...).
+            // Null elements are left out, as they always have been for lists of strings
             let list_value = array.value(row_idx);
-            let string_array = list_value
-                .as_any()
-                .downcast_ref::<StringArray>()
-                .expect("Invalid string array in list");
-            
-            let values: Vec<String> = (0..string_array.len())
-                .filter_map(|i| {
-                    if string_array.is_null(i) {
-                        None
-                    } else {
-                        Some(string_array.value(i).to_string())
-                    }
-                })
-                .collect();
+            let values: Vec<JsonValue> = (0..list_value.len())
+                .filter(|i| !list_value.is_null(*i))
+                .map(|i| convert_column_to_json(&list_value, i, read_options))
+                .collect::<Result<_, _>>()?;
             
-            Ok(json!(values))
+            Ok(JsonValue::Array(values))
+        }
+        DataType::Struct(_) => {
+            let array = column
+                .as_any()
+                .downcast_ref::<StructArray>()
+                .expect("Invalid struct array");
+            if array.is_null(row_idx) {
+                return Ok(JsonValue::Null);
+            }
+
+            let mut object = serde_json::Map::with_capacity(array.num_columns());
+            for (name, child) in array.column_names().into_iter().zip(array.columns()) {
+                object.insert(name.to_string(), convert_column_to_json(child, row_idx, read_options)?);
+            }
+            Ok(JsonValue::Object(object))
         }
         DataType::Timestamp(_, _) => {
             let array = column
@@ -104,41 +217,467 @@ fn convert_column_to_json(
                 json!(array.value(row_idx))
             })
         }
-        _ => Err(Error::msg(format!("Failed on {:?} {:?} {:?}", column, row_idx, column.data_type()))),
+        DataType::Time32(unit) | DataType::Time64(unit) => {
+            if column.is_null(row_idx) {
+                return Ok(JsonValue::Null);
+            }
+            let (raw, time, fraction) = match unit {
+                TimeUnit::Second => time_value(column.as_any().downcast_ref::<Time32SecondArray>().expect("Invalid time32 array"), row_idx, "%H:%M:%S"),
+                TimeUnit::Millisecond => time_value(column.as_any().downcast_ref::<Time32MillisecondArray>().expect("Invalid time32 array"), row_idx, "%H:%M:%S%.3f"),
+                TimeUnit::Microsecond => time_value(column.as_any().downcast_ref::<Time64MicrosecondArray>().expect("Invalid time64 array"), row_idx, "%H:%M:%S%.6f"),
+                TimeUnit::Nanosecond => time_value(column.as_any().downcast_ref::<Time64NanosecondArray>().expect("Invalid time64 array"), row_idx, "%H:%M:%S%.9f"),
+            };
+            Ok(match (read_options.time_format, time) {
+                (TimeFormat::Iso, Some(time)) => JsonValue::String(time.format(fraction).to_string()),
+                _ => json!(raw), // raw, or a value past midnight that isn't a valid time of day
+            })
+        }
+        DataType::FixedSizeBinary(_) => {
+            let array = column
+                .as_any()
+                .downcast_ref::<FixedSizeBinaryArray>()
+                .expect("Invalid fixed size binary array");
+            Ok(if array.is_null(row_idx) {
+                JsonValue::Null
+            } else {
+                let bytes = array.value(row_idx);
+                JsonValue::String(match read_options.binary_encoding {
+                    BinaryEncoding::Hex => to_hex(bytes),
+                    BinaryEncoding::Base64 => BASE64_STANDARD.encode(bytes),
+                })
+            })
+        }
+        DataType::Map(_, _) => {
+            let array = column
+                .as_any()
+                .downcast_ref::<MapArray>()
+                .expect("Invalid map array");
+            if array.is_null(row_idx) {
+                return Ok(JsonValue::Null);
+            }
+
+            // Each row's map is a run of (key, value) struct entries; keys have to be strings to be json keys
+            let entries = array.value(row_idx);
+            let keys = entries
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .ok_or_else(|| Error::msg(format!("Map keys must be strings, got {:?}", entries.column(0).data_type())))?;
+            let values = entries.column(1);
+            let mut map = serde_json::Map::with_capacity(keys.len());
+            for i in 0..keys.len() {
+                map.insert(keys.value(i).to_string(), convert_column_to_json(values, i, read_options)?);
+            }
+            Ok(JsonValue::Object(map))
+        }
+        _ => match read_options.unsupported_column_fallback {
+            Some(UnsupportedColumnFallback::DebugString) => {
+                warn_unsupported_column(column.data_type());
+                Ok(if column.is_null(row_idx) {
+                    JsonValue::Null
+                } else {
+                    JsonValue::String(array_value_to_string(column, row_idx)?)
+                })
+            },
+            None => Err(Error::msg(format!("Failed on {:?} {:?} {:?}", column, row_idx, column.data_type()))),
+        },
     }
 }
 
-pub(crate) fn load_parquet_as_json_parallel(path: PathBuf) -> Result<Vec<JsonValue>, Error> {
-	let open_file = File::open(path)?;
-	let arrow_reader = ParquetRecordBatchReaderBuilder::try_new(open_file)?
-		.with_batch_size(1024)
-		.build()?;
+pub(crate) fn column_json_schema(data_type: &DataType, read_options: &ParquetReadOptions) -> Option<JsonValue> {
+    /* The JSON Schema of the (non-null) values convert_column_to_json makes of a column of data_type, or None for
+    types it can't convert, whose columns are left out of the rows. Keep the two in step
+    */
+    let schema = match data_type {
+        DataType::Utf8 => json!({"type": "string"}),
+        DataType::Int64 | DataType::Timestamp(_, _) => json!({"type": "integer"}),
+        DataType::Float64 | DataType::Float32 => json!({"type": ["number", "null"]}), // NaN/inf come out as null
+        DataType::Boolean => json!({"type": "boolean"}),
+        DataType::List(field) => json!({"type": "array", "items": column_json_schema(field.data_type(), read_options)?}), // null elements are left out
+        DataType::Struct(fields) => {
+            let properties = fields.iter()
+                .map(|field| Some((field.name().clone(), field_json_schema(field, read_options)?)))
+                .collect::<Option<serde_json::Map<String, JsonValue>>>()?;
+            json!({"type": "object", "properties": properties})
+        },
+        DataType::Time32(_) | DataType::Time64(_) => match read_options.time_format {
+            TimeFormat::Raw => json!({"type": "integer"}),
+            TimeFormat::Iso => json!({"type": "string", "format": "time"}),
+        },
+        DataType::FixedSizeBinary(_) => match read_options.binary_encoding {
+            BinaryEncoding::Hex => json!({"type": "string", "contentEncoding": "base16"}),
+            BinaryEncoding::Base64 => json!({"type": "string", "contentEncoding": "base64"}),
+        },
+        DataType::Map(entries, _) => match entries.data_type() {
+            DataType::Struct(key_value) if key_value.len() == 2 && key_value[0].data_type() == &DataType::Utf8 => {
+                json!({"type": "object", "additionalProperties": field_json_schema(&key_value[1], read_options)?})
+            },
+            _ => return None,
+        },
+        _ => match read_options.unsupported_column_fallback {
+            Some(UnsupportedColumnFallback::DebugString) => json!({"type": "string"}),
+            None => return None,
+        },
+    };
+    Some(schema)
+}
+
+
+pub(crate) fn field_json_schema(field: &Field, read_options: &ParquetReadOptions) -> Option<JsonValue> {
+    // column_json_schema, allowing null too if the field is nullable
+    let schema = column_json_schema(field.data_type(), read_options)?;
+    Some(match field.is_nullable() {
+        true => nullable_json_schema(schema),
+        false => schema,
+    })
+}
+
+
+pub(crate) fn nullable_json_schema(mut schema: JsonValue) -> JsonValue {
+    let types = match schema["type"].take() {
+        JsonValue::Array(mut types) => {
+            if !types.contains(&json!("null")) {
+                types.push(json!("null"));
+            }
+            types
+        },
+        single_type => vec![single_type, json!("null")],
+    };
+    schema["type"] = JsonValue::Array(types);
+    schema
+}
+
+
+/// With --reader-threads, the pool that parquet decoding and arrow-to-json conversion run on, so it's sized apart
+/// from (and never waits behind) the row processing in rayon's global pool. Without one, reading shares the global pool
+static READER_POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+
+
+pub(crate) fn init_reader_pool(num_threads: usize) -> Result<(), Error> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .thread_name(|i| format!("reader-{}", i))
+        .build()?;
+    READER_POOL.set(pool).map_err(|_| Error::msg("Reader pool already set up"))
+}
 
-    let batches: Result<Vec<_>, _> = arrow_reader.collect();
-    let batches = batches?;
 
-	
-    // Process batches in parallel
-    let json_records: Vec<_> = batches.par_iter()
-        .flat_map(|batch| {
+pub(crate) fn on_reader_pool<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+    match READER_POOL.get() {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+
+fn batches_to_json(batches: &[RecordBatch], first_row: usize, layout: &RowGroupLayout, read_options: &ParquetReadOptions) -> Vec<JsonValue> {
+    // Process batches in parallel. first_row is the file-wide index of the first batch's first row
+    let batch_starts: Vec<usize> = batches.iter()
+        .scan(first_row, |next_row, batch| {
+            let start = *next_row;
+            *next_row += batch.num_rows();
+            Some(start)
+        })
+        .collect();
+    batches.par_iter().zip(batch_starts)
+        .flat_map(|(batch, batch_start)| {
             (0..batch.num_rows())
                 .map(|row_idx| {
                     let mut row_obj = json!({});                    
                     if let JsonValue::Object(ref mut map) = row_obj {
                         for (col_idx, column) in batch.columns().iter().enumerate() {
                             let col_name = batch.schema().field(col_idx).name().clone();
-                            if let Ok(value) = convert_column_to_json(column, row_idx) {
+                            if let Ok(value) = convert_column_to_json(column, row_idx, read_options) {
                                 map.insert(col_name.to_string(), value);
                             }
                         }
+                        if read_options.add_row_group_index {
+                            map.insert("row_group".to_string(), json!(layout.row_group_of(batch_start + row_idx)));
+                        }
                     }                    
                     row_obj
                 })
                 .collect::<Vec<_>>()
         })
-        .collect();
-    
-    Ok(json_records)
+        .collect()
+}
+
+
+/// The file-wide index of each row group's first row, from the parquet footer. A reader's batches can span
+/// row groups, so this is what maps rows back to the row group they came from
+struct RowGroupLayout {
+    row_group_starts: Vec<usize>,
+}
+
+impl RowGroupLayout {
+    fn new(metadata: &ParquetMetaData) -> Self {
+        let row_group_starts = metadata.row_groups().iter()
+            .scan(0, |next_row, row_group| {
+                let start = *next_row;
+                *next_row += row_group.num_rows() as usize;
+                Some(start)
+            })
+            .collect();
+        RowGroupLayout { row_group_starts }
+    }
+
+    fn row_group_of(&self, row: usize) -> usize {
+        self.row_group_starts.partition_point(|start| *start <= row).saturating_sub(1)
+    }
+}
+
+
+/// A read-only memory map of a whole file, unmapped on drop
+struct MmapRegion {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and owned by this struct, so sharing it across threads is fine
+unsafe impl Send for MmapRegion {}
+unsafe impl Sync for MmapRegion {}
+
+impl MmapRegion {
+    fn map(file: &File) -> Result<Self, Error> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(Error::msg("can't mmap an empty file"));
+        }
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(MmapRegion { ptr, len })
+    }
+}
+
+impl AsRef<[u8]> for MmapRegion {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MmapRegion {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len); }
+    }
+}
+
+
+fn build_parquet_reader<T: ChunkReader + 'static>(input: T, batch_size: usize) -> Result<(ParquetRecordBatchReader, usize, RowGroupLayout), Error> {
+	let builder = ParquetRecordBatchReaderBuilder::try_new(input)?;
+    let num_rows = builder.metadata().file_metadata().num_rows() as usize;
+    let layout = RowGroupLayout::new(builder.metadata());
+	let arrow_reader = builder
+		.with_batch_size(batch_size)
+		.build()?;
+    Ok((arrow_reader, num_rows, layout))
+}
+
+
+fn open_parquet_reader(path: PathBuf, read_options: &ParquetReadOptions) -> Result<(ParquetRecordBatchReader, usize, RowGroupLayout), Error> {
+    /* Returns the record batch reader, along with the number of rows the parquet metadata says the file has
+    and where its row groups start.
+    With use_mmap, the file is memory-mapped and read through a Bytes, falling back to plain
+    File reads if the mapping fails (e.g. filesystems that don't support mmap)
+    */
+	let open_file = File::open(path)?;
+    if read_options.use_mmap {
+        match MmapRegion::map(&open_file) {
+            Ok(region) => return build_parquet_reader(Bytes::from_owner(region), read_options.batch_size),
+            Err(e) => log_warn!(json!({"error": e.to_string()}), "Couldn't mmap parquet ({}), falling back to File reads", e),
+        }
+    }
+    build_parquet_reader(open_file, read_options.batch_size)
+}
+
+
+pub(crate) fn read_parquet_schema(path: &Path) -> Result<SchemaRef, Error> {
+    // Only reads the parquet footer, so this is cheap even for huge files
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    Ok(builder.schema().clone())
+}
+
+
+fn without_nullability(data_type: &DataType) -> DataType {
+    // data_type with every nested field made nullable, so two types compare equal whatever their nullability
+    let field = |field: &Field| Field::new(field.name(), without_nullability(field.data_type()), true);
+    match data_type {
+        DataType::List(item) => DataType::List(field(item).into()),
+        DataType::LargeList(item) => DataType::LargeList(field(item).into()),
+        DataType::FixedSizeList(item, size) => DataType::FixedSizeList(field(item).into(), *size),
+        DataType::Struct(fields) => DataType::Struct(fields.iter().map(|f| field(f)).collect()),
+        DataType::Map(entries, sorted) => DataType::Map(field(entries).into(), *sorted),
+        other => other.clone(),
+    }
+}
+
+
+pub(crate) fn schema_differences(expected: &Schema, actual: &Schema) -> Vec<String> {
+    /* How actual's columns differ from expected's, ignoring nullability (at any depth), column order and metadata.
+    Empty if they're the same
+    */
+    let mut differences = Vec::new();
+    for field in expected.fields() {
+        match actual.field_with_name(field.name()) {
+            Err(_) => differences.push(format!("missing column {}", field.name())),
+            Ok(other) if without_nullability(field.data_type()) != without_nullability(other.data_type()) => {
+                differences.push(format!("column {} is {} instead of {}", field.name(), other.data_type(), field.data_type()));
+            },
+            Ok(_) => {},
+        }
+    }
+    for field in actual.fields() {
+        if expected.field_with_name(field.name()).is_err() {
+            differences.push(format!("new column {} ({})", field.name(), field.data_type()));
+        }
+    }
+    differences
+}
+
+
+pub(crate) fn count_parquet_column_values(path: &Path, column: &str) -> Result<HashMap<Option<String>, usize>, Error> {
+    // How many rows have each value (None for nulls) of one top-level column, decoding only that column
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    let column_index = builder.schema().index_of(column)
+        .map_err(|_| FileProcessError::MissingColumns { missing: vec![column.to_string()], available: builder.schema().fields().iter().map(|field| field.name().clone()).collect() })?;
+    let mask = ProjectionMask::roots(builder.parquet_schema(), [column_index]);
+    let mut counts: HashMap<Option<String>, usize> = HashMap::new();
+    for batch in builder.with_projection(mask).build()? {
+        let values = batch?.column(0).clone();
+        for i in 0..values.len() {
+            let value = match values.is_null(i) {
+                true => None,
+                false => Some(array_value_to_string(&values, i)?),
+            };
+            *counts.entry(value).or_insert(0) += 1;
+        }
+    }
+    Ok(counts)
+}
+
+
+pub(crate) fn read_parquet_batches(path: &Path) -> Result<Vec<RecordBatch>, Error> {
+    // Every record batch of a (small) parquet, as-is
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+    Ok(reader.collect::<Result<Vec<RecordBatch>, _>>()?)
+}
+
+
+pub(crate) fn write_parquet_atomic(path: &Path, batches: &[RecordBatch], fsync: bool) -> Result<(), Error> {
+    // Writes batches (which must share a schema) to a temp file and renames it over path (flushed to disk first, with fsync)
+    let Some(first) = batches.first() else {
+        return Ok(());
+    };
+    let tmp_path = tmp_path_for(path, None)?;
+    let mut writer = ArrowWriter::try_new(File::create(&tmp_path)?, first.schema(), None)?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    let file = writer.into_inner()?;
+    if fsync {
+        file.sync_all()?;
+    }
+    rename_into_place(&tmp_path, path, fsync)
+}
+
+
+pub(crate) fn read_parquet_file_metadata(path: &Path) -> Result<JsonValue, Error> {
+    // File-level parquet metadata (footer only). key_value_metadata sometimes carries the dataset version/commit
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    let metadata = builder.metadata();
+    let file_metadata = metadata.file_metadata();
+    let key_value_metadata: serde_json::Map<String, JsonValue> = file_metadata.key_value_metadata()
+        .map(|kvs| kvs.iter().map(|kv| (kv.key.clone(), json!(kv.value))).collect())
+        .unwrap_or_default();
+    Ok(json!({
+        "created_by": file_metadata.created_by(),
+        "version": file_metadata.version(),
+        "num_rows": file_metadata.num_rows(),
+        "num_row_groups": metadata.num_row_groups(),
+        "key_value_metadata": key_value_metadata,
+    }))
+}
+
+
+pub(crate) fn read_parquet_key_value(path: &Path, key: &str) -> Result<Option<String>, Error> {
+    // One entry of the parquet's file-level key-value metadata (footer only)
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    let value = builder.metadata().file_metadata().key_value_metadata()
+        .and_then(|kvs| kvs.iter().find(|kv| kv.key == key))
+        .and_then(|kv| kv.value.clone());
+    Ok(value)
+}
+
+
+pub(crate) fn read_parquet_num_rows(path: &Path) -> Result<usize, Error> {
+    // Row count straight from the parquet metadata (i.e., without decoding any rows)
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    Ok(builder.metadata().file_metadata().num_rows() as usize)
+}
+
+
+pub(crate) fn load_parquet_as_json_parallel(path: PathBuf, read_options: &ParquetReadOptions) -> Result<Vec<JsonValue>, Error> {
+    on_reader_pool(|| {
+        let (arrow_reader, _, layout) = open_parquet_reader(path, read_options)?;
+        let batches: Result<Vec<_>, _> = arrow_reader.collect();
+        let batches = batches?;
+
+        Ok(batches_to_json(&batches, 0, &layout, read_options))
+    })
+}
+
+
+/// Lazily reads a parquet file as chunks of (at most) chunk_size json rows,
+/// so that only about one chunk's worth of rows is in memory at a time
+pub(crate) struct ParquetChunkReader {
+    reader: ParquetRecordBatchReader,
+    chunk_size: usize,
+    read_options: ParquetReadOptions,
+    buffer: Vec<JsonValue>,
+    pub num_rows: usize,
+    layout: RowGroupLayout,
+    rows_read: usize,
+}
+
+impl ParquetChunkReader {
+    pub fn new(path: PathBuf, chunk_size: usize, read_options: &ParquetReadOptions) -> Result<Self, Error> {
+        let (reader, num_rows, layout) = open_parquet_reader(path, read_options)?;
+        Ok(ParquetChunkReader { reader, chunk_size, read_options: *read_options, buffer: Vec::new(), num_rows, layout, rows_read: 0 })
+    }
+
+    fn read_chunk(&mut self) -> Option<Result<Vec<JsonValue>, Error>> {
+        let mut batches: Vec<RecordBatch> = Vec::new();
+        let mut buffered = self.buffer.len();
+        while buffered < self.chunk_size {
+            match self.reader.next() {
+                Some(Ok(batch)) => {
+                    buffered += batch.num_rows();
+                    batches.push(batch);
+                },
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => break,
+            }
+        }
+        self.buffer.extend(batches_to_json(&batches, self.rows_read, &self.layout, &self.read_options));
+        self.rows_read += batches.iter().map(RecordBatch::num_rows).sum::<usize>();
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let take = self.chunk_size.min(self.buffer.len());
+        let rest = self.buffer.split_off(take);
+        Some(Ok(std::mem::replace(&mut self.buffer, rest)))
+    }
+}
+
+impl Iterator for ParquetChunkReader {
+    type Item = Result<Vec<JsonValue>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        on_reader_pool(|| self.read_chunk())
+    }
 }
 
 /*==============================================================
@@ -151,74 +690,133 @@ pub enum DecodingError {
     DecodingFailed(String),
 }
 
+/// Normalizes a row's src_encoding, mapping the placeholder values to None
+pub(crate) fn encoding_label(label: Option<&str>) -> Option<&str> {
+    // Some rows have "null"/"None" (or nothing) as their src_encoding, from a metadata bug upstream. Those mean "unknown"
+    match label.map(str::trim) {
+        None | Some("") => None,
+        Some(label) if label.eq_ignore_ascii_case("null") || label.eq_ignore_ascii_case("none") => None,
+        Some(label) => Some(label),
+    }
+}
+
+
+/// The decoder an encoding name decodes with: one of oem_cp's tables for the OEM code pages encoding_rs lacks, or else encoding_rs
+enum TextDecoder {
+    Table(&'static [char; 128], &'static str),
+    EncodingRs(&'static Encoding),
+}
+
+impl TextDecoder {
+    fn for_name(encoding_name: &str) -> Result<Self, Error> {
+        match encoding_name {
+            "IBM437" | "CP437" => return Ok(TextDecoder::Table(&DECODING_TABLE_CP437, "IBM437")),
+            "IBM852" => return Ok(TextDecoder::Table(&DECODING_TABLE_CP852, "IBM852")),
+            "IBM855" => return Ok(TextDecoder::Table(&DECODING_TABLE_CP855, "IBM855")),
+            "IBM866" => return Ok(TextDecoder::Table(&DECODING_TABLE_CP866, "IBM866")),
+            _ => {},
+        }
+
+        let encoding = match encoding_name.to_uppercase().as_str() {
+            "BIG5" => BIG5,
+            "EUC-JP" => EUC_JP,
+            "GB18030" => GB18030,  
+            "ISO-8859-1" => WINDOWS_1252, 
+            "ISO-8859-2" | "ISO8859-2" => ISO_8859_2,
+            "ISO-8859-3" | "ISO8859-3" => ISO_8859_3,        
+            "ISO-8859-4" | "ISO8859-4" => ISO_8859_4,
+            "ISO-8859-5" | "ISO8859-5" => ISO_8859_5,
+            "ISO-8859-6" | "ISO8859-6" => ISO_8859_6,
+            "ISO-8859-7" | "ISO8859-7" => ISO_8859_7,
+            "ISO-8859-8" | "ISO8859-8" => ISO_8859_8,
+            "ISO-8859-9" | "ISO8859-9" => Encoding::for_label(b"ISO-8859-9").unwrap(),        
+            "ISO-8859-10" | "ISO8859-10" => ISO_8859_10,
+            "ISO-8859-11" | "ISO8859-11" => Encoding::for_label(b"ISO-8859-11").unwrap(),
+
+            "ISO-8859-13" | "ISO8859-13" => ISO_8859_13,
+            "ISO-8859-14" | "ISO8859-14" => ISO_8859_14,
+            "ISO-8859-15" | "ISO8859-15" => ISO_8859_15,
+            "ISO-8859-16" | "ISO8859-16" => ISO_8859_16,
+            "ISO-2022-JP" | "ISO2022JP" | "CSISO2022JP" => ISO_2022_JP,
+            "KOI8-R" | "KOI8R" => KOI8_R,
+            "KOI8-U" | "KOI8U" => KOI8_U,
+            "MACINTOSH" | "MAC" => MACINTOSH,
+            "MACCENTRALEUROPE" => WINDOWS_1250,
+            "MACCYRILLIC" => X_MAC_CYRILLIC,
+            "SHIFT_JIS" => SHIFT_JIS,
+            "TIS-620" => WINDOWS_874,
+            "UHC" => EUC_KR,
+            "UTF-16" => UTF_16BE,
+            "WINDOWS-874" | "CP874" => WINDOWS_874,
+            "WINDOWS-1250" | "CP1250" => WINDOWS_1250,
+            "WINDOWS-1251" | "CP1251" => WINDOWS_1251,
+            "WINDOWS-1252" | "CP1252" => WINDOWS_1252,
+            "WINDOWS-1253" | "CP1253" => WINDOWS_1253,
+            "WINDOWS-1254" | "CP1254" => WINDOWS_1254,
+            "WINDOWS-1255" | "CP1255" => WINDOWS_1255,
+            "WINDOWS-1256" | "CP1256" => WINDOWS_1256,
+            "WINDOWS-1257" | "CP1257" => WINDOWS_1257,
+            "WINDOWS-1258" | "CP1258" => WINDOWS_1258,
+            "UTF-8" | "UTF8" => UTF_8,
+            _ => return Err(Error::msg(format!("BAD ENCODING {:?}", encoding_name))),
+        };
+        Ok(TextDecoder::EncodingRs(encoding))
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            TextDecoder::Table(_, name) => name,
+            TextDecoder::EncodingRs(encoding) => encoding.name(),
+        }
+    }
+}
+
+
 /// Decodes bytes from the specified encoding into a UTF-8 String
-pub(crate) fn decode_to_string(bytes: &[u8], encoding_name: &str) -> Result<String, Error> {
-    // Get the encoding by name
-
-    if encoding_name == "IBM852" {
-        return Ok(decode_string_complete_table(bytes, &DECODING_TABLE_CP852));
-    }
-    if encoding_name == "IBM855" {
-        return Ok(decode_string_complete_table(bytes, &DECODING_TABLE_CP855));
-    }
-    if encoding_name == "IBM866" {
-        return Ok(decode_string_complete_table(bytes, &DECODING_TABLE_CP866));
-    }    
-
-    let encoding = match encoding_name.to_uppercase().as_str() {
-        "BIG5" => BIG5,
-        "EUC-JP" => EUC_JP,
-        "GB18030" => GB18030,  
-        "ISO-8859-1" => WINDOWS_1252, 
-        "ISO-8859-2" | "ISO8859-2" => ISO_8859_2,
-        "ISO-8859-3" | "ISO8859-3" => ISO_8859_3,        
-        "ISO-8859-4" | "ISO8859-4" => ISO_8859_4,
-        "ISO-8859-5" | "ISO8859-5" => ISO_8859_5,
-        "ISO-8859-6" | "ISO8859-6" => ISO_8859_6,
-        "ISO-8859-7" | "ISO8859-7" => ISO_8859_7,
-        "ISO-8859-8" | "ISO8859-8" => ISO_8859_8,
-        "ISO-8859-9" | "ISO8859-9" => Encoding::for_label(b"ISO-8859-9").unwrap(),        
-        "ISO-8859-10" | "ISO8859-10" => ISO_8859_10,
-        "ISO-8859-11" | "ISO8859-11" => Encoding::for_label(b"ISO-8859-11").unwrap(),
-
-        "ISO-8859-13" | "ISO8859-13" => ISO_8859_13,
-        "ISO-8859-14" | "ISO8859-14" => ISO_8859_14,
-        "ISO-8859-15" | "ISO8859-15" => ISO_8859_15,
-        "ISO-8859-16" | "ISO8859-16" => ISO_8859_16,
-        "KOI8-R" | "KOI8R" => KOI8_R,
-        "KOI8-U" | "KOI8U" => KOI8_U,
-        "MACINTOSH" | "MAC" => MACINTOSH,
-        "MACCENTRALEUROPE" => WINDOWS_1250,
-        "MACCYRILLIC" => X_MAC_CYRILLIC,
-        "SHIFT_JIS" => SHIFT_JIS,
-        "TIS-620" => WINDOWS_874,
-        "UHC" => EUC_KR,
-        "UTF-16" => UTF_16BE,
-        "WINDOWS-874" | "CP874" => WINDOWS_874,
-        "WINDOWS-1250" | "CP1250" => WINDOWS_1250,
-        "WINDOWS-1251" | "CP1251" => WINDOWS_1251,
-        "WINDOWS-1252" | "CP1252" => WINDOWS_1252,
-        "WINDOWS-1253" | "CP1253" => WINDOWS_1253,
-        "WINDOWS-1254" | "CP1254" => WINDOWS_1254,
-        "WINDOWS-1255" | "CP1255" => WINDOWS_1255,
-        "WINDOWS-1256" | "CP1256" => WINDOWS_1256,
-        "WINDOWS-1257" | "CP1257" => WINDOWS_1257,
-        "WINDOWS-1258" | "CP1258" => WINDOWS_1258,
-        "UTF-8" | "UTF8" => UTF_8,
-        _ => return Err(Error::msg(format!("BAD ENCODING {:?}", encoding_name))),
+pub(crate) fn decode_to_string(bytes: &[u8], encoding_name: &str, lossy: bool) -> Result<(String, &'static str), Error> {
+    // Get the encoding by name. Also returns the name of the decoder that was actually used, which can differ
+    // from encoding_name: aliases are resolved, and e.g. ISO-8859-1 is decoded as its WINDOWS-1252 superset.
+    // With lossy, undecodable bytes become U+FFFD replacement chars instead of an error
+    let encoding = match TextDecoder::for_name(encoding_name)? {
+        TextDecoder::Table(table, name) => return Ok((decode_string_complete_table(bytes, table), name)),
+        TextDecoder::EncodingRs(encoding) => encoding,
     };
-    
+
     // Decode the bytes
     let (cow, _, had_errors) = encoding.decode(bytes);
     
-    if had_errors {
+    if had_errors && !lossy {
         Err(Error::msg(format!(
             "Failed to decode bytes using {} encoding",
             encoding_name
         )))
     } else {
-        Ok(cow.into_owned())
+        Ok((cow.into_owned(), encoding.name()))
+    }
+}
+
+
+pub(crate) fn encode_from_string(contents: &str, decoded_with: &str) -> Option<Option<Vec<u8>>> {
+    /* Inverse of decode_to_string, given the decoder name it returned: the bytes contents encodes to.
+    Some(None) if contents has a char the encoding can't represent,
+    and None if there's no encoder at all (encoding_rs only encodes UTF-16 as UTF-8)
+    */
+    let table = match decoded_with {
+        "IBM437" => Some(&ENCODING_TABLE_CP437),
+        "IBM852" => Some(&ENCODING_TABLE_CP852),
+        "IBM855" => Some(&ENCODING_TABLE_CP855),
+        "IBM866" => Some(&ENCODING_TABLE_CP866),
+        _ => None,
+    };
+    if let Some(table) = table {
+        return Some(encode_string_checked(contents, table));
+    }
+    let encoding = Encoding::for_label(decoded_with.as_bytes())?;
+    if encoding.output_encoding() != encoding {
+        return None;
     }
+    let (cow, _, had_unmappable) = encoding.encode(contents);
+    Some((!had_unmappable).then(|| cow.into_owned()))
 }
 
 
@@ -233,8 +831,68 @@ pub(crate) enum FileProcessError {
     },
     FileNotFound {
         filename: PathBuf
-    }
-
+    },
+    BatchFailed {
+        failed_files: usize,
+        total_files: usize
+    },
+    TooManyFailedFiles {
+        failed_files: usize,
+        attempted_files: usize,
+        total_files: usize
+    },
+    OutputExists {
+        filename: PathBuf
+    },
+    WriteVerifyFailed {
+        filename: PathBuf,
+        expected_lines: usize,
+        actual_lines: usize
+    },
+    RoundtripMismatch {
+        chunk_num: usize,
+        expected_bytes: usize,
+        actual_bytes: usize
+    },
+    InvalidOutputUtf8 {
+        blob_id: String
+    },
+    CorruptBlob {
+        filename: PathBuf,
+        reason: String
+    },
+    DecodeFailed {
+        blob_id: String,
+        reason: String
+    },
+    TooManyDecodeErrors {
+        decode_errors: usize,
+        rows: usize
+    },
+    TooManyRows {
+        num_rows: usize,
+        max_rows: usize
+    },
+    RowCountMismatch {
+        filename: PathBuf,
+        declared_rows: usize,
+        accounted_rows: usize,
+        stats: JsonValue
+    },
+    MissingColumns {
+        missing: Vec<String>,
+        available: Vec<String>
+    },
+    LowDiskSpace {
+        path: PathBuf,
+        free_bytes: u64,
+        min_free_bytes: u64
+    },
+    SchemaChanged {
+        filename: PathBuf,
+        first_filename: PathBuf,
+        differences: Vec<String>
+    },
 }
 
 impl fmt::Display for FileProcessError {
@@ -246,34 +904,205 @@ impl fmt::Display for FileProcessError {
             FileProcessError::TooManyMissing { missing_count, total_files } => {
                 write!(f, "Too many files missing: {}/{}", missing_count, total_files)
             }
+            FileProcessError::BatchFailed { failed_files, total_files } => {
+                write!(f, "Failed to process {}/{} parquet files", failed_files, total_files)
+            }
+            FileProcessError::TooManyFailedFiles { failed_files, attempted_files, total_files } => {
+                write!(f, "Aborted the batch: {} of the first {} parquet files failed ({} in all)", failed_files, attempted_files, total_files)
+            }
+            FileProcessError::TooManyRows { num_rows, max_rows } => {
+                write!(f, "Parquet has {} rows, more than --max-rows-in-memory {}. Try --streaming (or --low-memory)", num_rows, max_rows)
+            }
+            FileProcessError::RowCountMismatch { filename, declared_rows, accounted_rows, stats } => {
+                write!(f, "Parquet {} declares {} rows, but {} were accounted for (written, missing, or left out on purpose): {}", filename.display(), declared_rows, accounted_rows, stats)
+            }
+            FileProcessError::MissingColumns { missing, available } => {
+                write!(f, "Parquet is missing required column(s) {}; it has {}", missing.join(", "), available.join(", "))
+            }
+            FileProcessError::CorruptBlob { filename, reason } => {
+                write!(f, "Corrupt blob {}: {}", filename.display(), reason)
+            }
+            FileProcessError::DecodeFailed { blob_id, reason } => {
+                write!(f, "Couldn't decode blob {}: {}", blob_id, reason)
+            }
+            FileProcessError::TooManyDecodeErrors { decode_errors, rows } => {
+                write!(f, "Too many rows failed to decode: {}/{} (is the file's src_encoding metadata wrong?)", decode_errors, rows)
+            }
+            FileProcessError::InvalidOutputUtf8 { blob_id } => {
+                write!(f, "Row with blob_id {} serialized to invalid utf-8", blob_id)
+            }
+            FileProcessError::OutputExists { filename } => {
+                write!(f, "Output {} already exists (pass --overwrite to replace it)", filename.display())
+            }
+            FileProcessError::WriteVerifyFailed { filename, expected_lines, actual_lines } => {
+                write!(f, "Re-reading {} after writing it gave {} lines, expected {}", filename.display(), actual_lines, expected_lines)
+            }
+            FileProcessError::RoundtripMismatch { chunk_num, expected_bytes, actual_bytes } => {
+                write!(f, "Chunk {} didn't survive a compression roundtrip: expected {} bytes, got {} back (or the bytes differ)", chunk_num, expected_bytes, actual_bytes)
+            }
+            FileProcessError::LowDiskSpace { path, free_bytes, min_free_bytes } => {
+                write!(f, "Only {} bytes free on the filesystem of {}, below --min-free-bytes {}; stopping before the volume fills", free_bytes, path.display(), min_free_bytes)
+            }
+            FileProcessError::SchemaChanged { filename, first_filename, differences } => {
+                write!(f, "Schema of {} differs from the first file's ({}): {}", filename.display(), first_filename.display(), differences.join("; "))
+            }
         }
     }
 }
 impl std::error::Error for FileProcessError {}
 
+impl FileProcessError {
+    /// Stable snake_case name and the variant's fields, for --error-format json
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            FileProcessError::TooManyMissing { missing_count, total_files } => json!({"code": "too_many_missing", "missing_count": missing_count, "total_files": total_files}),
+            FileProcessError::FileNotFound { filename } => json!({"code": "file_not_found", "filename": filename}),
+            FileProcessError::BatchFailed { failed_files, total_files } => json!({"code": "batch_failed", "failed_files": failed_files, "total_files": total_files}),
+            FileProcessError::TooManyFailedFiles { failed_files, attempted_files, total_files } => json!({"code": "too_many_failed_files", "failed_files": failed_files, "attempted_files": attempted_files, "total_files": total_files}),
+            FileProcessError::OutputExists { filename } => json!({"code": "output_exists", "filename": filename}),
+            FileProcessError::WriteVerifyFailed { filename, expected_lines, actual_lines } => json!({"code": "write_verify_failed", "filename": filename, "expected_lines": expected_lines, "actual_lines": actual_lines}),
+            FileProcessError::RoundtripMismatch { chunk_num, expected_bytes, actual_bytes } => json!({"code": "roundtrip_mismatch", "chunk_num": chunk_num, "expected_bytes": expected_bytes, "actual_bytes": actual_bytes}),
+            FileProcessError::InvalidOutputUtf8 { blob_id } => json!({"code": "invalid_output_utf8", "blob_id": blob_id}),
+            FileProcessError::CorruptBlob { filename, reason } => json!({"code": "corrupt_blob", "filename": filename, "reason": reason}),
+            FileProcessError::DecodeFailed { blob_id, reason } => json!({"code": "decode_failed", "blob_id": blob_id, "reason": reason}),
+            FileProcessError::TooManyDecodeErrors { decode_errors, rows } => json!({"code": "too_many_decode_errors", "decode_errors": decode_errors, "rows": rows}),
+            FileProcessError::TooManyRows { num_rows, max_rows } => json!({"code": "too_many_rows", "num_rows": num_rows, "max_rows": max_rows}),
+            FileProcessError::RowCountMismatch { filename, declared_rows, accounted_rows, stats } => json!({"code": "row_count_mismatch", "filename": filename, "declared_rows": declared_rows, "accounted_rows": accounted_rows, "stats": stats}),
+            FileProcessError::MissingColumns { missing, available } => json!({"code": "missing_columns", "missing": missing, "available": available}),
+            FileProcessError::LowDiskSpace { path, free_bytes, min_free_bytes } => json!({"code": "low_disk_space", "path": path, "free_bytes": free_bytes, "min_free_bytes": min_free_bytes}),
+            FileProcessError::SchemaChanged { filename, first_filename, differences } => json!({"code": "schema_changed", "filename": filename, "first_filename": first_filename, "differences": differences}),
+        }
+    }
+}
+
 
-pub(crate) fn read_gzip_file(path: &PathBuf) -> Result<Vec<u8>> {
+pub(crate) fn read_blob_file(path: &PathBuf) -> Result<Vec<u8>> {
     // Open the file
     if !path.exists() {
         return Err(FileProcessError::FileNotFound { filename: path.clone() }.into());
 
     }
 
-    let file = File::open(path)?;
-    
-    // Create a GzDecoder wrapping the file
-    let mut gz = GzDecoder::new(file);
+    decompress_blob(fs::read(path)?, path)
+}
+
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+pub(crate) fn is_compressed_blob(bytes: &[u8]) -> bool {
+    // Whether decompress_blob would decompress bytes, rather than pass them through as raw
+    bytes.starts_with(&GZIP_MAGIC) || bytes.starts_with(&ZSTD_MAGIC)
+}
+
+
+pub(crate) fn decompress_blob(bytes: Vec<u8>, path: &Path) -> Result<Vec<u8>> {
+    /* A blob's bytes, decompressed according to its magic bytes rather than its file name, so one blob store can
+    mix gzip, zstd and raw (uncompressed) blobs: gzip starts 1f 8b, zstd 28 b5 2f fd, and anything else is taken
+    to already be raw. path is only for error messages
+    */
+    if bytes.starts_with(&GZIP_MAGIC) {
+        return decode_gzip(bytes.as_slice(), path);
+    }
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        return zstd::stream::decode_all(bytes.as_slice())
+            .map_err(|e| FileProcessError::CorruptBlob { filename: path.to_path_buf(), reason: e.to_string() }.into());
+    }
+    Ok(bytes)
+}
+
+
+// How much of a streamed blob (stream_decode_blob) is read, decompressed and decoded at a time
+const STREAM_BUFFER_BYTES: usize = 1 << 16;
+
+pub(crate) fn stream_decode_blob(path: &Path, blob_id: &str, encoding_name: &str, lossy: bool, mut on_text: impl FnMut(&str)) -> Result<&'static str> {
+    /* read_blob_file then decode_to_string, one buffer at a time, for blobs too big to hold whole: hands each piece
+    of decoded text to on_text as it goes, and returns the name of the decoder used. Fails the way those two would,
+    with decode failures as DecodeFailed (by which point on_text has already had some of the text)
+    */
+    if !path.exists() {
+        return Err(FileProcessError::FileNotFound { filename: path.to_path_buf() }.into());
+    }
+    let decode_failed = |reason: String| -> Error { FileProcessError::DecodeFailed { blob_id: blob_id.to_string(), reason }.into() };
+    let decoder = TextDecoder::for_name(encoding_name).map_err(|e| decode_failed(e.to_string()))?;
+
+    // Sniff the magic bytes like decompress_blob, then put them back in front of the rest of the file
+    let mut file = File::open(path)?;
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut file).take(ZSTD_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    let is_gzip = magic.starts_with(&GZIP_MAGIC);
+    let is_zstd = magic.starts_with(&ZSTD_MAGIC);
+    let raw = std::io::Cursor::new(magic).chain(file);
+    let mut reader: Box<dyn Read> = match (is_gzip, is_zstd) {
+        (true, _) => Box::new(MultiGzDecoder::new(raw)),
+        (_, true) => Box::new(zstd::stream::Decoder::new(raw)?),
+        _ => Box::new(raw),
+    };
+
+    // encoding_rs's streaming decoder carries a multi-byte char split across buffers over to the next one
+    let mut stream_decoder = match decoder {
+        TextDecoder::EncodingRs(encoding) => Some(encoding.new_decoder()),
+        TextDecoder::Table(..) => None,
+    };
+    let mut buffer = vec![0; STREAM_BUFFER_BYTES];
+    let mut decoded = String::new();
+    loop {
+        let num_read = match reader.read(&mut buffer) {
+            Ok(num_read) => num_read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(match e.kind() {
+                _ if is_zstd => FileProcessError::CorruptBlob { filename: path.to_path_buf(), reason: e.to_string() }.into(),
+                ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::UnexpectedEof if is_gzip => {
+                    FileProcessError::CorruptBlob { filename: path.to_path_buf(), reason: e.to_string() }.into()
+                },
+                _ => e.into(),
+            }),
+        };
+        let last = num_read == 0;
+        match (&decoder, &mut stream_decoder) {
+            (TextDecoder::Table(table, _), _) => on_text(&decode_string_complete_table(&buffer[..num_read], table)),
+            (_, Some(stream_decoder)) => {
+                decoded.clear();
+                decoded.reserve(stream_decoder.max_utf8_buffer_length(num_read).unwrap_or(num_read * 3 + 16));
+                let (_, _, had_errors) = stream_decoder.decode_to_string(&buffer[..num_read], &mut decoded, last);
+                if had_errors && !lossy {
+                    return Err(decode_failed(format!("Failed to decode bytes using {} encoding", encoding_name)));
+                }
+                on_text(&decoded);
+            },
+            (TextDecoder::EncodingRs(_), None) => unreachable!(),
+        }
+        if last {
+            return Ok(decoder.name());
+        }
+    }
+}
+
+
+pub(crate) fn decode_gzip<R: Read>(reader: R, path: &Path) -> Result<Vec<u8>> {
+    // Gunzips a whole blob (from a file or a fetched body); path is only for error messages.
+    // Every member of a multi-member (concatenated) gzip, like `gzip -d`: GzDecoder would stop after the first
+    let mut gz = MultiGzDecoder::new(reader);
     
     // Create a buffer to store the decompressed data
     let mut buffer = Vec::new();
     
-    // Read the decompressed data into the buffer
-    gz.read_to_end(&mut buffer)?;
+    // Read the decompressed data into the buffer. Each member's footer CRC32 and length are checked as it hits them;
+    // those failures (and bad deflate data / truncation) come back as InvalidInput/InvalidData/UnexpectedEof
+    if let Err(e) = gz.read_to_end(&mut buffer) {
+        return Err(match e.kind() {
+            ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+                FileProcessError::CorruptBlob { filename: path.to_path_buf(), reason: e.to_string() }.into()
+            },
+            _ => e.into(),
+        });
+    }
     
     Ok(buffer)
 }
 
 
+#[allow(dead_code)]
 pub(crate) fn write_string_gzip(content: String, path: PathBuf) -> Result<(), Error> {
     // Use a large buffer (8MB) for better performance
     const BUFFER_SIZE: usize = 16 * 1024 * 1024;
@@ -292,17 +1121,458 @@ pub(crate) fn write_string_gzip(content: String, path: PathBuf) -> Result<(), Er
     Ok(())
 }
 
-pub(crate) fn write_bytes(content: Vec<u8>, path: PathBuf) -> Result<(), Error> {    
+pub(crate) fn write_bytes(content: Vec<u8>, path: PathBuf, fsync: bool) -> Result<(), Error> {    
+    /* With fsync, the file's data and then its parent directory's entry are flushed to disk
+    before returning, so a file that was written survives a crash/power loss
+    */
     if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).unwrap();
+        fs::create_dir_all(parent)?;
     }
-    let mut file = File::create(path).unwrap();
+    let mut file = File::create(&path)?;
 
-    file.write_all(&content).unwrap();
+    file.write_all(&content)?;
+    if fsync {
+        file.sync_all()?;
+        sync_parent_dir(&path)?;
+    }
 
+    Ok(())
+}
+
+
+pub(crate) fn write_bytes_atomic(content: Vec<u8>, path: &Path, tmp_dir: Option<&Path>, fsync: bool) -> Result<(), Error> {
+    // Writes to a temp file and renames it into place, so path is either absent or complete
+    write_bytes_atomic_verified(content, path, tmp_dir, fsync, |_| Ok(()))
+}
 
+
+pub(crate) fn write_bytes_atomic_verified<F>(content: Vec<u8>, path: &Path, tmp_dir: Option<&Path>, fsync: bool, verify: F) -> Result<(), Error>
+where
+    F: FnOnce(&Path) -> Result<(), Error>,
+{
+    // Same as write_bytes_atomic, but verify gets to check the temp file first; if it fails, the temp file is deleted
+    let tmp_path = tmp_path_for(path, tmp_dir)?;
+    write_bytes(content, tmp_path.clone(), fsync)?;
+    if let Err(e) = verify(&tmp_path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    rename_into_place(&tmp_path, path, fsync)
+}
+
+
+pub(crate) fn tmp_path_for(path: &Path, tmp_dir: Option<&Path>) -> Result<PathBuf, Error> {
+    /* Temp file for an output that will be renamed to path: in tmp_dir if given, otherwise next to path
+    (same directory => same filesystem => the rename is atomic)
+    */
+    let file_name = path.file_name().ok_or_else(|| Error::msg(format!("No file name in {:?}", path)))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let dir = match tmp_dir {
+        Some(tmp_dir) => tmp_dir,
+        None => path.parent().unwrap_or(Path::new(".")),
+    };
+    fs::create_dir_all(dir)?;
+    Ok(dir.join(tmp_name))
+}
+
+
+pub(crate) fn rename_into_place(tmp_path: &Path, path: &Path, fsync: bool) -> Result<(), Error> {
+    // rename(2) can't cross filesystems, in which case this falls back to a (non-atomic) copy
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(tmp_path, path) {
+        Ok(()) => {},
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            fs::copy(tmp_path, path)?;
+            if fsync {
+                File::open(path)?.sync_all()?;
+            }
+            fs::remove_file(tmp_path)?;
+        },
+        Err(e) => return Err(e.into()),
+    }
+    if fsync {
+        sync_parent_dir(path)?;
+    }
     Ok(())
 }
 
 
+pub(crate) fn same_filesystem(a: &Path, b: &Path) -> Result<bool, Error> {
+    Ok(fs::metadata(a)?.dev() == fs::metadata(b)?.dev())
+}
+
+
+pub(crate) fn free_bytes(path: &Path) -> Result<u64, Error> {
+    // Space available to us (not root) on path's filesystem, via statvfs. Goes up to the nearest existing
+    // ancestor, so this also works for output directories that haven't been created yet
+    let existing = path.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(Path::new("."));
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+
+fn sync_parent_dir(path: &Path) -> Result<(), Error> {
+    // A new file's directory entry is only durable once the directory itself is fsync'ed
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+
+
+pub(crate) fn read_lines_to_set(path: &Path) -> Result<HashSet<String>, Error> {
+    // Reads a (plaintext) file into a set of its non-empty, trimmed lines
+    let reader = BufReader::new(File::open(path)?);
+    let mut lines = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() {
+            lines.insert(line.to_string());
+        }
+    }
+    Ok(lines)
+}
+
 
+
+pub(crate) fn append_line(path: &Path, line: &str) -> Result<(), Error> {
+    /* Appends a single line to path (creating it if needed).
+    The line goes out in a single write to an O_APPEND file, so concurrent 
+    appenders (threads or processes) won't interleave partial lines
+    */
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = line.to_string();
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+
+
+pub(crate) fn read_jsonl_file(path: &Path) -> Result<Vec<JsonValue>, Error> {
+    // Reads a jsonl into rows, decompressing based on the extension (.zstd/.zst, .gz, or otherwise plaintext)
+    let file = File::open(path)?;
+    let reader: Box<dyn BufRead> = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("zstd") | Some("zst") => Box::new(BufReader::new(zstd::stream::read::Decoder::new(file)?)),
+        Some("gz") => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        _ => Box::new(BufReader::new(file)),
+    };
+    let mut rows = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            rows.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(rows)
+}
+
+
+
+pub(crate) fn write_json_atomic(path: &Path, value: &JsonValue, fsync: bool) -> Result<(), Error> {
+    // Writes to a sibling temp file and renames it over path, so readers never see a partial file.
+    // With fsync, like write_bytes: a manifest saying a file is done is only durable after the file itself is
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    write_bytes(value.to_string().into_bytes(), tmp_path.clone(), fsync)?;
+    fs::rename(&tmp_path, path)?;
+    if fsync {
+        sync_parent_dir(path)?;
+    }
+    Ok(())
+}
+
+
+
+/*=============================================================
+=                        FILE DISCOVERY                       =
+=============================================================*/
+
+fn discover_files_under(dir: &Path, extension: &str, spinner: &ProgressBar) -> Result<Vec<PathBuf>, Error> {
+    // Recursive helper: files are collected here, subdirectories get walked in parallel
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut subdirs: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            subdirs.push(path);
+        } else if path.extension().is_some_and(|ext| ext == extension) {
+            spinner.inc(1);
+            files.push(path);
+        }
+    }
+
+    let nested: Result<Vec<Vec<PathBuf>>, Error> = subdirs
+        .par_iter()
+        .map(|subdir| discover_files_under(subdir, extension, spinner))
+        .collect();
+    files.extend(nested?.into_iter().flatten());
+    Ok(files)
+}
+
+
+pub(crate) static NO_PROGRESS: OnceLock<bool> = OnceLock::new();
+pub(crate) static PROGRESS_REFRESH_HZ: OnceLock<u8> = OnceLock::new();
+
+
+pub(crate) fn progress_draw_target() -> ProgressDrawTarget {
+    // Where every progress bar and spinner draws, per --no-progress and --progress-refresh-hz.
+    // Always stderr (indicatif's default, made explicit): stdout carries the log lines and head's JSON
+    match NO_PROGRESS.get() {
+        Some(true) => ProgressDrawTarget::hidden(),
+        _ => ProgressDrawTarget::stderr_with_hz(progress_refresh_hz()),
+    }
+}
+
+
+fn progress_refresh_hz() -> u8 {
+    *PROGRESS_REFRESH_HZ.get().unwrap_or(&20)
+}
+
+
+pub(crate) fn discover_files(input_dir: &Path, extension: &str) -> Result<Vec<PathBuf>, Error> {
+    /* Walks input_dir (in parallel, one rayon task per subdirectory) and 
+    returns every *.<extension> file underneath it, sorted so runs are deterministic
+    */
+    let template = format!("{{spinner}} Discovering .{} files... {{human_pos}} found [{{elapsed_precise}}]", extension);
+    let spinner = ProgressBar::with_draw_target(None, progress_draw_target())
+        .with_style(ProgressStyle::with_template(&template).unwrap());
+    if !spinner.is_hidden() {
+        // Ticks as often as the bars redraw
+        spinner.enable_steady_tick(Duration::from_millis(1000 / progress_refresh_hz() as u64));
+    }
+
+    let mut files = discover_files_under(input_dir, extension, &spinner)?;
+    spinner.finish();
+    files.par_sort();
+    Ok(files)
+}
+
+
+pub(crate) fn list_blob_dir(blob_dir: &Path) -> Result<HashSet<String>, Error> {
+    // Every .gz and .zst under blob_dir (shard subdirectories included), as paths relative to blob_dir
+    let mut files = discover_files(blob_dir, "gz")?;
+    files.extend(discover_files(blob_dir, "zst")?);
+    Ok(files.iter()
+        .filter_map(|file| file.strip_prefix(blob_dir).ok())
+        .map(|relative| relative.to_string_lossy().into_owned())
+        .collect())
+}
+
+
+pub(crate) fn discover_parquet_files(input_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    discover_files(input_dir, "parquet")
+}
+
+
+/// What the row metadata files are: parquets, or jsonls of one JSON object per row (same columns, same layout on disk)
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputFormat {
+    Parquet,
+    Jsonl,
+    #[value(name="jsonl.zstd")]
+    JsonlZstd,
+}
+
+
+pub(crate) fn discover_input_files(input_dir: &Path, input_format: InputFormat) -> Result<Vec<PathBuf>, Error> {
+    match input_format {
+        InputFormat::Parquet => discover_parquet_files(input_dir),
+        InputFormat::Jsonl => discover_files(input_dir, "jsonl"),
+        InputFormat::JsonlZstd => {
+            let mut files = discover_files(input_dir, "zstd")?;
+            files.retain(|file| file.to_string_lossy().ends_with(".jsonl.zstd"));
+            Ok(files)
+        },
+    }
+}
+
+
+pub(crate) fn default_hf_cache() -> Option<PathBuf> {
+    // Same lookup order as huggingface_hub: $HF_HUB_CACHE, then $HF_HOME/hub, then ~/.cache/huggingface/hub
+    if let Some(cache) = std::env::var_os("HF_HUB_CACHE") {
+        return Some(PathBuf::from(cache));
+    }
+    if let Some(hf_home) = std::env::var_os("HF_HOME") {
+        return Some(PathBuf::from(hf_home).join("hub"));
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache").join("huggingface").join("hub"))
+}
+
+
+pub(crate) fn resolve_hf_snapshot(hf_cache: &Path, repo_id: &str, revision: &str) -> Result<PathBuf, Error> {
+    /* Finds a dataset repo's snapshot directory in a local HuggingFace hub cache, laid out like
+    <HF_CACHE>/datasets--<ORG>--<NAME>/snapshots/<COMMIT>/...
+    revision is a branch/tag (resolved through refs/<revision>) or a commit hash
+    */
+    let repo_dir = hf_cache.join(format!("datasets--{}", repo_id.replace('/', "--")));
+    let commit = match fs::read_to_string(repo_dir.join("refs").join(revision)) {
+        Ok(commit) => commit.trim().to_string(),
+        Err(_) => revision.to_string(),
+    };
+    let snapshot = repo_dir.join("snapshots").join(commit);
+    if !snapshot.is_dir() {
+        return Err(FileProcessError::FileNotFound { filename: snapshot }.into());
+    }
+    Ok(snapshot)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn read_options(binary_encoding: BinaryEncoding) -> ParquetReadOptions {
+        ParquetReadOptions {
+            batch_size: 1024,
+            use_mmap: false,
+            binary_encoding,
+            time_format: TimeFormat::Iso,
+            float_precision: None,
+            unsupported_column_fallback: None,
+            add_row_group_index: false,
+        }
+    }
+
+    fn column_json(column: arrow::array::ArrayRef, read_options: &ParquetReadOptions) -> Vec<JsonValue> {
+        (0..column.len()).map(|row_idx| convert_column_to_json(&column, row_idx, read_options).unwrap()).collect()
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decodes_cp437_box_drawing() {
+        let (decoded, decoder) = decode_to_string(&[0xC9, 0xCD, 0xBB], "CP437", false).unwrap();
+        assert_eq!(decoded, "╔═╗");
+        assert_eq!(decoder, "IBM437");
+    }
+
+    #[test]
+    fn decodes_iso_2022_jp() {
+        let bytes = b"\x1b$B\x30\x21\x1b(B";
+        let (decoded, decoder) = decode_to_string(bytes, "ISO-2022-JP", false).unwrap();
+        assert_eq!(decoded, "亜");
+        assert_eq!(decoder, "ISO-2022-JP");
+        // Aliases resolve to the same decoder, case-insensitively
+        assert_eq!(decode_to_string(bytes, "csiso2022jp", false).unwrap(), ("亜".to_string(), "ISO-2022-JP"));
+    }
+
+    #[test]
+    fn gunzips_every_member() {
+        let mut bytes = gzip(b"first member\n");
+        bytes.extend(gzip(b"second member\n"));
+        assert_eq!(decode_gzip(bytes.as_slice(), Path::new("blob.gz")).unwrap(), b"first member\nsecond member\n");
+    }
+
+    #[test]
+    fn corrupt_gzip_footer_is_corrupt_blob() {
+        let mut bytes = gzip(b"some contents\n");
+        let crc_start = bytes.len() - 8; // the footer: CRC32, then the length
+        bytes[crc_start] ^= 0xff;
+        let err = decode_gzip(bytes.as_slice(), Path::new("blob.gz")).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(FileProcessError::CorruptBlob { .. })), "{:?}", err);
+    }
+
+    #[test]
+    fn decompresses_blobs_by_magic_not_extension() {
+        let contents = b"def f():\n    return 1\n".to_vec();
+        let zstd_bytes = zstd::encode_all(contents.as_slice(), 0).unwrap();
+        assert_eq!(decompress_blob(gzip(&contents), Path::new("blob.zst")).unwrap(), contents);
+        assert_eq!(decompress_blob(zstd_bytes, Path::new("blob.gz")).unwrap(), contents);
+        for name in ["blob.gz", "blob.zst"] {
+            assert_eq!(decompress_blob(contents.clone(), Path::new(name)).unwrap(), contents);
+        }
+    }
+
+    #[test]
+    fn converts_fixed_size_binary() {
+        let sha1: Vec<u8> = (0..20).collect();
+        let column = std::sync::Arc::new(FixedSizeBinaryArray::try_from_sparse_iter_with_size([Some(sha1.as_slice()), None].into_iter(), 20).unwrap());
+        assert_eq!(column_json(column.clone(), &read_options(BinaryEncoding::Hex)),
+                   vec![json!("000102030405060708090a0b0c0d0e0f10111213"), JsonValue::Null]);
+        assert_eq!(column_json(column, &read_options(BinaryEncoding::Base64)),
+                   vec![json!("AAECAwQFBgcICQoLDA0ODxAREhM="), JsonValue::Null]);
+    }
+
+    #[test]
+    fn converts_maps() {
+        use arrow::array::{Int64Builder, MapBuilder, StringBuilder};
+        let mut builder = MapBuilder::new(None, StringBuilder::new(), Int64Builder::new());
+        builder.keys().append_value("stars");
+        builder.values().append_value(3);
+        builder.keys().append_value("forks");
+        builder.values().append_null();
+        builder.append(true).unwrap();
+        builder.append(false).unwrap();
+        builder.append(true).unwrap();
+        let column = std::sync::Arc::new(builder.finish());
+        assert_eq!(column_json(column, &read_options(BinaryEncoding::Hex)),
+                   vec![json!({"stars": 3, "forks": null}), JsonValue::Null, json!({})]);
+    }
+
+    #[test]
+    fn converts_times() {
+        let iso = read_options(BinaryEncoding::Hex);
+        let raw = ParquetReadOptions { time_format: TimeFormat::Raw, ..iso };
+        let seconds = 13 * 3600 + 5 * 60 + 9; // 13:05:09
+        let columns: [(arrow::array::ArrayRef, &str, i64); 4] = [
+            (std::sync::Arc::new(Time32SecondArray::from(vec![Some(seconds as i32), None])), "13:05:09", seconds),
+            (std::sync::Arc::new(Time32MillisecondArray::from(vec![Some((seconds * 1000 + 250) as i32), None])), "13:05:09.250", seconds * 1000 + 250),
+            (std::sync::Arc::new(Time64MicrosecondArray::from(vec![Some(seconds * 1_000_000 + 250), None])), "13:05:09.000250", seconds * 1_000_000 + 250),
+            (std::sync::Arc::new(Time64NanosecondArray::from(vec![Some(seconds * 1_000_000_000 + 250), None])), "13:05:09.000000250", seconds * 1_000_000_000 + 250),
+        ];
+        for (column, expected_iso, expected_raw) in columns {
+            assert_eq!(column_json(column.clone(), &iso), vec![json!(expected_iso), JsonValue::Null]);
+            assert_eq!(column_json(column, &raw), vec![json!(expected_raw), JsonValue::Null]);
+        }
+    }
+
+    #[test]
+    fn converts_lists_of_structs() {
+        use arrow::array::{Int64Builder, ListBuilder, StringBuilder, StructBuilder};
+        let fields = vec![Field::new("name", DataType::Utf8, true), Field::new("line", DataType::Int64, true)];
+        let mut builder = ListBuilder::new(StructBuilder::from_fields(fields, 2));
+        for (name, line) in [("main", 1), ("helper", 12)] {
+            let entry = builder.values();
+            entry.field_builder::<StringBuilder>(0).unwrap().append_value(name);
+            entry.field_builder::<Int64Builder>(1).unwrap().append_value(line);
+            entry.append(true);
+        }
+        builder.append(true);
+        builder.append(false);
+        let column = std::sync::Arc::new(builder.finish());
+        assert_eq!(column_json(column, &read_options(BinaryEncoding::Hex)),
+                   vec![json!([{"name": "main", "line": 1}, {"name": "helper", "line": 12}]), JsonValue::Null]);
+    }
+
+    #[test]
+    fn null_encoding_labels_are_unknown() {
+        for label in [None, Some(""), Some("null"), Some("NULL"), Some(" None ")] {
+            assert_eq!(encoding_label(label), None, "{:?}", label);
+        }
+        assert_eq!(encoding_label(Some(" utf-8 ")), Some("utf-8"));
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::OnceLock;
+use clap::ValueEnum;
+use serde_json::{json, Value as JsonValue};
+
+
+/*==============================================================
+=                            LOGGING                           =
+==============================================================*/
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    /// Plain text messages
+    Human,
+    /// One JSON object per event: level, timestamp, message, plus any event-specific fields
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+static LOG_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+static LOG_TO_STDERR: OnceLock<bool> = OnceLock::new();
+
+
+pub(crate) fn init_logging(format: LogFormat, to_stderr: bool) {
+    // Can only be set once, at startup. Until then (or if never called) we log human-readable text to stdout.
+    // to_stderr is for when stdout carries the rows themselves (--stdout)
+    let _ = LOG_FORMAT.set(format);
+    let _ = LOG_TO_STDERR.set(to_stderr);
+}
+
+
+fn emit(line: String) {
+    match LOG_TO_STDERR.get().copied().unwrap_or(false) {
+        true => eprintln!("{}", line),
+        false => println!("{}", line),
+    }
+}
+
+
+pub(crate) fn log_event(level: Level, message: String, fields: JsonValue) {
+    match LOG_FORMAT.get().copied().unwrap_or(LogFormat::Human) {
+        LogFormat::Human => match level {
+            Level::Info => emit(message),
+            _ => emit(format!("{}: {}", level.as_str().to_uppercase(), message)),
+        },
+        LogFormat::Json => {
+            let mut event = json!({
+                "level": level.as_str(),
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "message": message,
+            });
+            if let (Some(event), JsonValue::Object(fields)) = (event.as_object_mut(), fields) {
+                event.extend(fields);
+            }
+            emit(event.to_string());
+        },
+    }
+}
+
+
+/// log_info!(json!({..fields..}), "format string", args...)
+macro_rules! log_info {
+    ($fields:expr, $($arg:tt)+) => {
+        $crate::logging::log_event($crate::logging::Level::Info, format!($($arg)+), $fields)
+    };
+}
+
+macro_rules! log_warn {
+    ($fields:expr, $($arg:tt)+) => {
+        $crate::logging::log_event($crate::logging::Level::Warn, format!($($arg)+), $fields)
+    };
+}
+
+macro_rules! log_error {
+    ($fields:expr, $($arg:tt)+) => {
+        $crate::logging::log_event($crate::logging::Level::Error, format!($($arg)+), $fields)
+    };
+}
+
+pub(crate) use {log_info, log_warn, log_error};
@@ -1,18 +1,53 @@
-use std::path::PathBuf;
-use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use anyhow::{Result, Error};
-use crate::io::{load_parquet_as_json_parallel, read_gzip_file, decode_to_string, write_bytes, FileProcessError};
-use serde_json::{Value as JsonValue};
+use regex::Regex;
+use crate::rows::{sort_rows, shuffle_rows, group_rows, partition_rows, split_rows_by, split_rows_by_column, RowSampler, SortSpec};
+use crate::dedup::{dedup_key, DedupBy, DedupSet};
+use crate::extensions::add_ext_language;
+use crate::stats::{file_stats_batch, ErrorLimit, FileStatsRow, ProcessStats, ReencodeCounts, SkipReason, SlowestRows};
+use arrow::record_batch::RecordBatch;
+use arrow::datatypes::SchemaRef;
+use crate::hashing::{in_hash_sample, ChecksumAlgorithm, HashAlgorithm};
+use crate::contents::{looks_binary, replace_contents, truncate_contents, ContentsLength, ContentsReplace};
+use crate::ratelimit::RateLimiter;
+use crate::http::{check_url, http_get};
+use crate::codec::{train_dict, Codec};
+use crate::logging::{init_logging, log_info, log_warn, log_error, LogFormat};
+use crate::io::{load_parquet_as_json_parallel, ParquetChunkReader, read_blob_file, stream_decode_blob, decompress_blob, is_compressed_blob, decode_to_string, encode_from_string, encoding_label, write_bytes, write_bytes_atomic, write_bytes_atomic_verified, tmp_path_for, rename_into_place, same_filesystem, free_bytes, discover_parquet_files, discover_input_files, discover_files, read_lines_to_set, read_jsonl_file, progress_draw_target, NO_PROGRESS, PROGRESS_REFRESH_HZ, read_parquet_schema, schema_differences, field_json_schema, nullable_json_schema, read_parquet_num_rows, read_parquet_file_metadata, read_parquet_key_value, read_parquet_batches, count_parquet_column_values, init_reader_pool, on_reader_pool, write_parquet_atomic, list_blob_dir, default_hf_cache, resolve_hf_snapshot, write_json_atomic, append_line, BinaryEncoding, TimeFormat, InputFormat, ParquetReadOptions, UnsupportedColumnFallback, FileProcessError};
+use serde_json::{json, Value as JsonValue};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
-use std::time::Instant;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use zstd::stream::encode_all;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zstd::stream::{encode_all, Encoder};
 use zstd::DEFAULT_COMPRESSION_LEVEL;
+use base64::prelude::*;
 
+pub mod codec;
+pub mod contents;
+pub mod dedup;
+pub mod extensions;
+pub mod hashing;
+pub mod http;
 pub mod io;
+pub mod logging;
+pub mod ratelimit;
+pub mod rows;
+pub mod stats;
 
+const LOW_MEMORY_BATCH_SIZE: usize = 128;
+const LOW_MEMORY_MAX_LINES: usize = 2048;
+const COMPRESSION_SAMPLE_ROWS: usize = 256; // rows trial-compressed to seed the --target-file-bytes estimate
+const DATASET_MANIFEST_VERSION: u32 = 1; // bump on any incompatible change to the --dataset-manifest layout
+const FILE_MANIFEST_VERSION: u32 = 1; // likewise for --emit-file-manifest
+const LANGUAGE_MANIFEST_VERSION: u32 = 1; // and for --per-language-manifest
 const MISSING_THRESHOLD: f64 = 0.01; 
+const MAX_FETCH_BACKOFF_MS: u64 = 10_000; // --blob-fetch-retries' longest wait between attempts
 // We're okay if 1% of the rows 404, but if it's > than that, it probably means 
 // that we didn't actually download the data
 
@@ -27,165 +62,3649 @@ struct ArgParser {
     #[clap(subcommand)]
     command: Commands,
 
-    #[arg(long, default_value_t=0)]
+    /// Size of the worker pool. 0 or "auto" (the default) uses the container's CPU quota (cgroup v2 cpu.max)
+    /// if there is one, and the number of CPUs otherwise
+    #[arg(long, default_value="auto", value_parser=parse_thread_count)]
     threads: usize,
+
+    /// Give parquet reading (decoding and converting rows to json) its own pool of this many threads, sized apart from
+    /// --threads, which then only does row processing (blob fetches, decoding, compression). By default they share one pool
+    #[arg(long, value_parser=clap::value_parser!(u16).range(1..))]
+    reader_threads: Option<u16>,
+
+    /// Human-readable log lines, or one JSON object per log event (for log pipelines)
+    #[arg(long, value_enum, default_value_t=LogFormat::Human)]
+    log_format: LogFormat,
+
+    /// Don't draw progress bars (or the file discovery spinner). The per-file "Finished" summary lines are logged either way
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Redraw progress bars (and tick the file discovery spinner) at most this many times a second
+    /// (indicatif's default is 20). Lower it on slow terminals or over SSH, where redraws cost more
+    #[arg(long, default_value_t=20, value_parser=clap::value_parser!(u8).range(1..))]
+    progress_refresh_hz: u8,
+
+    /// How a failed run reports its error: a panic with the error's debug form (human), or a single-line JSON object
+    /// on stderr with code, message, causes, input, and the error's counts (json), exiting with status 1
+    #[arg(long, value_enum, default_value_t=ErrorFormat::Human)]
+    error_format: ErrorFormat,
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    #[clap(arg_required_else_help = true)]
+    ProcessParquet { 
+        /// Which parquet file we're working with
+        /// Should be formatted like <DIR>/the-stack-v2/raw-hf-parquets/<PROGRAMMING_LANGUAGE>/train-0000-of-1234.parquet
+        #[arg(required=true, long)]
+        parquet_file: PathBuf,
+
+        /// Where the completed jsonls go
+        #[arg(required=true, long, default_value="/mnt/raid0/jsonls/")]
+        local_jsonl_dir: PathBuf,
+
+        #[command(flatten)]
+        options: ProcessOptions,
+    }, 
+
+    #[clap(arg_required_else_help = true)]
+    ProcessDirectory {
+        /// Base directory to (recursively) search for parquet files
+        /// Files found should still be laid out like <DIR>/the-stack-v2/raw-hf-parquets/<PROGRAMMING_LANGUAGE>/train-0000-of-1234.parquet
+        #[arg(required=true, long)]
+        input_dir: PathBuf,
+
+        /// Where the completed jsonls go
+        #[arg(required=true, long, default_value="/mnt/raid0/jsonls/")]
+        local_jsonl_dir: PathBuf,
+
+        #[command(flatten)]
+        dir_options: DirectoryOptions,
+
+        #[command(flatten)]
+        options: ProcessOptions,
+    },
+
+    /// Reruns the row filtering/reordering/partitioning and contents transforms over a .jsonl.zstd (or .jsonl.gz)
+    /// this tool already produced, without going back to the parquet + blobs
+    #[clap(arg_required_else_help = true)]
+    ReprocessJsonl {
+        /// Which jsonl we're reprocessing. Should be named like the outputs of process-parquet
+        #[arg(required=true, long)]
+        input_file: PathBuf,
+
+        /// Where the reprocessed jsonls go
+        #[arg(required=true, long)]
+        local_jsonl_dir: PathBuf,
+
+        #[command(flatten)]
+        options: ProcessOptions,
+    },
+
+    /// Reprocesses just the rows of a parquet that a previous run left out for a retryable reason (missing,
+    /// corrupt, or failed-to-decode blobs, per its --row-error-log), e.g. after their blobs were re-downloaded.
+    /// Recovered rows go to one supplemental <LANGUAGE>-<XXXX>.jsonl.zstd (as with --single-file), so point
+    /// --local-jsonl-dir somewhere other than the original outputs
+    #[clap(arg_required_else_help = true)]
+    RetryFailed {
+        /// Which parquet file the rows came from. Laid out like for process-parquet
+        #[arg(required=true, long)]
+        parquet_file: PathBuf,
+
+        /// The --row-error-log of the previous run. Entries for other parquets are harmless, as only blob_ids are matched
+        #[arg(required=true, long)]
+        failed_rows: PathBuf,
+
+        /// Where the supplemental jsonl goes
+        #[arg(required=true, long)]
+        local_jsonl_dir: PathBuf,
+
+        #[command(flatten)]
+        options: ProcessOptions,
+    },
+
+    /// Prints the arrow schema of a parquet file
+    #[clap(arg_required_else_help = true)]
+    Schema {
+        /// Which parquet file to describe
+        #[arg(required=true, long)]
+        parquet_file: PathBuf,
+
+        /// Print the schema as JSON (a list of {name, data_type, nullable}) instead of a human-readable dump
+        #[arg(long, default_value_t=false)]
+        print_schema_json: bool,
+    },
+
+    /// Prints the first rows of a parquet file as pretty JSON (the data-level counterpart to schema)
+    #[clap(arg_required_else_help = true)]
+    Head {
+        /// Which parquet file to look at. With --with-contents, laid out like for process-parquet
+        #[arg(required=true, long)]
+        parquet_file: PathBuf,
+
+        /// How many rows to print
+        #[arg(long, default_value_t=10)]
+        num_rows: usize,
+
+        /// Also fetch each row's contents from the blob store, exactly as process-parquet would (transforms included)
+        #[arg(long, default_value_t=false)]
+        with_contents: bool,
+
+        #[command(flatten)]
+        options: ProcessOptions,
+    },
+
+    /// Trains a zstd dictionary (for --zstd-dict) on rows sampled evenly across a parquet,
+    /// serialized exactly as process-parquet would write them
+    #[clap(arg_required_else_help = true)]
+    TrainDict {
+        /// Which parquet file to sample rows from. Laid out like for process-parquet, since contents come from the blob store
+        #[arg(required=true, long)]
+        parquet_file: PathBuf,
+
+        /// Where the trained dictionary goes
+        #[arg(required=true, long)]
+        dict_out: PathBuf,
+
+        /// How many rows to sample
+        #[arg(long, default_value_t=10000)]
+        num_samples: usize,
+
+        /// Max dictionary size in bytes (zstd's own default is 110KiB)
+        #[arg(long, default_value_t=112640)]
+        max_dict_bytes: usize,
+
+        #[command(flatten)]
+        options: ProcessOptions,
+    },
+
+    /// Times decoding each row's blob with its src_encoding, and prints decode throughput per encoding.
+    /// Only the decode itself is timed (not reading or gunzipping the blob), one row at a time on one thread
+    #[clap(arg_required_else_help = true)]
+    BenchDecode {
+        /// Which parquet file to benchmark. Laid out like for process-parquet, since contents come from the blob store
+        #[arg(required=true, long)]
+        parquet_file: PathBuf,
+
+        /// Only benchmark (evenly spaced) rows up to this many. Every blob is held in memory while it's timed, one at a time
+        #[arg(long)]
+        max_rows: Option<usize>,
+
+        #[command(flatten)]
+        options: ProcessOptions,
+    },
+
+    /// Counts how many rows have each src_encoding across every parquet file under a directory, and prints them
+    /// most common first, with whether decoding supports each one. Only reads that one column, never any blobs
+    #[clap(arg_required_else_help = true)]
+    SurveyEncodings {
+        /// Directory to (recursively) search for parquet files, laid out like for process-directory
+        #[arg(required=true, long)]
+        input_dir: PathBuf,
+
+        /// Comma-separated languages (parquet directory names) to survey; all others are left out
+        #[arg(long, value_delimiter=',')]
+        languages: Vec<String>,
+
+        /// Comma-separated languages to leave out. Applied after --languages, so a language in both is left out
+        #[arg(long, value_delimiter=',')]
+        skip_languages: Vec<String>,
+    },
+
+    /// Checks that every .jsonl.zstd (or --gzip-per-record .jsonl.gz) under a directory decompresses and that each of its lines is a JSON object
+    #[clap(arg_required_else_help = true)]
+    Validate {
+        /// Directory to (recursively) search for outputs
+        #[arg(required=true, long)]
+        dir: PathBuf,
+
+        /// Count zero-byte outputs (e.g. left by a crash) as invalid. Either way they're listed
+        #[arg(long, default_value_t=false)]
+        error_on_empty: bool,
+
+        /// Dictionary the outputs were compressed with (--zstd-dict), if any
+        #[arg(long)]
+        zstd_dict: Option<PathBuf>,
+
+        /// How records are laid out in the outputs (the --record-framing they were written with)
+        #[arg(long, value_enum, default_value_t=RecordFraming::Ndjson)]
+        record_framing: RecordFraming,
+    },
+
+    /// Checks every .sha256/.crc32 sidecar (from --emit-checksums) under a directory against the file next to it
+    #[clap(arg_required_else_help = true)]
+    VerifyChecksums {
+        /// Directory to (recursively) search for checksum sidecars
+        #[arg(required=true, long)]
+        dir: PathBuf,
+    },
+
+    /// Links the parquets of a HuggingFace dataset repo (already downloaded into the local hub cache, e.g. with
+    /// `huggingface-cli download --repo-type dataset`) into the <DIR>/the-stack-v2/raw-hf-parquets/<LANGUAGE>/ layout,
+    /// so process-directory can run on <DIR> directly. Blobs still need to be under <DIR>/the-stack-v2/data/
+    #[clap(arg_required_else_help = true)]
+    LinkHfCache {
+        /// Dataset repo id, e.g. bigcode/the-stack-v2
+        #[arg(long, default_value="bigcode/the-stack-v2")]
+        repo_id: String,
+
+        /// Branch, tag or commit hash of the snapshot to link
+        #[arg(long, default_value="main")]
+        revision: String,
+
+        /// HuggingFace hub cache. Defaults to $HF_HUB_CACHE, $HF_HOME/hub or ~/.cache/huggingface/hub
+        #[arg(long)]
+        hf_cache: Option<PathBuf>,
+
+        /// <DIR> to build the layout under
+        #[arg(required=true, long)]
+        output_dir: PathBuf,
+    },
+
+    /// Maintenance utility: recompresses every .gz blob under a directory into a .zst blob
+    #[clap(arg_required_else_help = true)]
+    Recompress {
+        /// Blob directory to (recursively) recompress, e.g. <DIR>/the-stack-v2/data/
+        #[arg(required=true, long)]
+        blob_dir: PathBuf,
+
+        /// zstd compression level for the new blobs
+        #[arg(long, default_value_t=DEFAULT_COMPRESSION_LEVEL)]
+        level: i32,
+
+        /// Delete each .gz once its .zst has been written, fsynced, and read back to the same contents
+        #[arg(long, default_value_t=false)]
+        delete_original: bool,
+    },
+
+    /// Audits a blob directory before packaging it: counts its .gz and .zst blobs, decompresses a sample of them, and
+    /// reports the corrupt ones (bad gzip/zstd data, e.g. a truncated download) and the empty ones, with examples.
+    /// Blobs without gzip/zstd magic bytes (which the packager takes as raw contents) are counted as uncompressed.
+    /// Fails if any sampled blob is corrupt
+    #[clap(arg_required_else_help = true)]
+    ValidateBlobStore {
+        /// Blob directory to (recursively) audit, e.g. <DIR>/the-stack-v2/data/
+        #[arg(required=true, long)]
+        blob_dir: PathBuf,
+
+        /// Fraction of the blobs to decompress, picked deterministically by path (with --seed)
+        #[arg(long, default_value_t=1.0, value_parser=parse_fraction)]
+        sample_fraction: f64,
+
+        /// Seed for picking the sample
+        #[arg(long, default_value_t=0)]
+        seed: u64,
+
+        /// How many example paths to report for each kind of bad blob
+        #[arg(long, default_value_t=10)]
+        max_examples: usize,
+    },
+}
+
+
+/// Options that only make sense when processing many parquet files in one go
+#[derive(Args, Debug, Clone)]
+struct DirectoryOptions {
+    /// Abort the whole batch on the first file that fails (default is to log it and continue)
+    #[arg(long, default_value_t=false)]
+    fail_fast: bool,
+
+    /// Abort the whole batch once this many files have failed, since that many usually means something systemic
+    /// (bad credentials, a full disk, a wrong option) rather than bad files. The failures so far are logged
+    #[arg(long, value_parser=clap::value_parser!(u64).range(1..), conflicts_with="fail_fast")]
+    max_failed_files: Option<u64>,
+
+    /// File listing parquets that were fully processed (one path per line).
+    /// Paths in here are skipped on startup, and each newly completed parquet is appended
+    #[arg(long)]
+    checkpoint_file: Option<PathBuf>,
+
+    /// Write a JSON summary of the whole run here at the end: totals, plus per language the rows written, decoded contents
+    /// bytes (bytes_contents), output bytes and missing rate. Aggregated over the parquets processed in this run
+    /// (so not ones skipped via --checkpoint-file)
+    #[arg(long)]
+    dataset_manifest: Option<PathBuf>,
+
+    /// Also (or, without --dataset-manifest, instead) write a <LANGUAGE>.manifest.json per language to the output
+    /// directory at the end: its files processed and failed, parquet bytes, and stats aggregated over its parquets,
+    /// for releasing and reporting on each language's subset on its own. Same caveat about --checkpoint-file
+    #[arg(long, default_value_t=false)]
+    per_language_manifest: bool,
+
+    /// Comma-separated languages (parquet directory names) to process; all others are skipped
+    #[arg(long, value_delimiter=',')]
+    languages: Vec<String>,
+
+    /// Comma-separated languages to skip. Applied after --languages, so a language in both is skipped
+    #[arg(long, value_delimiter=',')]
+    skip_languages: Vec<String>,
+
+    /// Abort the batch, before processing it, on the first parquet whose schema differs from the first parquet's
+    /// (a column added, dropped or retyped; nullability and column order don't count), instead of writing a mix
+    #[arg(long, default_value_t=false)]
+    fail_on_schema_change: bool,
+
+    /// Only process the first this-many parquet files (in discovery order), e.g. to try out a batch on a huge tree.
+    /// Counted after the language filters and --checkpoint-file, so a rerun with the same checkpoint does the next ones
+    #[arg(long, value_parser=clap::value_parser!(u64).range(1..))]
+    max_files: Option<u64>,
+}
+
+
+/// Options shared by every command that turns parquets into jsonls.
+///
+/// Determinism: for the same inputs and options (including --seed), the output files are byte-identical
+/// between runs, whatever --threads is. Rows are processed in parallel but always written back in order,
+/// and the only randomness (--shuffle) comes from --seed. Monitoring files (--progress-file, manifests) carry timestamps
+#[derive(Args, Debug, Clone)]
+struct ProcessOptions {
+    /// Max number of lines per jsonl
+    #[arg(long, default_value_t=16384)] // 2^14 ~ 16k. Should have nice file sizes
+    max_lines: usize,
+
+    /// How many parquet rows the arrow reader decodes at a time. Lower this if reading the parquet OOMs
+    #[arg(long, default_value_t=1024)]
+    parquet_batch_size: usize,
+
+    /// Read the parquet one chunk at a time rather than loading every row up front.
+    /// Bounds memory to about one chunk, but can't be combined with options that need every row at once
+    #[arg(long, default_value_t=false, conflicts_with_all=["sort_by", "group_by", "shuffle", "blob_ids_file", "blob_id_regex", "num_partitions", "language_from_column", "partition_by"])]
+    streaming: bool,
+
+    /// Memory-map the parquet instead of reading it through buffered File reads. Mostly helps for parquets
+    /// on fast local disks (e.g. nitro drives) where read syscalls are the overhead; falls back to File reads if mmap fails
+    #[arg(long, default_value_t=false)]
+    mmap: bool,
+
+    /// How binary (e.g. FixedSizeBinary hash) columns are written out as json strings
+    #[arg(long, value_enum, default_value_t=BinaryEncoding::Hex)]
+    binary_encoding: BinaryEncoding,
+
+    /// How time-of-day (Time32/Time64) columns are written out: the raw integer in the column's unit, or an ISO time string
+    #[arg(long, value_enum, default_value_t=TimeFormat::Raw)]
+    time_format: TimeFormat,
+
+    /// Round float columns to this many decimals, so high-precision scores don't bloat the jsonl (default: full precision)
+    #[arg(long, value_parser=clap::value_parser!(u32).range(0..=15))]
+    float_precision: Option<u32>,
+
+    /// Instead of (silently) leaving out columns of a type we can't convert, write their values as strings
+    #[arg(long, value_enum)]
+    unsupported_column_fallback: Option<UnsupportedColumnFallback>,
+
+    /// Add a row_group field to every row: the index of the parquet row group it was read from, for lining outputs
+    /// back up with their parquet. Only for --input-format parquet
+    #[arg(long, default_value_t=false)]
+    add_row_group_index: bool,
+
+    /// Preset for memory-constrained machines: turns on --streaming, and caps
+    /// --parquet-batch-size at 128 and --max-lines at 2048
+    #[arg(long, default_value_t=false, conflicts_with_all=["sort_by", "group_by", "shuffle", "blob_ids_file", "blob_id_regex", "num_partitions", "language_from_column", "partition_by"])]
+    low_memory: bool,
+
+    /// Format of the row metadata files. With jsonl/jsonl.zstd, the files are still laid out (and named) like the parquets,
+    /// e.g. <DIR>/the-stack-v2/raw-hf-parquets/<PROGRAMMING_LANGUAGE>/train-0000-of-1234.jsonl.zstd, and are read whole
+    /// (so no --streaming). Options that read parquet internals (--emit-parquet-metadata, --max-rows-in-memory) don't apply
+    #[arg(long, value_enum, default_value_t=InputFormat::Parquet)]
+    input_format: InputFormat,
+
+    /// Name outputs with a filesystem-safe slug of the language instead of the verbatim directory/column value:
+    /// lowercased, ++ -> pp, # -> sharp, and anything else outside [a-z0-9-] -> _ (so C++ -> cpp, C# -> csharp).
+    /// Only file names change; the rows' own language field is left alone
+    #[arg(long, default_value_t=false)]
+    normalize_language_names: bool,
+
+    /// Zero-pad the shard number (the XXXX of train-XXXX-of-YYYY.parquet) to this many digits in output file names,
+    /// so outputs sort lexicographically in shard order even if upstream stops padding (0 -> 0000 with 4).
+    /// Shard numbers must then be numeric. Only names change; blobs are still looked up under the parquet's own number
+    #[arg(long, value_parser=clap::value_parser!(u64).range(1..=20))]
+    shard_pad_width: Option<u64>,
+
+    /// JSON object mapping languages to the slugs to name their outputs with (e.g. {"Jupyter Notebook": "ipynb"}).
+    /// Implies --normalize-language-names for every language not in the map
+    #[arg(long)]
+    language_map: Option<PathBuf>,
+
+    /// Comma-separated columns every parquet must have. Checked against the parquet's schema before any rows are
+    /// read, so a schema regression upstream fails the file immediately instead of silently dropping fields
+    #[arg(long, value_delimiter=',')]
+    require_columns: Vec<String>,
+
+    /// Write the parquet's file-level metadata (created_by, key-value metadata, row group count) to a
+    /// <LANGUAGE>-<XXXX>.parquet-metadata.json sidecar in the output directory (and log it). Only reads the footer
+    #[arg(long, default_value_t=false)]
+    emit_parquet_metadata: bool,
+
+    /// Write a JSON Schema of the output records here, once per run (from the first parquet's schema): each column's
+    /// field and json type as rows carry them, plus the fields the other options add (contents, content_hash,
+    /// row_group, ...) or drop (src_encoding, --exclude-columns). Only for --input-format parquet
+    #[arg(long)]
+    output_json_schema: Option<PathBuf>,
+
+    /// Once a parquet has been processed successfully, write a <LANGUAGE>-<XXXX>.manifest.json to the output directory
+    /// with its stats, the packager version and argv, and when it finished. Written last (and atomically), so it marks the parquet done
+    #[arg(long, default_value_t=false)]
+    emit_file_manifest: bool,
+
+    /// Skip any parquet whose --emit-file-manifest manifest is already in the output directory and well-formed.
+    /// A cheaper "already done" check than --resume's per-chunk one, at whole-parquet granularity
+    #[arg(long, default_value_t=false, requires="emit_file_manifest")]
+    skip_if_manifest_exists: bool,
+
+    /// Without --streaming, refuse (before decoding anything) parquets with more rows than this,
+    /// rather than risk an OOM from holding them all in memory
+    #[arg(long)]
+    max_rows_in_memory: Option<usize>,
+
+    /// A chunk only fails if BOTH more than MISSING_THRESHOLD (1%) of its rows are missing
+    /// AND more than this many rows are missing. Keeps tiny chunks from failing on a single 404
+    #[arg(long, default_value_t=0)]
+    max_missing_absolute: usize,
+
+    /// Comma-separated languages (e.g. rust,go) that tolerate no missing blobs at all: a single one fails the file.
+    /// Matched case-insensitively against the parquet's language directory, or its --language-map'ed output name
+    #[arg(long, value_delimiter=',')]
+    strict_languages: Vec<String>,
+
+    /// What to do with a row whose blob is missing: drop it, or keep its metadata with `contents: null` and `missing: true`.
+    /// Either way it counts as missing (towards the threshold above, and in the stats)
+    #[arg(long, value_enum, default_value_t=MissingPolicy::Drop)]
+    on_missing: MissingPolicy,
+
+    /// If present, a file of blob_ids (one per line). Only rows with these blob_ids are output;
+    /// all other rows are skipped (and not counted as missing)
+    #[arg(long)]
+    blob_ids_file: Option<PathBuf>,
+
+    /// Only output rows whose blob_id matches this regex (unanchored, so use ^ for prefixes);
+    /// all other rows are skipped (and not counted as missing). Applied after --blob-ids-file
+    #[arg(long)]
+    blob_id_regex: Option<Regex>,
+
+    /// Treat gzip blobs that fail their footer CRC32/length check (or have corrupt deflate data), and zstd blobs that
+    /// fail to decompress, as corrupt rows:
+    /// logged, counted separately from missing rows, and left out. Without this a corrupt blob aborts the run
+    #[arg(long, default_value_t=false)]
+    verify_gzip_crc: bool,
+
+    /// Tolerate rows whose blob can't be decoded with their src_encoding (or whose src_encoding is unknown) up to this limit
+    /// per file: a row count (50) or a fraction of the rows read so far (0.01). Those rows are logged, counted, and left out;
+    /// past the limit the file fails, since that many means its encoding metadata is systematically wrong.
+    /// Without this the first decode failure aborts the run
+    #[arg(long)]
+    max_decode_errors: Option<ErrorLimit>,
+
+    /// Comma-separated src_encodings (e.g. CP850,IBM866) whose undecodable bytes are replaced with U+FFFD instead of
+    /// failing the row. Matched case-insensitively against the row's src_encoding (or --default-encoding); every other
+    /// encoding still fails on the first bad byte
+    #[arg(long, value_delimiter=',')]
+    lossy_encodings: Vec<String>,
+
+    /// What to do with rows declared UTF-8 (by src_encoding or --default-encoding) whose blobs have invalid UTF-8 bytes,
+    /// apart from the policy for other encodings: strict counts them as decode failures (logged and left out, without
+    /// needing --max-decode-errors, though they count towards its limit if it's given), lenient keeps them decoded with
+    /// U+FFFD replacement chars. Unset, they're treated like any other encoding's decode failures
+    #[arg(long, value_enum)]
+    utf8_policy: Option<Utf8Policy>,
+
+    /// Treat a row whose blob read plus decode takes longer than this as a decode failure (so it counts towards
+    /// --max-decode-errors). The time is checked once the decode returns, since a running read or decode can't be
+    /// interrupted; this catches blobs on stalling storage, and --blob-fetch-timeout-secs already bounds HTTP reads
+    #[arg(long, value_parser=clap::value_parser!(u64).range(1..))]
+    row_timeout_ms: Option<u64>,
+
+    /// Stream blobs whose file is bigger than this (in bytes, as stored) into their output record instead of reading them
+    /// whole: each is decompressed, decoded and JSON-escaped a buffer at a time, so only the finished record is ever held,
+    /// not the decompressed bytes and decoded text alongside it. For the rare giant file; the records come out the same.
+    /// Local blob dirs only, and not with options that need a file's whole contents at once
+    #[arg(long, conflicts_with_all=["blob_source", "emit_raw_bytes", "emit_decoded_with", "verify_reencode", "contents_as_lines",
+                                    "contents_replace", "tabs_to_spaces", "trim_trailing_newline", "truncate_contents", "skip_empty_contents",
+                                    "skip_whitespace_only", "min_contents_length", "hash_contents", "detect_binary", "skip_binary"])]
+    stream_blobs_above_bytes: Option<u64>,
+
+    /// On a sample of rows (--reencode-sample-fraction), encode the decoded contents back to the encoding it was decoded
+    /// with and check that gives exactly the original (gunzipped) blob bytes. Lossy encodings can't always round-trip,
+    /// so this never fails anything: it logs the match rate per src_encoding after each file
+    #[arg(long, default_value_t=false)]
+    verify_reencode: bool,
+
+    /// Fraction of rows --verify-reencode checks, picked by a (stable, --seed'ed) hash of blob_id
+    #[arg(long, default_value_t=0.01, requires="verify_reencode", value_parser=parse_fraction)]
+    reencode_sample_fraction: f64,
+
+    /// List each parquet's blob directory (shard subdirectories included) once up front, so missing blobs are
+    /// found by a set lookup instead of a filesystem stat each. Costs roughly 100 bytes of memory per blob listed
+    #[arg(long, default_value_t=false)]
+    cache_blob_listing: bool,
+
+    /// Fetch blobs over HTTP from this URL template instead of the local data directory, with {blob_id} filled in,
+    /// e.g. http://mirror/the-stack-v2/{blob_id}.gz. A 404 counts as a missing blob; 5xx responses and
+    /// connection errors/timeouts are retried. Plain http:// only, as this build has no TLS
+    #[arg(long, conflicts_with="cache_blob_listing")]
+    blob_source: Option<String>,
+
+    /// How many times --blob-source retries a transient failure (with exponential backoff from 100ms, capped at 10s)
+    #[arg(long, default_value_t=3)]
+    blob_fetch_retries: u32,
+
+    /// Connect/read timeout for each --blob-source request, in seconds
+    #[arg(long, default_value_t=30)]
+    blob_fetch_timeout_secs: u64,
+
+    /// With --blob-source, fetch each chunk's blobs up front with this many concurrent requests (on their own pool,
+    /// separate from --threads) before decoding them, to hide network latency. Holds a whole chunk of compressed blobs in memory
+    #[arg(long, requires="blob_source", value_parser=clap::value_parser!(u64).range(1..))]
+    prefetch_concurrency: Option<u64>,
+
+    /// Cap on blob reads per second (across all threads), to go easy on shared blob storage
+    #[arg(long, value_parser=parse_positive_rate)]
+    max_reads_per_sec: Option<f64>,
+
+    /// Cap on (compressed) blob bytes read per second, across all threads
+    #[arg(long, value_parser=clap::value_parser!(u64).range(1..))]
+    max_read_bps: Option<u64>,
+
+    /// Encoding used for rows whose src_encoding is missing, empty, or a "null"/"None" placeholder
+    #[arg(long, default_value="UTF-8")]
+    default_encoding: String,
+
+    /// JSON object mapping bespoke src_encoding labels to the encodings they stand for (e.g. {"mylabel": "windows-1252"}),
+    /// translated before decoding. Labels match case-insensitively; every target must be an encoding we can decode
+    #[arg(long, alias="rename-encoding-aliases")]
+    encoding_aliases: Option<PathBuf>,
+
+    /// Keep each row's src_encoding field (the encoding its metadata claims) in the output; --keep-src-encoding false drops it
+    #[arg(long, default_value_t=true, action=clap::ArgAction::Set)]
+    keep_src_encoding: bool,
+
+    /// Comma-separated columns to leave out of the output rows. They're only removed as each row is serialized, so
+    /// they're still read and usable by everything before that (--sort-by, --group-by, --blob-id-regex,
+    /// --language-from-column, --extensions-map's path, --dedup-by, ...); this only makes the output smaller
+    #[arg(long, value_delimiter=',')]
+    exclude_columns: Vec<String>,
+
+    /// Add a decoded_with field to every row: the decoder that was actually used on its contents. This can differ from
+    /// src_encoding, e.g. --default-encoding for rows without one, or windows-1252 for ISO-8859-1 (which it's a superset of)
+    #[arg(long, default_value_t=false)]
+    emit_decoded_with: bool,
+
+    /// Add a contents_bytes field to every row: the gunzipped blob bytes exactly as stored, base64 encoded, for consumers
+    /// that do their own decoding. src_encoding is always kept then, whatever --keep-src-encoding says. `also` (the
+    /// default) decodes into contents as usual too; `instead` skips decoding, so rows have no contents field and the
+    /// contents options (--skip-empty-contents, --truncate-contents, ...) don't apply. base64 takes 4 bytes for every
+    /// 3, so contents_bytes alone is a third bigger than the blob, and `also` roughly doubles the output
+    #[arg(long, value_enum, num_args=0..=1, default_missing_value="also")]
+    emit_raw_bytes: Option<RawBytesMode>,
+
+    /// Store contents as a JSON array of lines rather than a single string.
+    /// Note this makes outputs slightly larger: every line costs an extra `"",` of JSON
+    #[arg(long, default_value_t=false)]
+    contents_as_lines: bool,
+
+    /// With --contents-as-lines, keep the trailing '\n' on each line (default strips them)
+    #[arg(long, default_value_t=false, requires="contents_as_lines")]
+    keep_line_endings: bool,
+
+    /// Strip a single trailing '\n' from the decoded contents of each file.
+    /// This is about the source file's own last newline; every jsonl line still ends with a --line-ending separator
+    #[arg(long, default_value_t=false)]
+    trim_trailing_newline: bool,
+
+    /// Separator after every JSON record in the outputs: lf ('\n'), or crlf ('\r\n') for consumers that want
+    /// Windows line endings. Newlines inside contents are escaped in the JSON either way, so they're unaffected
+    #[arg(long, value_enum, default_value_t=LineEnding::Lf)]
+    line_ending: LineEnding,
+
+    /// Whether the last record of each output file keeps its --line-ending (keep), or ends the file without one (strip),
+    /// for strict JSONL consumers. Validate accepts either. Only for ndjson --record-framing
+    #[arg(long, value_enum, default_value_t=FinalNewline::Keep, conflicts_with="stdout")]
+    final_newline: FinalNewline,
+
+    /// How records are laid out within each (decompressed) output: ndjson (one JSON object per line), or length-prefixed
+    /// (each JSON object preceded by its byte length as a little-endian u32, with no separator, so --line-ending doesn't apply).
+    /// Validate needs the same --record-framing to read length-prefixed outputs
+    #[arg(long, value_enum, default_value_t=RecordFraming::Ndjson, conflicts_with="line_ending")]
+    record_framing: RecordFraming,
+
+    /// Rewrite each file's decoded contents with a regex, given as '<regex>=><replacement>' (split at the first '=>';
+    /// the replacement can use $1 or ${name} for capture groups). Repeatable, applied in order, and before any other
+    /// contents transforms. The number of rows changed is logged at the end of the run
+    #[arg(long)]
+    contents_replace: Vec<ContentsReplace>,
+
+    /// Replace every tab in each file's decoded contents with this many spaces (a plain substitution, not tab stops).
+    /// Applied right after --contents-replace. The number of rows changed is logged at the end of the run
+    #[arg(long, alias="contents-tab-to-spaces")]
+    tabs_to_spaces: Option<usize>,
+
+    /// Cut each file's contents down to at most this much, given with a unit: bytes (100b, 100kb, 1mb; powers of 1024)
+    /// or chars (50000chars). Either way the cut lands on a utf-8 char boundary. Applied after --trim-trailing-newline
+    #[arg(long)]
+    truncate_contents: Option<ContentsLength>,
+
+    /// Drop rows whose contents are empty (after any contents transforms). Counted separately from missing rows
+    #[arg(long, default_value_t=false)]
+    skip_empty_contents: bool,
+
+    /// Drop rows whose contents are empty or entirely whitespace (after any contents transforms)
+    #[arg(long, default_value_t=false)]
+    skip_whitespace_only: bool,
+
+    /// Drop rows whose contents are shorter than this, in bytes (100b, 1kb, ...) or chars (20chars), after any
+    /// contents transforms. Counted as skipped (too_short), separately from missing rows
+    #[arg(long)]
+    min_contents_length: Option<ContentsLength>,
+
+    /// Add a content_hash field to every row: the hex hash of its (decoded, transformed) contents.
+    /// Unlike any filtering, this only annotates rows; nothing is dropped
+    #[arg(long, value_enum)]
+    hash_contents: Option<HashAlgorithm>,
+
+    /// Shuffle rows (with --seed) before chunking, e.g. so each output file is a uniform sample of the parquet.
+    /// Needs every row in memory at once
+    #[arg(long, default_value_t=false, conflicts_with_all=["sort_by", "group_by"])]
+    shuffle: bool,
+
+    /// Seed for anything random (--shuffle, --sample-out); the same seed always gives the same output
+    #[arg(long, default_value_t=0)]
+    seed: u64,
+
+    /// Add an is_binary field to every row, from a text-vs-binary heuristic on the decoded contents
+    /// (a NUL, or lots of control chars, in the first 8000 bytes). Rows are kept either way
+    #[arg(long, default_value_t=false)]
+    detect_binary: bool,
+
+    /// Drop rows whose decoded contents look binary (same heuristic as --detect-binary), counted as skipped.
+    /// Catches the odd binary file mislabeled as a text language
+    #[arg(long, default_value_t=false)]
+    skip_binary: bool,
+
+    /// Add an ext_language field to every row: the language its path's extension maps to in a built-in table (null if
+    /// the extension isn't in it). Known extensions also get ext_language_mismatch, true when the output's language
+    /// isn't one that uses the extension, which flags likely mislabeled files (a .py in the Rust shard)
+    #[arg(long, default_value_t=false)]
+    extensions_map: bool,
+
+    /// Drop rows already output earlier in the run (by blob_id, or by their final contents), counted as skipped.
+    /// Rows are checked in output order, so it's always the first copy that's kept. A file's rows only count as
+    /// seen once the file has been written, so nothing is dropped for duplicating a file that then failed
+    #[arg(long, value_enum)]
+    dedup_by: Option<DedupBy>,
+
+    /// Carry --dedup-by's seen-set across invocations: loaded from this file at startup (if it exists), and the new keys
+    /// of every file this run wrote are appended to it at the end (even if the batch fails, so a --checkpoint-file
+    /// retry dedups against them). Only append runs with the same --dedup-by to the same file
+    #[arg(long, requires="dedup_by")]
+    dedup_state: Option<PathBuf>,
+
+    /// Sort rows by this column before chunking, formatted like <column>[:asc|desc].
+    /// Rows missing the column (or null) always sort last. Needs every row in memory at once
+    #[arg(long)]
+    sort_by: Option<SortSpec>,
+
+    /// Group rows sharing a value of this column (e.g. a directory or extension column) together
+    /// before chunking so each chunk is more homogeneous. Applied after --sort-by, so groups stay sorted inside.
+    /// Like --sort-by this needs every row in memory, and it only improves compression with --compression-unit chunk
+    /// (where each file logs its compressed bytes against the same rows chunked ungrouped)
+    #[arg(long)]
+    group_by: Option<String>,
+
+    /// Whether each row gets its own zstd frame, or each chunk is compressed as one frame.
+    /// Both decompress to the same jsonl; chunk compresses better, row keeps per-row frames
+    #[arg(long, value_enum, default_value_t=CompressionUnit::Row)]
+    compression_unit: CompressionUnit,
+
+    /// Order each chunk's rows by the length of their decoded contents before they're written, smallest (asc) or
+    /// largest (desc) first. Unlike --sort-by this only reorders within a chunk, so it needs no more memory. With
+    /// --compression-unit chunk, the log says how the sorted chunks compressed against the same chunks unsorted
+    /// (which costs compressing every chunk twice); per-row frames compress the same in any order
+    #[arg(long, value_enum)]
+    sort_by_size: Option<SortDirection>,
+
+    /// Split rows into this many output partitions by a (stable) hash of blob_id.
+    /// Each partition gets its own chunk sequence, with the partition index in the filenames
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u64).range(1..))]
+    num_partitions: u64,
+
+    /// Split rows into output partitions by their value of this column (e.g. a license or repo column) instead:
+    /// each distinct value gets its own chunk sequence, named with part_<slug>. The slug is the value lowercased, with
+    /// ++ as pp and # as sharp, each run of other chars that aren't letters, digits or - as one _ (trimmed off both
+    /// ends), then any - as _ too; values that slug the same share a sequence. Null or missing is part_null, and a
+    /// value with nothing left is part__. Partitions are written one after another, so only one is open at a time
+    /// however many values there are. Rows written per partition are counted in the stats (rows_by_partition)
+    #[arg(long, conflicts_with="num_partitions")]
+    partition_by: Option<String>,
+
+    /// Roll output files near this many (compressed) bytes, instead of one file per --max-lines chunk.
+    /// Approximate: a running compression ratio estimates each row's compressed size, and compression is nonlinear.
+    /// --max-lines then only sets how many rows are processed at a time. Files are named with the chunk count as their -of-
+    /// total while they're written, then renamed to the number of files actually written once the sequence is done
+    #[arg(long)]
+    target_file_bytes: Option<usize>,
+
+    /// Write everything for a parquet (per language/partition) into one .jsonl.zstd, with no -NNNNNN-of-NNNNNN suffix.
+    /// Rows are streamed through a single zstd encoder, so memory stays bounded by --max-lines (which now only sets
+    /// how many rows are processed at a time). The file is one zstd frame, so --compression-unit doesn't apply
+    #[arg(long, default_value_t=false, conflicts_with_all=["target_file_bytes", "compression_unit", "verify_roundtrip"])]
+    single_file: bool,
+
+    /// Write the rows to stdout as uncompressed NDJSON instead of making .jsonl.zstd files, for piping into another program.
+    /// Implies --streaming, so memory stays bounded by about one --max-lines chunk; each chunk is written (in order) once
+    /// it's processed, and the write blocks while the pipe is full, so a slow consumer throttles the reads too.
+    /// Logs move to stderr; sidecars (manifests, --sample-out, ...) still go to their usual places
+    #[arg(long, alias="stream", default_value_t=false,
+          conflicts_with_all=["single_file", "target_file_bytes", "resume", "verify_roundtrip", "verify_writes", "emit_checksums",
+                              "sort_by", "group_by", "shuffle", "blob_ids_file", "blob_id_regex", "num_partitions", "language_from_column", "partition_by"])]
+    stdout: bool,
+
+    /// With --stdout, also write the byte offset in the stdout stream where each record starts to this file, as raw
+    /// little-endian u64s in record order, so a reader that saved the stream to a file can mmap it and jump straight
+    /// to record i at offset[i]. Offsets count every byte written, line endings and --record-framing prefixes included
+    #[arg(long, requires="stdout")]
+    emit_offset_index: Option<PathBuf>,
+
+    /// Number output files from this chunk index instead of 0, so workers that each package part of one parquet
+    /// (e.g. a range of its rows) don't collide. Only the file names change
+    #[arg(long, default_value_t=0, conflicts_with_all=["target_file_bytes", "single_file", "stdout"])]
+    chunk_index_offset: usize,
+
+    /// Use this as the -of-NNNNNN total in output file names, instead of this run's own chunk count,
+    /// so every worker's files agree on it. It's an error for a run's chunks to go past it
+    #[arg(long, conflicts_with_all=["target_file_bytes", "single_file", "stdout"])]
+    total_chunks_override: Option<usize>,
+
+    /// Write gzip (.jsonl.gz) instead of zstd outputs, each row compressed as its own gzip member and the members
+    /// concatenated (a valid multi-member gzip; like the default per-row zstd frames), for legacy gzip readers that
+    /// want per-record random access. Compresses noticeably worse than one member per file, as every row starts over
+    /// with an empty window and pays ~20 bytes of member header/trailer
+    #[arg(long, default_value_t=false, conflicts_with_all=["compression_unit", "single_file", "stdout", "zstd_dict", "zstd_workers", "zstd_checksum"])]
+    gzip_per_record: bool,
+
+    /// With --target-file-bytes, a row that would push a file past target * (1 + tolerance) starts a new file instead
+    #[arg(long, default_value_t=0.1, requires="target_file_bytes")]
+    file_bytes_tolerance: f64,
+
+    /// Skip chunks that a previous (interrupted) run already wrote. Each chunk sequence keeps a hidden .progress file
+    /// with its last completed chunk, so resuming doesn't have to stat every output; without one (or if its last
+    /// output is missing/corrupt) outputs are checked from the first chunk. Needs the same --max-lines as the first run
+    #[arg(long, default_value_t=false, conflicts_with_all=["target_file_bytes", "single_file"])]
+    resume: bool,
+
+    /// Replace outputs that already exist (logging each one). By default an existing .jsonl.zstd is an error,
+    /// so a run pointed at a populated directory can't clobber it. --resume may always rewrite the chunks it redoes
+    #[arg(long, default_value_t=false)]
+    overwrite: bool,
+
+    /// Where output files are written before being renamed into place. Defaults to the output directory itself,
+    /// which keeps the rename atomic; a directory on another filesystem means a (non-atomic) copy instead, so this warns
+    #[arg(long)]
+    tmp_dir: Option<PathBuf>,
+
+    /// Abort if the output filesystem (or --tmp-dir's) has less than this many bytes free: checked at startup and
+    /// again before each chunk is written, so a filling volume stops the run instead of leaving truncated files.
+    /// This always stops a whole process-directory batch, even without --fail-fast
+    #[arg(long)]
+    min_free_bytes: Option<u64>,
+
+    /// fsync every output file (and its directory) once written, so completed files are durable across a crash; manifests,
+    /// progress files and other sidecars included.
+    /// Costs throughput: each file then waits on the disk, which can be very slow on network filesystems
+    #[arg(long, default_value_t=false)]
+    fsync: bool,
+
+    /// After compressing each chunk, decompress it again and check it matches the uncompressed bytes exactly.
+    /// Roughly doubles the memory and adds a decompression per chunk, in exchange for catching codec/memory corruption
+    #[arg(long, default_value_t=false)]
+    verify_roundtrip: bool,
+
+    /// After writing each output (before it's renamed into place), read it back from disk, decompress it,
+    /// and check it has exactly as many lines as rows were written to it. Catches truncated writes on flaky storage
+    #[arg(long, default_value_t=false)]
+    verify_writes: bool,
+
+    /// Write a checksum sidecar (<output>.sha256 or <output>.crc32) next to every output, hashed from the file on disk.
+    /// verify-checksums checks them
+    #[arg(long, value_enum)]
+    emit_checksums: Option<ChecksumAlgorithm>,
+
+    /// Once each output is in place under its final name (and after its checksum sidecar), create an empty <output>.done
+    /// next to it, for watchers that pick up finished files while the run goes on. With --target-file-bytes the files
+    /// only get their final names once the whole sequence is written, so their markers all come then
+    #[arg(long, default_value_t=false, conflicts_with="stdout")]
+    emit_done_markers: bool,
+
+    /// Compress outputs with this zstd dictionary (e.g. from train-dict). Helps a lot for small chunks/per-row frames,
+    /// but every reader then needs the same dictionary to decompress (`zstd -D <dict> -d`)
+    #[arg(long)]
+    zstd_dict: Option<PathBuf>,
+
+    /// Embed a content checksum in every zstd frame, so `zstd -t`/any decompressor catches corruption with no sidecar.
+    /// Costs 4 bytes per frame (so turn it off for --compression-unit row on tiny rows if that matters)
+    #[arg(long, default_value_t=true, action=clap::ArgAction::Set)]
+    zstd_checksum: bool,
+
+    /// zstd worker threads per frame, on top of --threads, for frames of 4MiB or more (and the --single-file stream).
+    /// Keeps a chunk dominated by one giant row from compressing on a single core. 0 compresses every frame single-threaded
+    #[arg(long, default_value_t=0)]
+    zstd_workers: u32,
+
+    /// Take each row's language (for output naming) from this column instead of the parquet's directory name.
+    /// Rows are split into one chunk sequence per language; rows where the column is missing keep the directory's language
+    #[arg(long)]
+    language_from_column: Option<String>,
+
+    /// Take the parquet's language from this key of its file-level key-value metadata instead of its directory name,
+    /// for parquets that don't sit in a <PROGRAMMING_LANGUAGE> directory. Blobs are then looked up under
+    /// data/<that language>/<XXXX>. A parquet without the key fails
+    #[arg(long)]
+    language_from_metadata: Option<String>,
+
+    /// Log a warning for any chunk whose rows take longer than this many msecs to process (e.g. to spot slow disks or mounts)
+    #[arg(long)]
+    slow_chunk_warn_ms: Option<u64>,
+
+    /// Append a CSV line per chunk to this file: language, shard, partition, chunk, rows read and written, the
+    /// serialized bytes, and the msecs spent reading/decoding/serializing the chunk's rows and then writing them out
+    /// (compressing included; with --target-file-bytes a chunk's write may only buffer it). A header is written
+    /// first when the file is new, so a batch of invocations can share one file
+    #[arg(long)]
+    timings_csv: Option<PathBuf>,
+
+    /// Small JSON file rewritten after every chunk with the last completed chunk and a unix timestamp,
+    /// so an external watchdog can detect stalled runs. Purely for monitoring; nothing reads it back
+    #[arg(long)]
+    progress_file: Option<PathBuf>,
+
+    /// Check every serialized line is valid utf-8 before it's written, and either fail the file or skip (and count) bad lines.
+    /// serde_json should never produce invalid utf-8; this is a guard against regressions
+    #[arg(long, value_enum)]
+    validate_output_utf8: Option<InvalidUtf8Policy>,
+
+    /// Append a JSON line for every row left out of the output (missing blob, or skipped) to this file,
+    /// with its blob_id, reason, and output chunk. row_index is the row's position in the sequence being chunked,
+    /// which is the parquet row index unless rows were filtered, reordered, split or partitioned
+    #[arg(long)]
+    row_error_log: Option<PathBuf>,
+
+    /// Also write --sample-size random output rows here as pretty JSON, for a quick human look at what was produced.
+    /// Sampled (seeded by --seed) over every row written in this run, and rewritten after each parquet
+    #[arg(long)]
+    sample_out: Option<PathBuf>,
+
+    /// How many rows --sample-out keeps
+    #[arg(long, default_value_t=10)]
+    sample_size: usize,
+
+    /// Time every row (blob read, decode, and serialization; compression happens per chunk, so isn't included)
+    /// and log the blob_ids of the N slowest at the end of the run, for tracking down pathological blobs
+    #[arg(long, value_parser=clap::value_parser!(u64).range(1..))]
+    report_top_slowest_rows: Option<u64>,
+
+    /// Parquet with one row per processed parquet file (path, language, shard, rows, missing, bytes_in, bytes_out,
+    /// duration_ms, status), rewritten after each file. If it already exists, this run's rows are appended to it
+    #[arg(long)]
+    stats_parquet: Option<PathBuf>,
+}
+
+
+fn parse_thread_count(s: &str) -> Result<usize, String> {
+    match s {
+        "auto" => Ok(0),
+        _ => s.parse::<usize>().map_err(|_| format!("{:?} isn't a thread count or \"auto\"", s)),
+    }
+}
+
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("{:?} isn't a fraction in [0, 1]", s)),
+    }
+}
+
+
+fn parse_positive_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("{:?} isn't a positive number", s)),
+    }
+}
+
+
+impl ProcessOptions {
+    fn read_options(&self) -> ParquetReadOptions {
+        ParquetReadOptions {
+            batch_size: self.parquet_batch_size,
+            use_mmap: self.mmap,
+            binary_encoding: self.binary_encoding,
+            time_format: self.time_format,
+            float_precision: self.float_precision,
+            unsupported_column_fallback: self.unsupported_column_fallback,
+            add_row_group_index: self.add_row_group_index,
+        }
+    }
+
+    fn end_file_with(&self, last_record: &mut Vec<u8>) -> usize {
+        // --final-newline strip: takes the line ending off what's about to be a file's last record. Returns the bytes dropped
+        if self.final_newline == FinalNewline::Keep || self.record_framing != RecordFraming::Ndjson {
+            return 0;
+        }
+        let ending = self.line_ending.as_str().as_bytes();
+        if !last_record.ends_with(ending) {
+            return 0;
+        }
+        last_record.truncate(last_record.len() - ending.len());
+        ending.len()
+    }
+
+    fn row_json(&self, row: &JsonValue) -> String {
+        // A row serialized without its --exclude-columns (keys sorted either way, like serde_json's own maps)
+        match row.as_object() {
+            Some(fields) if !self.exclude_columns.is_empty() => {
+                let kept: BTreeMap<&String, &JsonValue> = fields.iter().filter(|(name, _)| !self.exclude_columns.contains(name)).collect();
+                serde_json::to_string(&kept).unwrap()
+            },
+            _ => row.to_string(),
+        }
+    }
+
+    fn frame_record(&self, record: String) -> Result<Vec<u8>, Error> {
+        // One serialized row, as it goes in the output (--record-framing)
+        match self.record_framing {
+            RecordFraming::Ndjson => Ok((record + self.line_ending.as_str()).into_bytes()),
+            RecordFraming::LengthPrefixed => {
+                let len = u32::try_from(record.len()).map_err(|_| Error::msg(format!("{}-byte record is too big for a u32 length prefix", record.len())))?;
+                let mut bytes = Vec::with_capacity(4 + record.len());
+                bytes.extend_from_slice(&len.to_le_bytes());
+                bytes.extend_from_slice(record.as_bytes());
+                Ok(bytes)
+            },
+        }
+    }
+
+    fn is_lossy(&self, encoding: &str) -> bool {
+        self.lossy_encodings.iter().any(|lossy| lossy.trim().eq_ignore_ascii_case(encoding))
+            || self.utf8_policy_for(encoding) == Some(Utf8Policy::Lenient)
+    }
+
+    fn utf8_policy_for(&self, encoding: &str) -> Option<Utf8Policy> {
+        // --utf8-policy, if encoding is one of the names decode_to_string decodes as UTF-8
+        let is_utf8 = encoding.eq_ignore_ascii_case("UTF-8") || encoding.eq_ignore_ascii_case("UTF8");
+        self.utf8_policy.filter(|_| is_utf8)
+    }
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Utf8Policy {
+    Strict,
+    Lenient,
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum InvalidUtf8Policy {
+    Fail,
+    Skip,
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum MissingPolicy {
+    Drop,
+    KeepNull,
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum CompressionUnit {
+    Row,
+    Chunk,
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SortDirection {
+    Asc,
+    Desc,
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum RawBytesMode {
+    Also,
+    Instead,
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum FinalNewline {
+    Keep,
+    Strip,
+}
+
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordFraming {
+    Ndjson,
+    LengthPrefixed,
+}
+
+impl RecordFraming {
+    fn payload(self, record: &[u8]) -> &[u8] {
+        // The JSON of a framed record (for ndjson, still with its line ending, which JSON parsers skip as whitespace)
+        match self {
+            RecordFraming::Ndjson => record,
+            RecordFraming::LengthPrefixed => &record[4..],
+        }
+    }
+
+    fn read_records<R: BufRead>(self, mut reader: R, mut on_record: impl FnMut(&[u8]) -> Result<(), Error>) -> Result<usize, Error> {
+        // Calls on_record with the JSON of each record in turn, and returns how many there were
+        let mut record = Vec::new();
+        let mut num_records = 0;
+        loop {
+            record.clear();
+            match self {
+                RecordFraming::Ndjson => {
+                    if reader.read_until(b'\n', &mut record)? == 0 {
+                        break;
+                    }
+                },
+                RecordFraming::LengthPrefixed => {
+                    if reader.fill_buf()?.is_empty() {
+                        break;
+                    }
+                    let mut len = [0u8; 4];
+                    reader.read_exact(&mut len)
+                        .map_err(|_| Error::msg(format!("Record {} has a truncated length prefix", num_records + 1)))?;
+                    // Read no more than the file actually has, rather than trusting a (maybe corrupt) prefix to size a buffer
+                    let len = u32::from_le_bytes(len) as usize;
+                    if (&mut reader).take(len as u64).read_to_end(&mut record)? < len {
+                        return Err(Error::msg(format!("Record {} is shorter than its length prefix", num_records + 1)));
+                    }
+                },
+            }
+            num_records += 1;
+            on_record(&record)?;
+        }
+        Ok(num_records)
+    }
+}
+
+
+/// State derived from ProcessOptions that we only want to build once per invocation
+/// (and then share across every parquet file processed)
+struct ProcessConfig {
+    options: ProcessOptions,
+    blob_ids: Option<HashSet<String>>,
+    read_limiter: Option<RateLimiter>,
+    byte_limiter: Option<RateLimiter>,
+    codec: Codec,
+    prefetch_pool: Option<rayon::ThreadPool>,
+    sampler: Option<Mutex<RowSampler>>,
+    language_map: Option<BTreeMap<String, String>>,
+    encoding_aliases: HashMap<String, String>,
+    prior_file_stats: Vec<RecordBatch>,
+    file_stats: Mutex<Vec<FileStatsRow>>,
+    reencode_counts: Mutex<BTreeMap<String, ReencodeCounts>>,
+    slowest_rows: Option<Mutex<SlowestRows>>,
+    dedup: Option<Mutex<DedupSet>>,
+    offset_index: Option<Mutex<OffsetIndex>>,
+    contents_replaced: AtomicUsize, // rows --contents-replace changed
+    tabs_replaced: AtomicUsize, // rows --tabs-to-spaces changed
+    json_schema_written: AtomicBool, // --output-json-schema
+}
+
+
+/// The open --emit-offset-index file, and the stdout offset the next record will start at
+struct OffsetIndex {
+    file: BufWriter<File>,
+    next_offset: u64,
+}
+
+impl ProcessConfig {
+    fn new(options: &ProcessOptions, local_jsonl_dir: &Path) -> Result<Self, Error> {
+        if let Some(tmp_dir) = &options.tmp_dir {
+            fs::create_dir_all(tmp_dir)?;
+            fs::create_dir_all(local_jsonl_dir)?;
+            if !same_filesystem(tmp_dir, local_jsonl_dir)? {
+                log_warn!(json!({"tmp_dir": tmp_dir, "output_dir": local_jsonl_dir}),
+                          "--tmp-dir {:?} is on a different filesystem than {:?}: outputs will be copied into place, not atomically renamed", tmp_dir, local_jsonl_dir);
+            }
+        }
+        check_free_space(options, local_jsonl_dir)?;
+        if options.output_json_schema.is_some() && options.input_format != InputFormat::Parquet {
+            return Err(Error::msg("--output-json-schema only applies to --input-format parquet"));
+        }
+        if options.stream_blobs_above_bytes.is_some() && options.dedup_by == Some(DedupBy::Contents) {
+            return Err(Error::msg("--stream-blobs-above-bytes can't be combined with --dedup-by contents, which needs each file's whole contents"));
+        }
+        if options.emit_raw_bytes == Some(RawBytesMode::Instead) && options.dedup_by == Some(DedupBy::Contents) {
+            return Err(Error::msg("--emit-raw-bytes instead can't be combined with --dedup-by contents: rows have no contents field to dedup by"));
+        }
+        if let Some(blob_source) = &options.blob_source {
+            if !blob_source.contains("{blob_id}") {
+                return Err(Error::msg(format!("--blob-source {:?} has no {{blob_id}} placeholder", blob_source)));
+            }
+            check_url(blob_source)?;
+        }
+        let blob_ids = match &options.blob_ids_file {
+            Some(path) => Some(read_lines_to_set(path)?),
+            None => None,
+        };
+        let mut options = options.clone();
+        if options.stdout {
+            options.streaming = true;
+        }
+        if options.low_memory {
+            options.streaming = true;
+            options.parquet_batch_size = options.parquet_batch_size.min(LOW_MEMORY_BATCH_SIZE);
+            options.max_lines = options.max_lines.min(LOW_MEMORY_MAX_LINES);
+        }
+        let read_limiter = options.max_reads_per_sec.map(RateLimiter::new);
+        let byte_limiter = options.max_read_bps.map(|bps| RateLimiter::new(bps as f64));
+        let codec = match options.gzip_per_record {
+            true => Codec::gzip(),
+            false => Codec::load(options.zstd_dict.as_deref(), options.zstd_checksum, options.zstd_workers)?,
+        };
+        let prefetch_pool = match options.prefetch_concurrency {
+            Some(concurrency) => Some(rayon::ThreadPoolBuilder::new().num_threads(concurrency as usize).build()?),
+            None => None,
+        };
+        let sampler = options.sample_out.as_ref().map(|_| Mutex::new(RowSampler::new(options.sample_size, options.seed)));
+        let slowest_rows = options.report_top_slowest_rows.map(|top| Mutex::new(SlowestRows::new(top as usize)));
+        let dedup = match options.dedup_by {
+            Some(_) => {
+                let dedup = DedupSet::load(options.dedup_state.as_deref())?;
+                if let Some(dedup_state) = &options.dedup_state {
+                    log_info!(json!({"dedup_state": dedup_state, "keys": dedup.len()}), "Loaded {:?} dedup keys from {:?}", dedup.len(), dedup_state);
+                }
+                Some(Mutex::new(dedup))
+            },
+            None => None,
+        };
+        if let Some(timings_csv) = options.timings_csv.as_ref().filter(|path| fs::metadata(path).map_or(true, |metadata| metadata.len() == 0)) {
+            append_line(timings_csv, TIMINGS_CSV_HEADER)?;
+        }
+        let offset_index = match &options.emit_offset_index {
+            Some(path) => Some(Mutex::new(OffsetIndex { file: BufWriter::new(File::create(path)?), next_offset: 0 })),
+            None => None,
+        };
+        let language_map = match &options.language_map {
+            Some(path) => Some(serde_json::from_str(&fs::read_to_string(path)?)?),
+            None => None,
+        };
+        let encoding_aliases = match &options.encoding_aliases {
+            Some(path) => load_encoding_aliases(path)?,
+            None => HashMap::new(),
+        };
+        let prior_file_stats = match &options.stats_parquet {
+            Some(path) if path.exists() => read_parquet_batches(path)?,
+            _ => Vec::new(),
+        };
+        Ok(ProcessConfig { options, blob_ids, read_limiter, byte_limiter, codec, prefetch_pool, sampler, language_map, encoding_aliases, prior_file_stats, file_stats: Mutex::new(Vec::new()), reencode_counts: Mutex::new(BTreeMap::new()), slowest_rows, dedup, offset_index, contents_replaced: AtomicUsize::new(0), tabs_replaced: AtomicUsize::new(0), json_schema_written: AtomicBool::new(false) })
+    }
+
+    fn is_strict_language(&self, output_language: &str) -> bool {
+        self.options.strict_languages.iter().map(|language| language.trim())
+            .any(|language| language.eq_ignore_ascii_case(output_language) || self.output_language(language).eq_ignore_ascii_case(output_language))
+    }
+
+    fn output_language(&self, language: &str) -> String {
+        // The language as it appears in output file names
+        if let Some(slug) = self.language_map.as_ref().and_then(|language_map| language_map.get(language)) {
+            return slug.clone();
+        }
+        match self.options.normalize_language_names || self.language_map.is_some() {
+            true => language_slug(language),
+            false => language.to_string(),
+        }
+    }
+
+    fn row_encoding<'a>(&'a self, src_encoding: Option<&'a str>) -> &'a str {
+        // The encoding to decode a row with: its src_encoding (through --encoding-aliases), or --default-encoding
+        match encoding_label(src_encoding) {
+            Some(label) => self.encoding_aliases.get(&label.to_lowercase()).map_or(label, String::as_str),
+            None => &self.options.default_encoding,
+        }
+    }
+
+    fn pqt_locations(&self, pqt: &Path) -> Result<(PathBuf, String, String), Error> {
+        // extract_pqt_locations, with --language-from-metadata taking precedence over the directory name
+        let (blob_dir, language, pqt_number) = extract_pqt_locations(pqt.to_path_buf())?;
+        let Some(key) = &self.options.language_from_metadata else {
+            return Ok((blob_dir, language, pqt_number));
+        };
+        let language = read_parquet_key_value(pqt, key)?
+            .ok_or_else(|| Error::msg(format!("Parquet {:?} has no {:?} key in its key-value metadata (--language-from-metadata)", pqt, key)))?;
+        let blob_dir = blob_dir.parent().and_then(Path::parent).unwrap().join(&language).join(&pqt_number);
+        Ok((blob_dir, language, pqt_number))
+    }
+
+    fn output_shard(&self, pqt_number: &str) -> Result<String, Error> {
+        // The shard number as it appears in output file names (--shard-pad-width)
+        let Some(width) = self.options.shard_pad_width else {
+            return Ok(pqt_number.to_string());
+        };
+        let number: u64 = match !pqt_number.is_empty() && pqt_number.bytes().all(|byte| byte.is_ascii_digit()) {
+            true => pqt_number.parse()?,
+            false => return Err(Error::msg(format!("Shard number {:?} isn't numeric, so it can't be padded (--shard-pad-width)", pqt_number))),
+        };
+        Ok(format!("{:0width$}", number, width = width as usize))
+    }
+
+    fn record_file_stats(&self, row: FileStatsRow) -> Result<(), Error> {
+        let Some(stats_parquet) = &self.options.stats_parquet else {
+            return Ok(());
+        };
+        let mut file_stats = self.file_stats.lock().unwrap();
+        file_stats.push(row);
+        let mut batches = self.prior_file_stats.clone();
+        batches.push(file_stats_batch(&file_stats)?);
+        write_parquet_atomic(stats_parquet, &batches, self.options.fsync)
+    }
+
+    fn check_reencode(&self, blob_id: &str, encoding: &str, decoded_with: &str, contents: &str, blob_bytes: &[u8]) {
+        // --verify-reencode, for the rows in its sample
+        if !in_hash_sample(blob_id, self.options.seed, self.options.reencode_sample_fraction) {
+            return;
+        }
+        let reencoded = encode_from_string(contents, decoded_with);
+        let mut reencode_counts = self.reencode_counts.lock().unwrap();
+        let counts = reencode_counts.entry(encoding.to_string()).or_default();
+        match reencoded {
+            None => counts.unsupported += 1,
+            Some(reencoded) => {
+                counts.checked += 1;
+                if reencoded.as_deref() == Some(blob_bytes) {
+                    counts.matched += 1;
+                }
+            },
+        }
+    }
+
+    fn log_reencode_counts(&self, pqt: &Path) {
+        // One line per src_encoding seen in pqt's sample, then start over for the next file
+        let reencode_counts = std::mem::take(&mut *self.reencode_counts.lock().unwrap());
+        for (encoding, counts) in reencode_counts {
+            let match_rate = counts.match_rate();
+            log_info!(json!({"parquet": pqt, "src_encoding": encoding, "checked": counts.checked, "matched": counts.matched, "unsupported": counts.unsupported, "match_rate": match_rate}),
+                      "Re-encoding round-tripped {:?}/{:?} sampled {} rows ({:?} with no encoder to check)", counts.matched, counts.checked, encoding, counts.unsupported);
+        }
+    }
+
+    fn settle_dedup(&self, file_written: bool) {
+        if let Some(dedup) = &self.dedup {
+            dedup.lock().unwrap().settle(file_written);
+        }
+    }
+
+    fn save_dedup_state(&self) -> Result<(), Error> {
+        // Appends the keys of every file written so far; also run when a batch fails, for a --checkpoint-file retry
+        if let (Some(dedup), Some(dedup_state)) = (&self.dedup, &self.options.dedup_state) {
+            let num_new = dedup.lock().unwrap().append_new_keys(dedup_state)?;
+            log_info!(json!({"dedup_state": dedup_state, "new_keys": num_new}), "Appended {:?} new dedup keys to {:?}", num_new, dedup_state);
+        }
+        Ok(())
+    }
+
+    fn finish_run(&self) -> Result<(), Error> {
+        // Once the whole run is done: --report-top-slowest-rows, saving --dedup-state, syncing --emit-offset-index,
+        // and counting --contents-replace's and --tabs-to-spaces' rows
+        if let Some(slowest_rows) = &self.slowest_rows {
+            for (rank, (elapsed, blob_id)) in slowest_rows.lock().unwrap().sorted().into_iter().enumerate() {
+                let msecs = elapsed.as_secs_f64() * 1000.0;
+                log_info!(json!({"rank": rank + 1, "blob_id": blob_id, "msecs": msecs}), "Slowest row #{}: blob_id {:?} took {:.3} msecs", rank + 1, blob_id, msecs);
+            }
+        }
+        self.save_dedup_state()?;
+        if let (Some(offset_index), Some(path)) = (&self.offset_index, &self.options.emit_offset_index) {
+            let mut offset_index = offset_index.lock().unwrap();
+            offset_index.file.flush()?;
+            offset_index.file.get_ref().sync_all()?;
+            let num_records = offset_index.file.get_ref().metadata()?.len() / 8;
+            log_info!(json!({"offset_index": path, "records": num_records, "bytes": offset_index.next_offset}), "Wrote {:?} record offsets to {:?}", num_records, path);
+        }
+        if !self.options.contents_replace.is_empty() {
+            let num_replaced = self.contents_replaced.load(Ordering::Relaxed);
+            log_info!(json!({"contents_replaced": num_replaced}), "Replaced contents in {:?} rows", num_replaced);
+        }
+        if self.options.tabs_to_spaces.is_some() {
+            let num_replaced = self.tabs_replaced.load(Ordering::Relaxed);
+            log_info!(json!({"tabs_replaced": num_replaced}), "Replaced tabs with spaces in {:?} rows", num_replaced);
+        }
+        Ok(())
+    }
+
+    fn write_samples(&self) -> Result<(), Error> {
+        if let (Some(sample_out), Some(sampler)) = (&self.options.sample_out, &self.sampler) {
+            let samples = sampler.lock().unwrap().samples();
+            write_bytes_atomic(serde_json::to_vec_pretty(&samples)?, sample_out, None, self.options.fsync)?;
+        }
+        Ok(())
+    }
+}
+
+
+/*=================================================
+=                HELPER METHODS                   =
+=================================================*/
+
+
+fn build_pbar(num_items: usize, units: &str) -> ProgressBar {
+    let mut template = String::from(units);
+    template.push_str(" {human_pos}/{human_len} [{elapsed_precise}/{duration_precise}] [{wide_bar:.cyan/blue}]");
+    let pbar = ProgressBar::with_draw_target(Some(num_items as u64), progress_draw_target())
+        .with_style(
+            ProgressStyle::with_template(&template).unwrap()
+        );
+    pbar.inc(0);
+    pbar
+}
+
+
+
+fn log_file_summary(input: &Path, stats: &ProcessStats, start_main: Instant) {
+    // One line with the whole outcome of a file (whatever the progress bars are doing), then warnings for anything left out
+    let msecs = start_main.elapsed().as_millis();
+    let mut fields = stats.to_json();
+    fields["input"] = json!(input);
+    fields["msecs"] = json!(msecs);
+    let skipped = match stats.total_skipped() {
+        0 => String::from("0"),
+        total => format!("{:?} {}", total, fields["rows_skipped"]),
+    };
+    log_info!(fields, "Finished {:?}: {:?} rows written, {:?} missing, {} skipped, {:?} files, {:?} bytes out (ratio {:.3}), in {:?} msecs",
+              input, stats.rows_written, stats.rows_missing, skipped, stats.chunks_written, stats.bytes_compressed,
+              stats.compression_ratio().unwrap_or(0.0), msecs);
+    if stats.rows_corrupt > 0 {
+        log_warn!(json!({"corrupt": stats.rows_corrupt}), "Left out {:?} rows with corrupt blobs", stats.rows_corrupt);
+    }
+    if stats.rows_decode_failed > 0 {
+        log_warn!(json!({"decode_failed": stats.rows_decode_failed}), "Left out {:?} rows that failed to decode", stats.rows_decode_failed);
+    }
+}
+
+
+
+fn load_encoding_aliases(path: &Path) -> Result<HashMap<String, String>, Error> {
+    // --encoding-aliases, keyed by lowercased label. Checked up front, so a typo'd target fails the run, not every row
+    let aliases: BTreeMap<String, String> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    for (label, encoding) in &aliases {
+        if decode_to_string(b"", encoding, false).is_err() {
+            return Err(Error::msg(format!("--encoding-aliases maps {:?} to {:?}, which isn't an encoding we can decode", label, encoding)));
+        }
+    }
+    Ok(aliases.into_iter().map(|(label, encoding)| (label.to_lowercase(), encoding)).collect())
+}
+
+
+fn extract_pqt_locations(pqt: PathBuf) -> Result<(PathBuf, String, String), Error> {
+    /* Given a parquet file of the form 
+    BASE_DIR/the-stack-v2/raw-hf-parquets/<PROGRAMMING_LANGUAGE>/train-XXXX-of-YYYY.parquet
+
+    outputs the (dir where contents live, language, number XXXX above)
+    */
+    let pl_dir = pqt.parent().unwrap();
+    let raw_hf_dir = pl_dir.parent().unwrap();
+    let stack_dir = raw_hf_dir.parent().unwrap();
+
+    let language = pl_dir.file_name().unwrap().to_str().unwrap();
+    let number = pqt.file_name().unwrap().to_str().unwrap().split('-').nth(1).unwrap();
+    let blob_dir = stack_dir.join("data").join(language).join(number);
+
+    Ok((blob_dir, language.to_string(), number.to_string())) 
+}
+
+
+fn parse_output_file_name(path: &Path) -> Result<(String, String), Error> {
+    /* Inverse of get_output_file_loc/get_single_file_loc: given a file named like
+    <LANGUAGE>-<XXXX>[-partNNNN|-part_<value>][-NNNNNN-of-NNNNNN].jsonl.zstd
+    outputs the (language, number XXXX above)
+    */
+    let bad_name = || Error::msg(format!("Can't parse language/shard from {:?}", path));
+    let file_name = path.file_name().and_then(|name| name.to_str()).ok_or_else(bad_name)?;
+    let stem = file_name.split(".jsonl").next().unwrap();
+    let parts: Vec<&str> = stem.rsplitn(4, '-').collect(); // [total, "of", chunk, prefix]
+    let mut prefix = match parts.as_slice() {
+        [_, "of", _, prefix] => prefix,
+        _ => stem, // --single-file output
+    };
+    if let Some((rest, partition)) = prefix.rsplit_once('-') {
+        if partition.starts_with("part") {
+            prefix = rest;
+        }
+    }
+    let (language, number) = prefix.rsplit_once('-').ok_or_else(bad_name)?;
+    Ok((language.to_string(), number.to_string()))
+}
+
+
+fn language_slug(language: &str) -> String {
+    // Runs of unsafe chars collapse into one _, and leading/trailing ones are dropped
+    let language = language.to_lowercase().replace("++", "pp").replace('#', "sharp");
+    let mut slug = String::with_capacity(language.len());
+    for c in language.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+    slug.trim_end_matches('_').to_string()
+}
+
+
+fn get_output_file_loc(local_jsonl_dir: &Path, language: &str, parquet_num: &str, partition: Option<&str>, jsonl_num: usize, total_num: usize, extension: &str) -> PathBuf {
+    let filename = match partition {
+        Some(partition) => format!("{}-{}-{}-{:06}-of-{:06}.{}", language, parquet_num, partition, jsonl_num, total_num, extension),
+        None => format!("{}-{}-{:06}-of-{:06}.{}", language, parquet_num, jsonl_num, total_num, extension),
+    };
+    local_jsonl_dir.join(filename)
+}
+
+
+fn get_single_file_loc(local_jsonl_dir: &Path, language: &str, parquet_num: &str, partition: Option<&str>, extension: &str) -> PathBuf {
+    let filename = match partition {
+        Some(partition) => format!("{}-{}-{}.{}", language, parquet_num, partition, extension),
+        None => format!("{}-{}.{}", language, parquet_num, extension),
+    };
+    local_jsonl_dir.join(filename)
+}
+
+
+
+
+
+
+fn transform_contents(mut contents: String, config: &ProcessConfig) -> String {
+    // Any edits to the decoded text happen here, before it gets turned into json
+    let options = &config.options;
+    if !options.contents_replace.is_empty() {
+        let replaced;
+        (contents, replaced) = replace_contents(contents, &options.contents_replace);
+        if replaced {
+            config.contents_replaced.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    if let Some(num_spaces) = options.tabs_to_spaces {
+        if contents.contains('\t') {
+            contents = contents.replace('\t', &" ".repeat(num_spaces));
+            config.tabs_replaced.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    if options.trim_trailing_newline && contents.ends_with('\n') {
+        contents.pop();
+    }
+    if let Some(limit) = options.truncate_contents {
+        truncate_contents(&mut contents, limit);
+    }
+    contents
+}
+
+
+fn contents_to_json(contents: String, options: &ProcessOptions) -> JsonValue {
+    if !options.contents_as_lines {
+        return JsonValue::String(contents);
+    }
+    // split_inclusive so that a trailing newline doesn't create a spurious empty last line
+    let lines = contents.split_inclusive('\n')
+        .map(|line| {
+            if options.keep_line_endings {
+                JsonValue::String(line.to_string())
+            } else {
+                JsonValue::String(line.strip_suffix('\n').unwrap_or(line).to_string())
+            }
+        })
+        .collect();
+    JsonValue::Array(lines)
+}
+
+
+fn finish_row(mut row: JsonValue, contents: String, config: &ProcessConfig) -> RowOutcome {
+    // Transforms the decoded contents, decides whether the row is kept, and if so sets its contents field
+    let options = &config.options;
+    let contents = transform_contents(contents, config);
+    if options.skip_empty_contents && contents.is_empty() {
+        return RowOutcome::Skip(SkipReason::EmptyContents);
+    }
+    if options.skip_whitespace_only && contents.trim().is_empty() {
+        return RowOutcome::Skip(SkipReason::WhitespaceOnly);
+    }
+    if options.min_contents_length.is_some_and(|min_length| min_length.longer_than(&contents)) {
+        return RowOutcome::Skip(SkipReason::TooShort);
+    }
+    if options.skip_binary && looks_binary(&contents) {
+        return RowOutcome::Skip(SkipReason::Binary);
+    }
+    if options.detect_binary {
+        row["is_binary"] = JsonValue::Bool(looks_binary(&contents));
+    }
+    if let Some(algorithm) = options.hash_contents {
+        row["content_hash"] = JsonValue::String(algorithm.hash_hex(contents.as_bytes()));
+    }
+    row["contents"] = contents_to_json(contents, options);
+    RowOutcome::Keep(row)
+}
+
+
+/// Where a parquet's blobs live, plus (with --cache-blob-listing) the set of blobs known to be there
+struct BlobStore {
+    loc: PathBuf,
+    listing: Option<HashSet<String>>,
+    url_template: Option<String>,
+    prefetched: Mutex<HashMap<String, Result<Vec<u8>, Error>>>,
+}
+
+impl BlobStore {
+    fn open(loc: PathBuf, config: &ProcessConfig) -> Result<Self, Error> {
+        let listing = match config.options.cache_blob_listing {
+            true => {
+                let start_list = Instant::now();
+                let listing = list_blob_dir(&loc)?;
+                let list_msecs = start_list.elapsed().as_millis();
+                log_info!(json!({"blob_dir": loc, "num_blobs": listing.len(), "msecs": list_msecs}), "Listed {:?} blobs in {:?} msecs", listing.len(), list_msecs);
+                Some(listing)
+            },
+            false => None,
+        };
+        Ok(BlobStore { loc, listing, url_template: config.options.blob_source.clone(), prefetched: Mutex::new(HashMap::new()) })
+    }
+
+    fn prefetch(&self, rows: &[JsonValue], config: &ProcessConfig) {
+        // --prefetch-concurrency: fetch (but don't decode) every blob of the chunk about to be processed.
+        // A no-op for local blob dirs, where reads are cheap enough to do inline
+        let (Some(url_template), Some(pool)) = (&self.url_template, &config.prefetch_pool) else {
+            return;
+        };
+        let fetched: Vec<(String, Result<Vec<u8>, Error>)> = pool.install(|| {
+            rows.par_iter()
+                .filter_map(|row| row["blob_id"].as_str())
+                .filter(|blob_id| self.blob_file(blob_id).is_some())
+                .map(|blob_id| (blob_id.to_string(), fetch_blob_body(&url_template.replace("{blob_id}", blob_id), config)))
+                .collect()
+        });
+        *self.prefetched.lock().unwrap() = fetched.into_iter().collect();
+    }
+
+    fn fetch(&self, url_template: &str, blob_id: &str, config: &ProcessConfig) -> Result<Vec<u8>, Error> {
+        let url = url_template.replace("{blob_id}", blob_id);
+        let prefetched = self.prefetched.lock().unwrap().remove(blob_id);
+        let body = match prefetched {
+            Some(body) => body?,
+            None => fetch_blob_body(&url, config)?,
+        };
+        decompress_blob(body, Path::new(&url))
+    }
+
+    fn blob_file(&self, blob_id: &str) -> Option<PathBuf> {
+        /* Where a blob is: <blob_id>.gz, or else <blob_id>.zst (as left by recompress). With --cache-blob-listing,
+        going by the listing, and None if it has neither; otherwise <blob_id>.gz unless only the .zst exists.
+        Either file is decompressed by what its bytes start with, not its extension
+        */
+        let names = BLOB_EXTENSIONS.iter().map(|extension| format!("{}.{}", blob_id, extension));
+        match &self.listing {
+            Some(listing) => names.into_iter().find(|name| listing.contains(name)).map(|name| self.loc.join(name)),
+            None => {
+                let gz_file = self.loc.join(format!("{}.gz", blob_id));
+                let zst_file = self.loc.join(format!("{}.zst", blob_id));
+                Some(if !gz_file.exists() && zst_file.exists() { zst_file } else { gz_file })
+            },
+        }
+    }
+
+    fn read(&self, blob_id: &str, config: &ProcessConfig) -> Result<Vec<u8>, Error> {
+        // A blob's (decompressed, but not yet decoded) bytes, from wherever the blobs live
+        let Some(blob_file) = self.blob_file(blob_id) else {
+            return Err(FileProcessError::FileNotFound { filename: self.loc.join(format!("{}.gz", blob_id)) }.into());
+        };
+        match &self.url_template {
+            Some(url_template) => self.fetch(url_template, blob_id, config),
+            None => {
+                self.throttle(&blob_file, config);
+                read_blob_file(&blob_file)
+            },
+        }
+    }
+
+    fn throttle(&self, blob_file: &Path, config: &ProcessConfig) {
+        // --max-reads-per-sec/--max-read-bps for a local blob read
+        if let Some(read_limiter) = &config.read_limiter {
+            read_limiter.acquire(1.0);
+        }
+        if let Some(byte_limiter) = &config.byte_limiter {
+            // Throttles on the (compressed) bytes read from the store; a missing blob costs nothing here
+            let blob_bytes = fs::metadata(blob_file).map(|metadata| metadata.len()).unwrap_or(0);
+            byte_limiter.acquire(blob_bytes as f64);
+        }
+    }
+
+    fn streamed_file(&self, blob_id: &str, config: &ProcessConfig) -> Option<PathBuf> {
+        // --stream-blobs-above-bytes: the blob's (local) file, if it's big enough to be streamed rather than read whole
+        let threshold = config.options.stream_blobs_above_bytes?;
+        let blob_file = self.blob_file(blob_id).filter(|_| self.url_template.is_none())?;
+        fs::metadata(&blob_file).is_ok_and(|metadata| metadata.len() > threshold).then_some(blob_file)
+    }
+}
+
+
+// What blob files can be named, in the order they're looked for
+const BLOB_EXTENSIONS: [&str; 2] = ["gz", "zst"];
+
+
+fn fetch_blob_body(url: &str, config: &ProcessConfig) -> Result<Vec<u8>, Error> {
+    // --blob-source: GETs one (still gzipped) blob, retrying transient failures
+    let options = &config.options;
+    let timeout = Duration::from_secs(options.blob_fetch_timeout_secs);
+    if let Some(read_limiter) = &config.read_limiter {
+        read_limiter.acquire(1.0);
+    }
+    let mut attempt = 0;
+    loop {
+        let response = http_get(url, timeout);
+        let transient = match &response {
+            Ok(response) => response.status >= 500,
+            Err(_) => true,
+        };
+        if transient && attempt < options.blob_fetch_retries {
+            let error = match &response {
+                Ok(response) => format!("HTTP {}", response.status),
+                Err(e) => e.to_string(),
+            };
+            log_warn!(json!({"url": url, "attempt": attempt + 1, "error": error}), "Fetching {} failed ({}), retrying", url, error);
+            std::thread::sleep(Duration::from_millis((100u64 << attempt.min(16)).min(MAX_FETCH_BACKOFF_MS)));
+            attempt += 1;
+            continue;
+        }
+        let response = response?;
+        return match response.status {
+            200 => {
+                if let Some(byte_limiter) = &config.byte_limiter {
+                    byte_limiter.acquire(response.body.len() as f64);
+                }
+                Ok(response.body)
+            },
+            404 => Err(FileProcessError::FileNotFound { filename: PathBuf::from(url) }.into()),
+            status => Err(Error::msg(format!("GET {} returned HTTP {}", url, status))),
+        };
+    }
+}
+
+
+fn process_row(mut row: JsonValue, blobs: &BlobStore, config: &ProcessConfig) -> Result<RowOutcome, Error> {
+    let start_row = Instant::now();
+    let Some(blob_id) = row.get("blob_id").and_then(JsonValue::as_str) else {
+        // Malformed input (e.g. a jsonl row without one) rather than a bug, so it fails the file instead of panicking
+        return Err(Error::msg(format!("Row has no string blob_id to fetch contents for: {}", row)));
+    };
+    if let Some(blob_file) = blobs.streamed_file(blob_id, config) {
+        // Read later, as the row is serialized
+        blobs.throttle(&blob_file, config);
+        let encoding = config.row_encoding(row["src_encoding"].as_str()).to_string();
+        if !config.options.keep_src_encoding {
+            if let Some(fields) = row.as_object_mut() {
+                fields.remove("src_encoding");
+            }
+        }
+        return Ok(RowOutcome::Stream(row, StreamedBlob { blob_file, encoding }));
+    }
+    let blob_contents = blobs.read(blob_id, config);
+    match blob_contents {
+        Ok(contents) => {
+            let raw_bytes = config.options.emit_raw_bytes.map(|mode| (mode, BASE64_STANDARD.encode(&contents)));
+            if let Some((RawBytesMode::Instead, raw_bytes)) = raw_bytes {
+                row["contents_bytes"] = JsonValue::String(raw_bytes);
+                return Ok(RowOutcome::Keep(row));
+            }
+            let encoding = config.row_encoding(row["src_encoding"].as_str());
+            let (utf_str, decoded_with) = match decode_to_string(&contents, encoding, config.options.is_lossy(encoding)) {
+                Ok(decoded) => decoded,
+                Err(e) if config.options.utf8_policy_for(encoding) == Some(Utf8Policy::Strict) => {
+                    return Ok(RowOutcome::DecodeFailed(e.to_string()));
+                },
+                Err(e) => return Err(FileProcessError::DecodeFailed { blob_id: blob_id.to_string(), reason: e.to_string() }.into()),
+            };
+            if let Some(row_timeout_ms) = config.options.row_timeout_ms {
+                let row_msecs = start_row.elapsed().as_millis();
+                if row_msecs > row_timeout_ms as u128 {
+                    let reason = format!("read and decode took {} msecs, over --row-timeout-ms {}", row_msecs, row_timeout_ms);
+                    return Err(FileProcessError::DecodeFailed { blob_id: blob_id.to_string(), reason }.into());
+                }
+            }
+            if config.options.verify_reencode {
+                config.check_reencode(blob_id, encoding, decoded_with, &utf_str, &contents);
+            }
+            if config.options.emit_decoded_with {
+                row["decoded_with"] = JsonValue::String(decoded_with.to_string());
+            }
+            if !config.options.keep_src_encoding && config.options.emit_raw_bytes.is_none() {
+                if let Some(fields) = row.as_object_mut() {
+                    fields.remove("src_encoding");
+                }
+            }
+            if let Some((_, raw_bytes)) = raw_bytes {
+                row["contents_bytes"] = JsonValue::String(raw_bytes);
+            }
+            Ok(finish_row(row, utf_str, config))
+        },
+        Err(e) => {
+            Err(e)
+        }
+    }
+
+
+}
+
+
+
+fn stream_row(mut row: JsonValue, blob_id: &str, blob: StreamedBlob, config: &ProcessConfig) -> Result<RowOutcome, Error> {
+    /* Serializes a RowOutcome::Stream row with its blob's contents spliced in as they're decoded. Comes out exactly
+    as row_json + frame_record would make it: keys in (sorted) order, and contents escaped by serde_json a piece at a time
+    (JSON escapes are per char, so escaping the pieces separately gives the same as escaping them all at once)
+    */
+    let options = &config.options;
+    let start_row = Instant::now();
+    let lossy = options.is_lossy(&blob.encoding);
+    let decode_failure = |e: Error| match e.downcast_ref() {
+        // --utf8-policy strict, as in process_row
+        Some(FileProcessError::DecodeFailed { reason, .. }) if options.utf8_policy_for(&blob.encoding) == Some(Utf8Policy::Strict) => {
+            Ok(RowOutcome::DecodeFailed(reason.clone()))
+        },
+        _ => Err(e),
+    };
+    row["contents"] = JsonValue::Null; // so that it's visited in its place among the keys
+    let Some(fields) = row.as_object() else {
+        return Err(Error::msg(format!("Row with blob_id {} isn't a JSON object", blob_id)));
+    };
+    let mut record = String::from("{");
+    let mut contents_bytes = 0;
+    let mut decoded_with = None;
+    for (name, value) in fields.iter().filter(|(name, _)| !options.exclude_columns.contains(name)) {
+        if record.len() > 1 {
+            record.push(',');
+        }
+        record.push_str(&serde_json::to_string(name)?);
+        record.push(':');
+        if name != "contents" {
+            record.push_str(&value.to_string());
+            continue;
+        }
+        record.push('"');
+        let decoded = stream_decode_blob(&blob.blob_file, blob_id, &blob.encoding, lossy, |text| {
+            let escaped = serde_json::to_string(text).unwrap();
+            record.push_str(&escaped[1..escaped.len() - 1]);
+            contents_bytes += text.len();
+        });
+        match decoded {
+            Ok(decoded) => decoded_with = Some(decoded),
+            Err(e) => return decode_failure(e),
+        }
+        record.push('"');
+    }
+    if decoded_with.is_none() {
+        // --exclude-columns contents: still decoded, so the row fails (or doesn't) just like it would otherwise
+        match stream_decode_blob(&blob.blob_file, blob_id, &blob.encoding, lossy, |text| contents_bytes += text.len()) {
+            Ok(decoded) => decoded_with = Some(decoded),
+            Err(e) => return decode_failure(e),
+        }
+    }
+    record.push('}');
+    if let Some(row_timeout_ms) = options.row_timeout_ms {
+        let row_msecs = start_row.elapsed().as_millis();
+        if row_msecs > row_timeout_ms as u128 {
+            let reason = format!("read and decode took {} msecs, over --row-timeout-ms {}", row_msecs, row_timeout_ms);
+            return Err(FileProcessError::DecodeFailed { blob_id: blob_id.to_string(), reason }.into());
+        }
+    }
+    log_info!(json!({"blob_id": blob_id, "blob_file": blob.blob_file, "decoded_with": decoded_with, "contents_bytes": contents_bytes}),
+              "Streamed blob {} ({:?} bytes of contents)", blob_id, contents_bytes);
+    Ok(RowOutcome::Serialized { bytes: options.frame_record(record)?, contents_bytes })
+}
+
+
+fn reprocess_row(mut row: JsonValue, config: &ProcessConfig) -> Result<RowOutcome, Error> {
+    // Rows from an existing jsonl already have contents, so only the content transforms get (re)applied
+    match row.get("contents") {
+        Some(JsonValue::String(_)) => {
+            let contents = match row["contents"].take() {
+                JsonValue::String(contents) => contents,
+                _ => unreachable!(),
+            };
+            Ok(finish_row(row, contents, config))
+        },
+        _ => Ok(RowOutcome::Keep(row)),
+    }
+}
+
+
+
+fn compress_rows(serialized_rows: Vec<Vec<u8>>, compression_unit: CompressionUnit, codec: &Codec) -> Result<Vec<u8>, Error> {
+    // Concatenated zstd frames are still a valid zstd stream, so either way this decompresses to the whole chunk
+    match compression_unit {
+        CompressionUnit::Row => {
+            let frames: Result<Vec<Vec<u8>>, _> = serialized_rows.par_iter()
+                .map(|row| codec.compress(row.as_slice()))
+                .collect();
+            Ok(frames?.concat())
+        },
+        CompressionUnit::Chunk => {
+            codec.compress(serialized_rows.concat().as_slice())
+        },
+    }
+}
+
+
+
+fn verify_roundtrip(compressed: &[u8], expected: &[u8], chunk_num: usize, codec: &Codec) -> Result<(), Error> {
+    let decompressed = codec.decompress(compressed)?;
+    if decompressed != expected {
+        return Err(FileProcessError::RoundtripMismatch { chunk_num, expected_bytes: expected.len(), actual_bytes: decompressed.len() }.into());
+    }
+    Ok(())
+}
+
+
+
+fn check_overwrite(output_file_loc: &Path, options: &ProcessOptions) -> Result<(), Error> {
+    if !output_file_loc.exists() {
+        return Ok(());
+    }
+    if !options.overwrite && !options.resume {
+        return Err(FileProcessError::OutputExists { filename: output_file_loc.to_path_buf() }.into());
+    }
+    log_warn!(json!({"output": output_file_loc}), "Overwriting existing output {:?}", output_file_loc);
+    Ok(())
+}
+
+
+fn write_checksum_sidecar(output_file_loc: &Path, algorithm: ChecksumAlgorithm, fsync: bool) -> Result<(), Error> {
+    let checksum = algorithm.checksum_file(output_file_loc)?;
+    let file_name = output_file_loc.file_name().unwrap().to_string_lossy();
+    let line = format!("{}  {}\n", checksum, file_name);
+    write_bytes_atomic(line.into_bytes(), &algorithm.sidecar_loc(output_file_loc), None, fsync)
+}
+
+
+fn write_done_marker(output_file_loc: &Path, fsync: bool) -> Result<(), Error> {
+    // --emit-done-markers. Empty, so it's complete as soon as it exists
+    let mut marker_loc = output_file_loc.as_os_str().to_owned();
+    marker_loc.push(".done");
+    let marker = File::create(PathBuf::from(marker_loc))?;
+    if fsync {
+        marker.sync_all()?;
+    }
+    Ok(())
+}
+
+
+fn check_free_space(options: &ProcessOptions, local_jsonl_dir: &Path) -> Result<(), Error> {
+    // --min-free-bytes, for the output directory and (if it's elsewhere) --tmp-dir
+    let Some(min_free_bytes) = options.min_free_bytes else {
+        return Ok(());
+    };
+    for path in std::iter::once(local_jsonl_dir).chain(options.tmp_dir.as_deref()) {
+        let free_bytes = free_bytes(path)?;
+        if free_bytes < min_free_bytes {
+            return Err(FileProcessError::LowDiskSpace { path: path.to_path_buf(), free_bytes, min_free_bytes }.into());
+        }
+    }
+    Ok(())
+}
+
+
+fn verify_written_file(path: &Path, expected_lines: usize, codec: &Codec, framing: RecordFraming) -> Result<(), Error> {
+    // Streams the file back through the decoder, so this works for --single-file outputs too
+    let mut decoder = codec.stream_decoder(File::open(path)?)?;
+    let actual_lines = match framing {
+        RecordFraming::Ndjson => {
+            let mut buffer = vec![0u8; 1 << 16];
+            let mut actual_lines = 0;
+            let mut last_byte = b'\n';
+            loop {
+                let num_read = decoder.read(&mut buffer)?;
+                if num_read == 0 {
+                    break;
+                }
+                actual_lines += buffer[..num_read].iter().filter(|byte| **byte == b'\n').count();
+                last_byte = buffer[num_read - 1];
+            }
+            // A last line without its '\n' (--final-newline strip) is still a line
+            actual_lines + (last_byte != b'\n') as usize
+        },
+        RecordFraming::LengthPrefixed => framing.read_records(BufReader::new(decoder), |_| Ok(()))?,
+    };
+    if actual_lines != expected_lines {
+        return Err(FileProcessError::WriteVerifyFailed { filename: path.to_path_buf(), expected_lines, actual_lines }.into());
+    }
+    Ok(())
+}
+
+
+
+/*=============================================
+=                 COLLECT METHOD              =
+=============================================*/
+
+
+/// Which partition a chunk sequence is: a --num-partitions hash bucket, or a --partition-by value (sanitized)
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum OutputPartition {
+    Hash(usize),
+    Value(String),
+}
+
+impl OutputPartition {
+    fn file_label(&self) -> String {
+        // What goes in file names. parse_output_file_name recognizes both by their "part" prefix
+        match self {
+            OutputPartition::Hash(partition) => format!("part{:04}", partition),
+            OutputPartition::Value(slug) => format!("part_{}", slug),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        match self {
+            OutputPartition::Hash(partition) => json!(partition),
+            OutputPartition::Value(slug) => json!(slug),
+        }
+    }
+}
+
+
+fn partition_slug(value: &JsonValue) -> String {
+    // A --partition-by value as it goes in file names. No -, so parse_output_file_name can still split names on it
+    let value = match value {
+        JsonValue::Null => "null".to_string(),
+        JsonValue::String(value) => value.clone(),
+        other => other.to_string(),
+    };
+    let slug = language_slug(&value).replace('-', "_");
+    if slug.is_empty() { "_".to_string() } else { slug }
+}
+
+
+/// Where one sequence of chunks gets written (and how its files are named)
+struct OutputTarget<'a> {
+    local_jsonl_dir: &'a Path,
+    language: &'a str,
+    pqt_number: &'a str,
+    partition: Option<OutputPartition>,
+    chunk_index_offset: usize,
+    total_chunks_override: Option<usize>,
+    extension: &'static str,
+}
+
+impl OutputTarget<'_> {
+    fn file_loc(&self, jsonl_num: usize, total_num: usize) -> PathBuf {
+        // --chunk-index-offset/--total-chunks-override only change the names, so callers keep counting from 0
+        let total_num = self.total_chunks_override.unwrap_or(total_num);
+        let partition = self.partition.as_ref().map(OutputPartition::file_label);
+        get_output_file_loc(self.local_jsonl_dir, self.language, self.pqt_number, partition.as_deref(), self.chunk_index_offset + jsonl_num, total_num, self.extension)
+    }
+
+    fn single_file_loc(&self) -> PathBuf {
+        let partition = self.partition.as_ref().map(OutputPartition::file_label);
+        get_single_file_loc(self.local_jsonl_dir, self.language, self.pqt_number, partition.as_deref(), self.extension)
+    }
+
+    fn resume_progress_loc(&self) -> PathBuf {
+        // Hidden, and not .jsonl.zstd, so nothing downstream mistakes it for an output
+        let single_file_loc = self.single_file_loc();
+        let stem = single_file_loc.file_name().unwrap().to_string_lossy();
+        self.local_jsonl_dir.join(format!(".{}.progress", stem.trim_end_matches(self.extension).trim_end_matches('.')))
+    }
+}
+
+
+fn resume_point(target: &OutputTarget, num_chunks: usize, codec: &Codec) -> Result<usize, Error> {
+    /* With --resume: index of the first chunk that still needs writing.
+    First trusts the .progress file (O(1)) if its chunk count matches and its last chunk's output decompresses.
+    Otherwise falls back to stat-ing outputs from chunk 0 until the first one that doesn't exist
+    */
+    let progress_loc = target.resume_progress_loc();
+    if let Ok(contents) = fs::read_to_string(&progress_loc) {
+        let progress: JsonValue = serde_json::from_str(&contents).unwrap_or_default();
+        let last_chunk = progress["last_chunk"].as_u64().map(|n| n as usize);
+        let total_chunks = progress["total_chunks"].as_u64().map(|n| n as usize);
+        if let (Some(last_chunk), Some(num_chunks)) = (last_chunk, total_chunks.filter(|total| *total == num_chunks)) {
+            let valid = fs::read(target.file_loc(last_chunk, num_chunks)).is_ok_and(|bytes| codec.decompress(bytes.as_slice()).is_ok());
+            if valid {
+                return Ok(last_chunk + 1);
+            }
+        }
+        log_warn!(json!({"progress_file": progress_loc}), "Ignoring stale/invalid progress file {:?}, checking outputs instead", progress_loc);
+    }
+    Ok((0..num_chunks).find(|chunk_num| !target.file_loc(*chunk_num, num_chunks).exists()).unwrap_or(num_chunks))
+}
+
+
+/// What gets done to each row before it's serialized (e.g. fetching its contents from the blob store)
+type RowFn<'a> = dyn Fn(JsonValue) -> Result<RowOutcome, Error> + Sync + 'a;
+type PrefetchFn<'a> = dyn Fn(&[JsonValue]) + Sync + 'a; // called with each chunk's rows before row_fn sees any of them
+
+
+/// Whether a row (that we could read) makes it into the output
+enum RowOutcome {
+    Keep(JsonValue),
+    Skip(SkipReason),
+    DecodeFailed(String), // --utf8-policy strict: counted like a tolerated decode failure, whatever --max-decode-errors says
+    Stream(JsonValue, StreamedBlob), // --stream-blobs-above-bytes: the row so far, its blob left to stream_row
+    Serialized { bytes: Vec<u8>, contents_bytes: usize }, // what stream_row makes of a Stream row
+}
+
+
+/// A blob --stream-blobs-above-bytes leaves to be read as its row is serialized, and the encoding to decode it with
+struct StreamedBlob {
+    blob_file: PathBuf,
+    encoding: String,
+}
+
+
+/// How long a row took, and its blob_id (for --report-top-slowest-rows)
+type RowTiming = (Duration, String);
+
+
+/// What became of each row of a chunk, once serialized
+enum ChunkRow {
+    Line { bytes: Vec<u8>, contents_bytes: usize, dedup: Option<(u64, String)> }, // with --dedup-by, the row's key and blob_id
+    Missing { blob_id: String, line: Option<Vec<u8>> },
+    Corrupt { blob_id: String },
+    DecodeFailed { blob_id: String },
+    Skipped { blob_id: String, reason: SkipReason },
+}
+
+
+fn write_chunks<I>(chunks: I, num_chunks: usize, row_fn: &RowFn, prefetch: Option<&PrefetchFn>, original_order: Option<&[usize]>, target: &OutputTarget, config: &ProcessConfig) -> Result<ProcessStats, Error> 
+where
+    I: Iterator<Item = Result<Vec<JsonValue>, Error>>,
+{
+    // Loops over chunks of rows, fetching contents and writing one .jsonl.zstd per chunk.
+    // original_order (--group-by) is where each row was before grouping, to compare against that order's compression
+    let options = &config.options;
+    if let Some(total_chunks) = target.total_chunks_override.filter(|total| target.chunk_index_offset + num_chunks > *total) {
+        return Err(Error::msg(format!("Chunks {}..{} don't fit in --total-chunks-override {}", target.chunk_index_offset, target.chunk_index_offset + num_chunks, total_chunks)));
+    }
+    let pbar = build_pbar(num_chunks, "Chunks");
+    let mut writer = ChunkWriter::new(target, config, num_chunks);
+    let resume_from = if options.resume { resume_point(target, num_chunks, &config.codec)? } else { 0 };
+    if resume_from > 0 {
+        log_info!(json!({"language": target.language, "shard": target.pqt_number, "resume_from": resume_from, "chunks": num_chunks}), "Resuming at chunk {:?}/{:?}", resume_from, num_chunks);
+        writer.next_file_num = resume_from;
+    }
+    // What becomes of one row: row_fn on it, then serialization (run in parallel over each chunk)
+    let serialize_row = |v: JsonValue, blob_id: String| -> Result<ChunkRow, Error> {
+        let missing_row = (options.on_missing == MissingPolicy::KeepNull).then(|| v.clone());
+        let proc_output = row_fn(v).and_then(|outcome| match outcome {
+            RowOutcome::Stream(mut value, blob) => {
+                if options.extensions_map {
+                    add_ext_language(&mut value, target.language);
+                }
+                stream_row(value, &blob_id, blob, config)
+            },
+            outcome => Ok(outcome),
+        });
+        match proc_output {
+            Ok(RowOutcome::Keep(mut value)) => {
+                // File was read correctly
+                if options.extensions_map {
+                    add_ext_language(&mut value, target.language);
+                }
+                let bytes = options.frame_record(options.row_json(&value))?;
+                if let Some(policy) = options.validate_output_utf8 {
+                    if std::str::from_utf8(options.record_framing.payload(&bytes)).is_err() {
+                        log_warn!(json!({"blob_id": blob_id}), "Row with blob_id {:?} serialized to invalid utf-8", blob_id);
+                        if policy == InvalidUtf8Policy::Fail {
+                            return Err(FileProcessError::InvalidOutputUtf8 { blob_id }.into());
+                        }
+                        return Ok(ChunkRow::Skipped { blob_id, reason: SkipReason::InvalidUtf8 });
+                    }
+                }
+                let dedup = options.dedup_by.map(|dedup_by| {
+                    let key = match (dedup_by, &value["contents"]) {
+                        (DedupBy::BlobId, _) => dedup_key(blob_id.as_bytes()),
+                        (DedupBy::Contents, JsonValue::String(contents)) => dedup_key(contents.as_bytes()),
+                        (DedupBy::Contents, contents) => dedup_key(contents.to_string().as_bytes()), // e.g. --contents-as-lines
+                    };
+                    (key, blob_id)
+                });
+                let contents_bytes = match &value["contents"] {
+                    JsonValue::String(contents) => contents.len(),
+                    JsonValue::Array(lines) => lines.iter().filter_map(JsonValue::as_str).map(str::len).sum(), // --contents-as-lines
+                    _ => 0,
+                };
+                Ok(ChunkRow::Line { bytes, contents_bytes, dedup })
+            }
+            Ok(RowOutcome::Serialized { bytes, contents_bytes }) => {
+                // --dedup-by contents doesn't stream, so any key is the blob_id's
+                let dedup = options.dedup_by.map(|_| (dedup_key(blob_id.as_bytes()), blob_id));
+                Ok(ChunkRow::Line { bytes, contents_bytes, dedup })
+            },
+            Ok(RowOutcome::Stream(..)) => unreachable!("stream_row serializes every streamed row"),
+            Ok(RowOutcome::Skip(reason)) => Ok(ChunkRow::Skipped { blob_id, reason }),
+            Ok(RowOutcome::DecodeFailed(reason)) => {
+                log_warn!(json!({"blob_id": blob_id, "error": reason}), "Couldn't decode blob {}: {}", blob_id, reason);
+                Ok(ChunkRow::DecodeFailed { blob_id })
+            },
+            Err(e) if matches!(e.downcast_ref(), Some(FileProcessError::FileNotFound { ..})) => {
+                // File missing, count it and proceed (keeping just the metadata with --on-missing keep-null)
+                let line = missing_row.map(|mut row| {
+                    row["contents"] = JsonValue::Null;
+                    row["missing"] = json!(true);
+                    options.frame_record(options.row_json(&row))
+                }).transpose()?;
+                Ok(ChunkRow::Missing { blob_id, line })
+            },
+            Err(e) if options.verify_gzip_crc && matches!(e.downcast_ref(), Some(FileProcessError::CorruptBlob { .. })) => {
+                log_warn!(json!({"blob_id": blob_id, "error": e.to_string()}), "{}", e);
+                Ok(ChunkRow::Corrupt { blob_id })
+            },
+            Err(e) if options.max_decode_errors.is_some() && matches!(e.downcast_ref(), Some(FileProcessError::DecodeFailed { .. })) => {
+                log_warn!(json!({"blob_id": blob_id, "error": e.to_string()}), "{}", e);
+                Ok(ChunkRow::DecodeFailed { blob_id })
+            },
+            Err(e) => {
+                // Fails the whole file (and only this file, in a process-directory batch)
+                Err(e)
+            },
+        }
+    };
+    let mut rows_read = 0; // towards --max-decode-errors fractions
+    let strict_language = config.is_strict_language(target.language);
+    let compare_unsorted = options.sort_by_size.is_some() && options.compression_unit == CompressionUnit::Chunk;
+    let mut bytes_compressed_unsorted = 0; // --sort-by-size: what the chunks would have compressed to unsorted
+    let mut ungrouped = original_order
+        .filter(|_| resume_from == 0 && options.compression_unit == CompressionUnit::Chunk)
+        .map(|original_order| UngroupedBaseline::new(original_order, options.max_lines));
+    for (chunk_num, chunk) in chunks.enumerate() {
+        // and process each row of the chunk (in parallel!)
+        let chunk = chunk?;
+        if chunk_num < resume_from {
+            writer.stats.rows_resumed += chunk.len();
+            pbar.inc(1);
+            continue;
+        }
+        let start_chunk = Instant::now();
+        if let Some(prefetch) = prefetch {
+            prefetch(&chunk);
+        }
+        let chunk_size = chunk.len();
+        let chunk_rows: Result<Vec<(ChunkRow, Option<RowTiming>)>, Error> = chunk.into_par_iter()
+            .map(|v| {
+                let start_row = Instant::now();
+                let blob_id = v["blob_id"].as_str().unwrap_or_default().to_string();
+                let timed_blob_id = config.slowest_rows.as_ref().map(|_| blob_id.clone());
+                serialize_row(v, blob_id).map(|chunk_row| (chunk_row, timed_blob_id.map(|blob_id| (start_row.elapsed(), blob_id))))
+            })
+            .collect();
+        let mut serialized_rows: Vec<Vec<u8>> = Vec::with_capacity(chunk_size);
+        let mut row_sizes: Vec<usize> = Vec::new(); // --sort-by-size: decoded contents bytes of each serialized row
+        let mut failed_rows = 0;
+        let mut row_errors: Vec<String> = Vec::new();
+        let mut chunk_slowest = options.report_top_slowest_rows.map(|top| SlowestRows::new(top as usize));
+        let mut dedup_set = config.dedup.as_ref().map(|dedup| dedup.lock().unwrap());
+        for (row_num, (chunk_row, timing)) in chunk_rows?.into_iter().enumerate() {
+            if let (Some(chunk_slowest), Some((elapsed, blob_id))) = (&mut chunk_slowest, timing) {
+                chunk_slowest.offer(elapsed, blob_id);
+            }
+            let position = chunk_num * options.max_lines + row_num;
+            let (blob_id, reason) = match chunk_row {
+                ChunkRow::Line { dedup: Some((key, blob_id)), .. } if !dedup_set.as_mut().unwrap().insert(key) => {
+                    writer.stats.record_skip(SkipReason::Duplicate);
+                    (blob_id, SkipReason::Duplicate.as_str())
+                },
+                ChunkRow::Line { bytes, contents_bytes, .. } => {
+                    writer.stats.bytes_contents += contents_bytes;
+                    if let Some(ungrouped) = &mut ungrouped {
+                        ungrouped.offer(position, bytes.clone());
+                    }
+                    serialized_rows.push(bytes);
+                    row_sizes.push(contents_bytes);
+                    continue;
+                },
+                ChunkRow::Missing { blob_id, line } => {
+                    failed_rows += 1;
+                    if let (Some(ungrouped), Some(line)) = (&mut ungrouped, &line) {
+                        ungrouped.offer(position, line.clone());
+                    }
+                    row_sizes.extend(line.as_ref().map(|_| 0));
+                    serialized_rows.extend(line);
+                    (blob_id, "missing")
+                },
+                ChunkRow::Corrupt { blob_id } => {
+                    writer.stats.rows_corrupt += 1;
+                    (blob_id, "corrupt_blob")
+                },
+                ChunkRow::DecodeFailed { blob_id } => {
+                    writer.stats.rows_decode_failed += 1;
+                    (blob_id, "decode_failed")
+                },
+                ChunkRow::Skipped { blob_id, reason } => {
+                    writer.stats.record_skip(reason);
+                    (blob_id, reason.as_str())
+                },
+            };
+            if let Some(ungrouped) = &mut ungrouped {
+                ungrouped.offer(position, Vec::new());
+            }
+            if options.row_error_log.is_some() {
+                row_errors.push(json!({
+                    "blob_id": blob_id,
+                    "reason": reason,
+                    "language": target.language,
+                    "shard": target.pqt_number,
+                    "partition": target.partition.as_ref().map(OutputPartition::to_json),
+                    "chunk": chunk_num,
+                    "row_index": position,
+                }).to_string());
+            }
+        }
+        if let Some(row_error_log) = &options.row_error_log {
+            if !row_errors.is_empty() {
+                // One append per chunk, so each chunk's lines land together
+                append_line(row_error_log, &row_errors.join("\n"))?;
+            }
+        }
+        if let (Some(slowest_rows), Some(chunk_slowest)) = (&config.slowest_rows, chunk_slowest) {
+            slowest_rows.lock().unwrap().merge(chunk_slowest);
+        }
+        writer.stats.rows_missing += failed_rows;
+        if failed_rows > 0 {
+            log_info!(json!({"language": target.language, "shard": target.pqt_number, "chunk": chunk_num, "missing": failed_rows, "rows": chunk_size}), "Had {:?}/{:?} missing rows", failed_rows, chunk_size);
+        }
+        if ((failed_rows as f64 / chunk_size as f64) > MISSING_THRESHOLD && failed_rows > options.max_missing_absolute) || (failed_rows > 0 && strict_language) {
+            return Err(FileProcessError::TooManyMissing { missing_count: failed_rows, total_files: chunk_size }.into());
+
+        }
+        rows_read += chunk_size;
+        if options.max_decode_errors.is_some_and(|limit| limit.exceeded(writer.stats.rows_decode_failed, rows_read)) {
+            return Err(FileProcessError::TooManyDecodeErrors { decode_errors: writer.stats.rows_decode_failed, rows: rows_read }.into());
+        }
+
+        let chunk_msecs = start_chunk.elapsed().as_millis();
+        log_info!(json!({"language": target.language, "shard": target.pqt_number, "chunk": chunk_num, "msecs": chunk_msecs}), "Processed cuhnk in {:?} msecs", chunk_msecs);
+        if options.slow_chunk_warn_ms.is_some_and(|threshold| chunk_msecs > threshold as u128) {
+            log_warn!(json!({"language": target.language, "shard": target.pqt_number, "chunk": chunk_num, "msecs": chunk_msecs}),
+                      "Chunk {:?} of {}-{} was slow: {:?} msecs", chunk_num, target.language, target.pqt_number, chunk_msecs);
+        }
+        if let Some(ungrouped) = &mut ungrouped {
+            ungrouped.compress_ready(config)?;
+        }
+        if let Some(direction) = options.sort_by_size {
+            if compare_unsorted {
+                bytes_compressed_unsorted += config.codec.compress(&serialized_rows.concat())?.len();
+            }
+            // Stable, so rows of the same size keep their order
+            let mut sized_rows: Vec<(usize, Vec<u8>)> = row_sizes.into_iter().zip(serialized_rows).collect();
+            match direction {
+                SortDirection::Asc => sized_rows.sort_by_key(|(size, _)| *size),
+                SortDirection::Desc => sized_rows.sort_by_key(|(size, _)| std::cmp::Reverse(*size)),
+            }
+            serialized_rows = sized_rows.into_iter().map(|(_, row)| row).collect();
+        }
+        if let Some(sampler) = &config.sampler {
+            let mut sampler = sampler.lock().unwrap();
+            serialized_rows.iter().for_each(|row| sampler.offer(options.record_framing.payload(row)));
+        }
+        let timings = options.timings_csv.as_ref().map(|timings_csv| {
+            (timings_csv, serialized_rows.len(), serialized_rows.iter().map(|row| row.len()).sum::<usize>(), start_chunk.elapsed())
+        });
+        let start_write = Instant::now();
+        writer.add_chunk(serialized_rows)?;
+        if let Some((timings_csv, rows_written, bytes, process_elapsed)) = timings {
+            let partition = match &target.partition {
+                Some(OutputPartition::Hash(partition)) => partition.to_string(),
+                Some(OutputPartition::Value(slug)) => csv_field(slug),
+                None => String::new(),
+            };
+            append_line(timings_csv, &format!("{},{},{},{},{},{},{},{:.3},{:.3}", csv_field(target.language), csv_field(target.pqt_number), partition,
+                                              chunk_num, chunk_size, rows_written, bytes, process_elapsed.as_secs_f64() * 1000.0, start_write.elapsed().as_secs_f64() * 1000.0))?;
+        }
+        pbar.inc(1);
+    }
+    let stats = writer.finish()?;
+    if let Some(mut ungrouped) = ungrouped {
+        let bytes_compressed_ungrouped = ungrouped.finish(config)?;
+        if bytes_compressed_ungrouped > 0 && stats.bytes_compressed > 0 {
+            let grouped_ratio = stats.bytes_uncompressed as f64 / stats.bytes_compressed as f64;
+            let ungrouped_ratio = stats.bytes_uncompressed as f64 / bytes_compressed_ungrouped as f64;
+            log_info!(json!({"language": target.language, "shard": target.pqt_number, "bytes_compressed": stats.bytes_compressed, "bytes_compressed_ungrouped": bytes_compressed_ungrouped, "ratio": grouped_ratio, "ungrouped_ratio": ungrouped_ratio}),
+                      "--group-by compressed {}-{} to {:?} bytes (ratio {:.3}), vs {:?} bytes ungrouped (ratio {:.3})", target.language, target.pqt_number, stats.bytes_compressed, grouped_ratio, bytes_compressed_ungrouped, ungrouped_ratio);
+        }
+    }
+    if compare_unsorted && bytes_compressed_unsorted > 0 && stats.bytes_compressed > 0 {
+        let sorted_ratio = stats.bytes_uncompressed as f64 / stats.bytes_compressed as f64;
+        let unsorted_ratio = stats.bytes_uncompressed as f64 / bytes_compressed_unsorted as f64;
+        log_info!(json!({"language": target.language, "shard": target.pqt_number, "bytes_compressed": stats.bytes_compressed, "bytes_compressed_unsorted": bytes_compressed_unsorted, "ratio": sorted_ratio, "unsorted_ratio": unsorted_ratio}),
+                  "--sort-by-size compressed {}-{} to {:?} bytes (ratio {:.3}), vs {:?} bytes unsorted (ratio {:.3})", target.language, target.pqt_number, stats.bytes_compressed, sorted_ratio, bytes_compressed_unsorted, unsorted_ratio);
+    }
+    Ok(stats)
+}
+
+
+/// --group-by's baseline: the rows re-chunked in their order from before grouping (and any --sort-by-size),
+/// each chunk compressed once all of its rows have been serialized
+struct UngroupedBaseline<'a> {
+    original_order: &'a [usize],
+    max_lines: usize,
+    pending: BTreeMap<usize, Vec<u8>>, // by original position, empty for rows that aren't written
+    next_chunk_start: usize,
+    bytes_compressed: usize,
+}
+
+impl<'a> UngroupedBaseline<'a> {
+    fn new(original_order: &'a [usize], max_lines: usize) -> Self {
+        UngroupedBaseline { original_order, max_lines, pending: BTreeMap::new(), next_chunk_start: 0, bytes_compressed: 0 }
+    }
+
+    fn offer(&mut self, position: usize, bytes: Vec<u8>) {
+        self.pending.insert(self.original_order[position], bytes);
+    }
+
+    fn compress_ready(&mut self, config: &ProcessConfig) -> Result<(), Error> {
+        while self.next_chunk_start < self.original_order.len() {
+            let chunk_end = (self.next_chunk_start + self.max_lines).min(self.original_order.len());
+            if self.pending.range(self.next_chunk_start..chunk_end).count() < chunk_end - self.next_chunk_start {
+                break;
+            }
+            self.compress_until(chunk_end, config)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, config: &ProcessConfig) -> Result<usize, Error> {
+        // Whatever is left (rows of a file cut short), as one last chunk
+        self.compress_ready(config)?;
+        if !self.pending.is_empty() {
+            self.compress_until(self.original_order.len(), config)?;
+        }
+        Ok(self.bytes_compressed)
+    }
+
+    fn compress_until(&mut self, chunk_end: usize, config: &ProcessConfig) -> Result<(), Error> {
+        let rest = self.pending.split_off(&chunk_end);
+        let chunk: Vec<u8> = std::mem::replace(&mut self.pending, rest).into_values().flatten().collect();
+        if !chunk.is_empty() {
+            self.bytes_compressed += config.codec.compress(&chunk)?.len();
+        }
+        self.next_chunk_start = chunk_end;
+        Ok(())
+    }
+}
+
+
+const TIMINGS_CSV_HEADER: &str = "language,shard,partition,chunk,rows_read,rows_written,bytes,process_msecs,write_msecs";
+
+
+fn csv_field(value: &str) -> String {
+    // Quoted (with quotes doubled) only when it has to be
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+
+/// Turns serialized rows into output files: by default exactly one file per chunk,
+/// with --target-file-bytes, files rolled near the target size (which can span or split chunks),
+/// or with --single-file, every chunk streamed into the same file
+struct ChunkWriter<'a> {
+    target: &'a OutputTarget<'a>,
+    config: &'a ProcessConfig,
+    num_chunks: usize,
+    pending: Vec<Vec<u8>>,
+    pending_bytes: usize,
+    sampled_ratio: Option<f64>,
+    single_file: Option<Encoder<'a, BufWriter<File>>>,
+    chunks_added: usize,
+    next_file_num: usize,
+    held_back_row: Option<Vec<u8>>, // --single-file with --final-newline strip: the latest row, not yet written
+    stats: ProcessStats,
+}
+
+impl<'a> ChunkWriter<'a> {
+    fn new(target: &'a OutputTarget<'a>, config: &'a ProcessConfig, num_chunks: usize) -> Self {
+        ChunkWriter { target, config, num_chunks, pending: Vec::new(), pending_bytes: 0, sampled_ratio: None, single_file: None, chunks_added: 0, next_file_num: 0, held_back_row: None, stats: ProcessStats::default() }
+    }
+
+    fn add_chunk(&mut self, serialized_rows: Vec<Vec<u8>>) -> Result<(), Error> {
+        self.chunks_added += 1;
+        if self.config.options.stdout {
+            return self.write_stdout(serialized_rows);
+        }
+        check_free_space(&self.config.options, self.target.local_jsonl_dir)?;
+        if self.config.options.single_file {
+            return self.stream_to_single_file(serialized_rows);
+        }
+        let target_bytes = match self.config.options.target_file_bytes {
+            None => return self.write_file(serialized_rows),
+            Some(target_bytes) => target_bytes as f64,
+        };
+        let max_bytes = target_bytes * (1.0 + self.config.options.file_bytes_tolerance);
+        if self.sampled_ratio.is_none() && !serialized_rows.is_empty() {
+            // Seed the estimate by trial-compressing the first few rows we see
+            let sample: Vec<Vec<u8>> = serialized_rows.iter().take(COMPRESSION_SAMPLE_ROWS).cloned().collect();
+            let sample_bytes: usize = sample.iter().map(|row| row.len()).sum();
+            let compressed_bytes = compress_rows(sample, self.config.options.compression_unit, &self.config.codec)?.len();
+            self.sampled_ratio = Some(compressed_bytes as f64 / sample_bytes.max(1) as f64);
+        }
+        for row in serialized_rows {
+            if !self.pending.is_empty() && self.estimate_compressed(self.pending_bytes + row.len()) > max_bytes {
+                self.flush_pending()?;
+            }
+            self.pending_bytes += row.len();
+            self.pending.push(row);
+            if self.estimate_compressed(self.pending_bytes) >= target_bytes {
+                self.flush_pending()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stream_to_single_file(&mut self, serialized_rows: Vec<Vec<u8>>) -> Result<(), Error> {
+        // The file is only created once there's a row to put in it
+        if serialized_rows.is_empty() {
+            return Ok(());
+        }
+        let encoder = match &mut self.single_file {
+            Some(encoder) => encoder,
+            None => {
+                check_overwrite(&self.target.single_file_loc(), &self.config.options)?;
+                let tmp_path = tmp_path_for(&self.target.single_file_loc(), self.config.options.tmp_dir.as_deref())?;
+                let file = BufWriter::new(File::create(tmp_path)?);
+                self.single_file.insert(self.config.codec.stream_encoder(file)?)
+            },
+        };
+        self.stats.rows_written += serialized_rows.len();
+        self.stats.bytes_uncompressed += serialized_rows.iter().map(|row| row.len()).sum::<usize>();
+        let mut rows = self.held_back_row.take().into_iter().chain(serialized_rows).peekable();
+        while let Some(row) = rows.next() {
+            if rows.peek().is_none() && self.config.options.final_newline == FinalNewline::Strip {
+                // Might be the file's last row, which only finish knows
+                self.held_back_row = Some(row);
+                break;
+            }
+            encoder.write_all(&row)?;
+        }
+        self.write_progress(self.chunks_added - 1, &self.target.single_file_loc())
+    }
+
+    fn write_stdout(&mut self, serialized_rows: Vec<Vec<u8>>) -> Result<(), Error> {
+        // A whole chunk per lock, so rows never interleave with anything else written to stdout
+        let mut stdout = std::io::stdout().lock();
+        if let Some(offset_index) = &self.config.offset_index {
+            // Under the stdout lock, so the offsets are in the same order as the rows
+            let mut offset_index = offset_index.lock().unwrap();
+            for row in &serialized_rows {
+                let offset = offset_index.next_offset;
+                offset_index.file.write_all(&offset.to_le_bytes())?;
+                offset_index.next_offset += row.len() as u64;
+            }
+            offset_index.file.flush()?;
+        }
+        for row in &serialized_rows {
+            stdout.write_all(row)?;
+        }
+        stdout.flush()?;
+        self.stats.rows_written += serialized_rows.len();
+        self.stats.bytes_uncompressed += serialized_rows.iter().map(|row| row.len()).sum::<usize>();
+        Ok(())
+    }
+
+    fn finish_single_file(&mut self, encoder: Encoder<'a, BufWriter<File>>) -> Result<(), Error> {
+        let output_file_loc = self.target.single_file_loc();
+        let start_save = Instant::now();
+        let file = encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+        if self.config.options.fsync {
+            file.sync_all()?;
+        }
+        self.stats.bytes_compressed += file.metadata()?.len() as usize;
+        let tmp_path = tmp_path_for(&output_file_loc, self.config.options.tmp_dir.as_deref())?;
+        if self.config.options.verify_writes {
+            if let Err(e) = verify_written_file(&tmp_path, self.stats.rows_written, &self.config.codec, self.config.options.record_framing) {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        }
+        rename_into_place(&tmp_path, &output_file_loc, self.config.options.fsync)?;
+        if let Some(algorithm) = self.config.options.emit_checksums {
+            write_checksum_sidecar(&output_file_loc, algorithm, self.config.options.fsync)?;
+        }
+        if self.config.options.emit_done_markers {
+            write_done_marker(&output_file_loc, self.config.options.fsync)?;
+        }
+        self.stats.chunks_written += 1;
+        let save_msecs = start_save.elapsed().as_millis();
+        log_info!(json!({"language": self.target.language, "shard": self.target.pqt_number, "output": output_file_loc, "msecs": save_msecs}), "Finished single file in {:?} msecs", save_msecs);
+        Ok(())
+    }
+
+    fn write_progress(&self, last_chunk: usize, last_output: &Path) -> Result<(), Error> {
+        if let Some(progress_file) = &self.config.options.progress_file {
+            let progress = json!({
+                "last_chunk": last_chunk,
+                "total_chunks": self.num_chunks,
+                "last_output": last_output,
+                "updated_at": SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            });
+            write_json_atomic(progress_file, &progress, self.config.options.fsync)?;
+        }
+        Ok(())
+    }
+
+    fn estimate_compressed(&self, uncompressed_bytes: usize) -> f64 {
+        // Running compression ratio of everything written so far (compression is nonlinear, so this is only an estimate)
+        let ratio = match self.stats.compression_ratio() {
+            Some(ratio) => 1.0 / ratio,
+            None => self.sampled_ratio.unwrap_or(1.0),
+        };
+        uncompressed_bytes as f64 * ratio
+    }
+
+    fn flush_pending(&mut self) -> Result<(), Error> {
+        self.pending_bytes = 0;
+        let rows = std::mem::take(&mut self.pending);
+        self.write_file(rows)
+    }
+
+    fn write_file(&mut self, mut serialized_rows: Vec<Vec<u8>>) -> Result<(), Error> {
+        let options = &self.config.options;
+        if let Some(last_row) = serialized_rows.last_mut() {
+            options.end_file_with(last_row);
+        }
+        let target = self.target;
+        let file_num = self.next_file_num;
+        self.next_file_num += 1;
+        let num_rows = serialized_rows.len();
+        self.stats.rows_written += num_rows;
+        self.stats.bytes_uncompressed += serialized_rows.iter().map(|row| row.len()).sum::<usize>();
+        let expected_bytes = options.verify_roundtrip.then(|| serialized_rows.concat());
+        let processed_chunks = compress_rows(serialized_rows, options.compression_unit, &self.config.codec)?;
+        if let Some(expected_bytes) = expected_bytes {
+            verify_roundtrip(&processed_chunks, &expected_bytes, file_num, &self.config.codec)?;
+        }
+        self.stats.bytes_compressed += processed_chunks.len();
+
+        let output_file_loc = target.file_loc(file_num, self.num_chunks);
+        check_overwrite(&output_file_loc, options)?;
+        let start_save = Instant::now();
+
+        write_bytes_atomic_verified(processed_chunks, &output_file_loc, options.tmp_dir.as_deref(), options.fsync, |tmp_path| match options.verify_writes {
+            true => verify_written_file(tmp_path, num_rows, &self.config.codec, options.record_framing),
+            false => Ok(()),
+        })?;
+        if let Some(algorithm) = options.emit_checksums {
+            write_checksum_sidecar(&output_file_loc, algorithm, options.fsync)?;
+        }
+        if options.emit_done_markers && options.target_file_bytes.is_none() {
+            write_done_marker(&output_file_loc, options.fsync)?;
+        }
+        let save_msecs = start_save.elapsed().as_millis();
+        log_info!(json!({"language": target.language, "shard": target.pqt_number, "chunk": file_num, "output": output_file_loc, "msecs": save_msecs}), "Saved chunk in {:?} msecs", save_msecs);
+        self.stats.chunks_written += 1;
+        if options.resume {
+            let progress = json!({"last_chunk": file_num, "total_chunks": self.num_chunks});
+            write_json_atomic(&target.resume_progress_loc(), &progress, options.fsync)?;
+        }
+        self.write_progress(file_num, &output_file_loc)
+    }
+
+    fn finish(mut self) -> Result<ProcessStats, Error> {
+        if let Some(mut encoder) = self.single_file.take() {
+            if let Some(mut last_row) = self.held_back_row.take() {
+                self.stats.bytes_uncompressed -= self.config.options.end_file_with(&mut last_row);
+                encoder.write_all(&last_row)?;
+            }
+            self.finish_single_file(encoder)?;
+        }
+        if !self.pending.is_empty() {
+            self.flush_pending()?;
+        }
+        self.fix_file_totals()?;
+        if self.config.options.emit_done_markers && self.config.options.target_file_bytes.is_some() {
+            for file_num in 0..self.next_file_num {
+                write_done_marker(&self.target.file_loc(file_num, self.num_chunks), self.config.options.fsync)?;
+            }
+        }
+        Ok(self.stats)
+    }
+
+    fn fix_file_totals(&mut self) -> Result<(), Error> {
+        /* With --target-file-bytes, the number of files rarely matches the chunk count their -of- totals were named
+        with up front, so once the whole sequence is written, rename every file to carry the true total. All the new
+        names are checked before any file is renamed; checksum sidecars name their file, so they're rewritten too
+        */
+        let options = &self.config.options;
+        let num_files = self.next_file_num;
+        if options.target_file_bytes.is_none() || num_files == 0 || num_files == self.num_chunks {
+            return Ok(());
+        }
+        let renames: Vec<(PathBuf, PathBuf)> = (0..num_files)
+            .map(|file_num| (self.target.file_loc(file_num, self.num_chunks), self.target.file_loc(file_num, num_files)))
+            .collect();
+        for (_, final_loc) in &renames {
+            check_overwrite(final_loc, options)?;
+        }
+        for (written_loc, final_loc) in &renames {
+            rename_into_place(written_loc, final_loc, options.fsync)?;
+            if let Some(algorithm) = options.emit_checksums {
+                fs::remove_file(algorithm.sidecar_loc(written_loc))?;
+                write_checksum_sidecar(final_loc, algorithm, options.fsync)?;
+            }
+        }
+        log_info!(json!({"language": self.target.language, "shard": self.target.pqt_number, "files": num_files, "chunks": self.num_chunks}),
+                  "Renamed {:?} files to -of-{:06} (named for {:?} chunks while writing)", num_files, num_files, self.num_chunks);
+        self.num_chunks = num_files;
+        self.write_progress(num_files - 1, &renames[num_files - 1].1)
+    }
+}
+
+
+fn select_rows(mut rows: Vec<JsonValue>, config: &ProcessConfig) -> (Vec<JsonValue>, Option<Vec<usize>>) {
+    // Applies the (whole-file) row filtering and reordering options. With --group-by, also returns each row's
+    // position from before the grouping (by its position after), for comparing against the ungrouped compression
+    let options = &config.options;
+    if let Some(blob_ids) = &config.blob_ids {
+        let num_rows = rows.len();
+        rows.retain(|row| row["blob_id"].as_str().is_some_and(|id| blob_ids.contains(id)));
+        log_info!(json!({"kept": rows.len(), "rows": num_rows}), "Kept {:?}/{:?} rows from blob_ids file", rows.len(), num_rows);
+    }
+    if let Some(blob_id_regex) = &options.blob_id_regex {
+        let num_rows = rows.len();
+        rows.retain(|row| row["blob_id"].as_str().is_some_and(|id| blob_id_regex.is_match(id)));
+        log_info!(json!({"kept": rows.len(), "rows": num_rows, "regex": blob_id_regex.as_str()}), "Kept {:?}/{:?} rows matching blob_id regex {:?}", rows.len(), num_rows, blob_id_regex.as_str());
+    }
+    if options.shuffle {
+        shuffle_rows(&mut rows, options.seed);
+    }
+    if let Some(sort_spec) = &options.sort_by {
+        sort_rows(&mut rows, sort_spec);
+    }
+    let original_order = options.group_by.as_ref().map(|group_column| group_rows(&mut rows, group_column));
+    (rows, original_order)
+}
+
+
+fn write_rows((rows, original_order): (Vec<JsonValue>, Option<Vec<usize>>), local_jsonl_dir: &Path, language: String, pqt_number: &str, row_fn: &RowFn, prefetch: Option<&PrefetchFn>, config: &ProcessConfig) -> Result<ProcessStats, Error> {
+    // Writes chunks of rows (each language and partition gets its own sequence of chunks).
+    // original_order (from select_rows) only applies when the rows all go to one sequence of chunks, unsplit
+    let options = &config.options;
+    let language_groups = match &options.language_from_column {
+        Some(column) => split_rows_by_column(rows, column, &language),
+        None => vec![(language, rows)],
+    };
+    let mut stats = ProcessStats::default();
+    for (language, rows) in language_groups {
+        let language = config.output_language(&language);
+        if let Some(column) = &options.partition_by {
+            for (slug, partition_rows) in split_rows_by(rows, |row| partition_slug(&row[column.as_str()])) {
+                let target = OutputTarget { local_jsonl_dir, language: &language, pqt_number, partition: Some(OutputPartition::Value(slug.clone())), chunk_index_offset: options.chunk_index_offset, total_chunks_override: options.total_chunks_override, extension: config.codec.extension() };
+                let chunks = partition_rows.chunks(options.max_lines).map(|chunk| Ok(chunk.to_vec()));
+                let partition_stats = write_chunks(chunks, partition_rows.len().div_ceil(options.max_lines), row_fn, prefetch, None, &target, config)?;
+                *stats.rows_by_partition.entry(slug).or_insert(0) += partition_stats.rows_written;
+                stats.merge(&partition_stats);
+            }
+        } else if options.num_partitions > 1 {
+            for (partition, partition_rows) in partition_rows(rows, options.num_partitions).iter().enumerate() {
+                let target = OutputTarget { local_jsonl_dir, language: &language, pqt_number, partition: Some(OutputPartition::Hash(partition)), chunk_index_offset: options.chunk_index_offset, total_chunks_override: options.total_chunks_override, extension: config.codec.extension() };
+                let chunks = partition_rows.chunks(options.max_lines).map(|chunk| Ok(chunk.to_vec()));
+                stats.merge(&write_chunks(chunks, partition_rows.len().div_ceil(options.max_lines), row_fn, prefetch, None, &target, config)?);
+            }
+        } else {
+            let target = OutputTarget { local_jsonl_dir, language: &language, pqt_number, partition: None, chunk_index_offset: options.chunk_index_offset, total_chunks_override: options.total_chunks_override, extension: config.codec.extension() };
+            let chunks = rows.chunks(options.max_lines).map(|chunk| Ok(chunk.to_vec()));
+            let original_order = original_order.as_deref().filter(|_| options.language_from_column.is_none());
+            stats.merge(&write_chunks(chunks, rows.len().div_ceil(options.max_lines), row_fn, prefetch, original_order, &target, config)?);
+        }
+    }
+    Ok(stats)
+}
+
+
+fn check_required_columns(pqt: &Path, required: &[String]) -> Result<(), Error> {
+    let schema = read_parquet_schema(pqt)?;
+    let missing: Vec<String> = required.iter()
+        .filter(|column| schema.field_with_name(column).is_err())
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        let available = schema.fields().iter().map(|field| field.name().clone()).collect();
+        return Err(FileProcessError::MissingColumns { missing, available }.into());
+    }
+    Ok(())
+}
+
+
+fn output_json_schema(parquet_schema: &SchemaRef, options: &ProcessOptions) -> JsonValue {
+    /* --output-json-schema: a JSON Schema (draft 2020-12) of the records written for a parquet with this schema.
+    Fields only some rows have, like ext_language_mismatch, or what --on-missing keep-null's rows lack, aren't required
+    */
+    let read_options = options.read_options();
+    let kept_missing = options.on_missing == MissingPolicy::KeepNull;
+    let raw_instead = options.emit_raw_bytes == Some(RawBytesMode::Instead);
+    let mut fields: Vec<(String, JsonValue, bool)> = parquet_schema.fields().iter()
+        .filter_map(|field| Some((field.name().clone(), field_json_schema(field, &read_options)?, true)))
+        .collect();
+    if !options.keep_src_encoding && options.emit_raw_bytes.is_none() {
+        // Dropped from the rows that get decoded, but still on --on-missing keep-null's rows
+        match kept_missing {
+            true => fields.iter_mut().filter(|(name, _, _)| name == "src_encoding").for_each(|(_, _, required)| *required = false),
+            false => fields.retain(|(name, _, _)| name != "src_encoding"),
+        }
+    }
+    if options.add_row_group_index {
+        fields.push(("row_group".to_string(), json!({"type": "integer"}), true));
+    }
+    if !raw_instead {
+        let contents = match options.contents_as_lines {
+            true => json!({"type": "array", "items": {"type": "string"}}),
+            false => json!({"type": "string"}),
+        };
+        fields.push(("contents".to_string(), if kept_missing { nullable_json_schema(contents) } else { contents }, true));
+        if options.detect_binary {
+            fields.push(("is_binary".to_string(), json!({"type": "boolean"}), !kept_missing));
+        }
+        if options.hash_contents.is_some() {
+            fields.push(("content_hash".to_string(), json!({"type": "string"}), !kept_missing));
+        }
+        if options.emit_decoded_with {
+            fields.push(("decoded_with".to_string(), json!({"type": "string"}), !kept_missing));
+        }
+    }
+    if options.emit_raw_bytes.is_some() {
+        fields.push(("contents_bytes".to_string(), json!({"type": "string", "contentEncoding": "base64"}), !kept_missing));
+    }
+    if options.extensions_map {
+        fields.push(("ext_language".to_string(), json!({"type": ["string", "null"]}), !kept_missing));
+        fields.push(("ext_language_mismatch".to_string(), json!({"type": "boolean"}), false));
+    }
+    if kept_missing {
+        fields.push(("missing".to_string(), json!({"type": "boolean"}), false));
+    }
+    fields.retain(|(name, _, _)| !options.exclude_columns.contains(name));
+
+    let required: Vec<&String> = fields.iter().filter(|(_, _, required)| *required).map(|(name, _, _)| name).collect();
+    let properties: serde_json::Map<String, JsonValue> = fields.iter().map(|(name, schema, _)| (name.clone(), schema.clone())).collect();
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "the-stack-v2-packager output record",
+        "packager_version": env!("CARGO_PKG_VERSION"),
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+
+fn process_parquet_file(pqt: &Path, local_jsonl_dir: &Path, config: &ProcessConfig) -> Result<ProcessStats, Error> {
+    // Step 1: load parquet file into vec of rows 
+    let start_main = Instant::now();    
+    let options = &config.options;
+    let (blob_loc, language, pqt_number) = config.pqt_locations(pqt)?;
+    let pqt_number = config.output_shard(&pqt_number)?;
+    if options.input_format != InputFormat::Parquet {
+        return process_jsonl_metadata_file(pqt, blob_loc, local_jsonl_dir, &language, &pqt_number, config);
+    }
+    if !options.require_columns.is_empty() {
+        check_required_columns(pqt, &options.require_columns)?;
+    }
+    if let Some(schema_path) = &options.output_json_schema {
+        if !config.json_schema_written.swap(true, Ordering::Relaxed) {
+            let schema = output_json_schema(&read_parquet_schema(pqt)?, options);
+            write_json_atomic(schema_path, &schema, options.fsync)?;
+            log_info!(json!({"parquet": pqt, "json_schema": schema_path}), "Wrote the output records' JSON Schema (from {:?}) to {:?}", pqt, schema_path);
+        }
+    }
+    if options.emit_parquet_metadata {
+        let mut metadata = read_parquet_file_metadata(pqt)?;
+        metadata["parquet"] = json!(pqt);
+        log_info!(json!({"parquet": pqt, "parquet_metadata": metadata}), "Parquet {:?} was created by {} and has {} row groups",
+                  pqt, metadata["created_by"].as_str().unwrap_or("unknown"), metadata["num_row_groups"]);
+        let sidecar = local_jsonl_dir.join(format!("{}-{}.parquet-metadata.json", config.output_language(&language), pqt_number));
+        write_json_atomic(&sidecar, &metadata, options.fsync)?;
+    }
+    if options.streaming {
+        return process_parquet_file_streaming(pqt, &blob_loc, local_jsonl_dir, &language, &pqt_number, config);
+    }
+    if let Some(max_rows) = options.max_rows_in_memory {
+        let num_rows = read_parquet_num_rows(pqt)?;
+        if num_rows > max_rows {
+            return Err(FileProcessError::TooManyRows { num_rows, max_rows }.into());
+        }
+    }
+    let rows: Vec<JsonValue> = load_parquet_as_json_parallel(pqt.to_path_buf(), &options.read_options())?;
+    let read_msecs = start_main.elapsed().as_millis();
+    log_info!(json!({"parquet": pqt, "msecs": read_msecs}), "Read pqt in {:?} msecs", read_msecs);
+    let rows_loaded = rows.len();
+    let (rows, original_order) = select_rows(rows, config);
+    let rows_filtered = rows_loaded - rows.len();
+
+    // Step 2: fetch contents and write chunks of rows
+    let blobs = BlobStore::open(blob_loc, config)?;
+    let row_fn = |row| process_row(row, &blobs, config);
+    let prefetch = |rows: &[JsonValue]| blobs.prefetch(rows, config);
+    let mut stats = write_rows((rows, original_order), local_jsonl_dir, language, &pqt_number, &row_fn, Some(&prefetch), config)?;
+    stats.rows_filtered = rows_filtered;
+    check_row_count(pqt, &mut stats, config)?;
+
+    log_file_summary(pqt, &stats, start_main);
+    config.write_samples()?;
+    Ok(stats)
+}
+
+
+fn check_row_count(pqt: &Path, stats: &mut ProcessStats, config: &ProcessConfig) -> Result<(), Error> {
+    // Every row the parquet metadata declares has to come out the other end written, missing, or deliberately dropped;
+    // anything else means rows went missing without a trace somewhere between the reader and the writer
+    stats.rows_declared = read_parquet_num_rows(pqt)?;
+    let accounted_rows = stats.rows_accounted(config.options.on_missing == MissingPolicy::KeepNull);
+    if accounted_rows != stats.rows_declared {
+        return Err(FileProcessError::RowCountMismatch { filename: pqt.to_path_buf(), declared_rows: stats.rows_declared, accounted_rows, stats: stats.to_json() }.into());
+    }
+    Ok(())
+}
+
+
+fn process_jsonl_metadata_file(input_file: &Path, blob_loc: PathBuf, local_jsonl_dir: &Path, language: &str, pqt_number: &str, config: &ProcessConfig) -> Result<ProcessStats, Error> {
+    // process_parquet_file for --input-format jsonl/jsonl.zstd: only the row source differs
+    let start_main = Instant::now();
+    let options = &config.options;
+    if options.streaming {
+        return Err(Error::msg("--input-format jsonl/jsonl.zstd doesn't support --streaming/--low-memory/--stdout"));
+    }
+    let rows = read_jsonl_file(input_file)?;
+    let read_msecs = start_main.elapsed().as_millis();
+    log_info!(json!({"input_file": input_file, "msecs": read_msecs}), "Read jsonl in {:?} msecs", read_msecs);
+    if !options.require_columns.is_empty() {
+        // A jsonl has no schema, so a column counts as present if any row has it
+        let available: BTreeSet<&String> = rows.iter()
+            .filter_map(|row| row.as_object())
+            .flat_map(|row| row.keys())
+            .collect();
+        let missing: Vec<String> = options.require_columns.iter().filter(|column| !available.contains(column)).cloned().collect();
+        if !missing.is_empty() {
+            let available = available.into_iter().cloned().collect();
+            return Err(FileProcessError::MissingColumns { missing, available }.into());
+        }
+    }
+    let (rows, original_order) = select_rows(rows, config);
+
+    let blobs = BlobStore::open(blob_loc, config)?;
+    let row_fn = |row| process_row(row, &blobs, config);
+    let prefetch = |rows: &[JsonValue]| blobs.prefetch(rows, config);
+    let stats = write_rows((rows, original_order), local_jsonl_dir, language.to_string(), pqt_number, &row_fn, Some(&prefetch), config)?;
+
+    log_file_summary(input_file, &stats, start_main);
+    config.write_samples()?;
+    Ok(stats)
+}
+
+
+fn file_manifest_loc(pqt: &Path, local_jsonl_dir: &Path, config: &ProcessConfig) -> Result<PathBuf, Error> {
+    let (_, language, pqt_number) = config.pqt_locations(pqt)?;
+    Ok(local_jsonl_dir.join(format!("{}-{}.manifest.json", config.output_language(&language), config.output_shard(&pqt_number)?)))
+}
+
+
+fn file_manifest_complete(manifest_loc: &Path) -> bool {
+    // Present, parses, and is a manifest of ours (a truncated or foreign file means the parquet gets redone)
+    let Ok(manifest) = fs::read_to_string(manifest_loc) else {
+        return false;
+    };
+    serde_json::from_str::<JsonValue>(&manifest)
+        .is_ok_and(|manifest| manifest["manifest_version"] == FILE_MANIFEST_VERSION && manifest["stats"].is_object())
+}
+
+
+fn process_parquet_file_recorded(pqt: &Path, local_jsonl_dir: &Path, config: &ProcessConfig) -> Result<ProcessStats, Error> {
+    // process_parquet_file, plus a --stats-parquet row for how it went (failures included), and the --emit-file-manifest manifest
+    let start_main = Instant::now();
+    let manifest_loc = match config.options.emit_file_manifest {
+        true => Some(file_manifest_loc(pqt, local_jsonl_dir, config)?),
+        false => None,
+    };
+    if let Some(manifest_loc) = manifest_loc.as_ref().filter(|manifest_loc| config.options.skip_if_manifest_exists && file_manifest_complete(manifest_loc)) {
+        log_info!(json!({"parquet": pqt, "manifest": manifest_loc}), "Skipping {:?}, which already has a manifest", pqt);
+        return Ok(ProcessStats::default());
+    }
+    let mut result = process_parquet_file(pqt, local_jsonl_dir, config);
+    if let (Some(manifest_loc), Ok(stats)) = (&manifest_loc, &result) {
+        let manifest = json!({
+            "manifest_version": FILE_MANIFEST_VERSION,
+            "packager_version": env!("CARGO_PKG_VERSION"),
+            "argv": std::env::args().collect::<Vec<String>>(),
+            "parquet": pqt,
+            "finished_at": chrono::Utc::now().to_rfc3339(),
+            "stats": stats.to_json(),
+        });
+        if let Err(e) = write_json_atomic(manifest_loc, &manifest, config.options.fsync) {
+            result = Err(e);
+        }
+    }
+    config.settle_dedup(result.is_ok());
+    if config.options.verify_reencode {
+        config.log_reencode_counts(pqt);
+    }
+    if config.options.stats_parquet.is_some() {
+        let (_, language, shard) = config.pqt_locations(pqt)?;
+        let stats = result.as_ref().cloned().unwrap_or_default();
+        config.record_file_stats(FileStatsRow {
+            parquet: pqt.to_string_lossy().into_owned(),
+            language,
+            shard,
+            rows: stats.rows_written,
+            missing: stats.rows_missing,
+            bytes_in: fs::metadata(pqt).map(|metadata| metadata.len()).unwrap_or(0),
+            bytes_out: stats.bytes_compressed,
+            duration_ms: start_main.elapsed().as_millis(),
+            status: if result.is_ok() { "ok" } else { "failed" },
+        })?;
+    }
+    result
 }
 
 
-#[derive(Subcommand, Debug)]
-enum Commands {
-    #[clap(arg_required_else_help = true)]
-    ProcessParquet { 
-        /// Which parquet file we're working with
-        /// Should be formatted like <DIR>/the-stack-v2/raw-hf-parquets/<PROGRAMMING_LANGUAGE>/train-0000-of-1234.parquet
-        #[arg(required=true, long)]
-        parquet_file: PathBuf,
-
-        /// Where the completed jsonls go
-        #[arg(required=true, long, default_value="/mnt/raid0/jsonls/")]
-        local_jsonl_dir: PathBuf,
+fn process_parquet_file_streaming(pqt: &Path, blob_loc: &Path, local_jsonl_dir: &Path, language: &str, pqt_number: &str, config: &ProcessConfig) -> Result<ProcessStats, Error> {
+    // Same as process_parquet_file, but only ever holds about one chunk of rows in memory
+    let start_main = Instant::now();
+    let options = &config.options;
+    let reader = ParquetChunkReader::new(pqt.to_path_buf(), options.max_lines, &options.read_options())?;
+    let num_chunks = reader.num_rows.div_ceil(options.max_lines);
+    let language = config.output_language(language);
+    let target = OutputTarget { local_jsonl_dir, language: &language, pqt_number, partition: None, chunk_index_offset: options.chunk_index_offset, total_chunks_override: options.total_chunks_override, extension: config.codec.extension() };
+    let blobs = BlobStore::open(blob_loc.to_path_buf(), config)?;
+    let row_fn = |row| process_row(row, &blobs, config);
+    let prefetch = |rows: &[JsonValue]| blobs.prefetch(rows, config);
+    let mut stats = write_chunks(reader, num_chunks, &row_fn, Some(&prefetch), None, &target, config)?;
+    check_row_count(pqt, &mut stats, config)?;
 
-        /// Max number of lines per jsonl
-        #[arg(long, default_value_t=16384)] // 2^14 ~ 16k. Should have nice file sizes
-        max_lines: usize,
-    }, 
+    log_file_summary(pqt, &stats, start_main);
+    config.write_samples()?;
+    Ok(stats)
 }
 
 
-/*=================================================
-=                HELPER METHODS                   =
-=================================================*/
+fn reprocess_jsonl_file(input_file: &Path, local_jsonl_dir: &Path, config: &ProcessConfig) -> Result<ProcessStats, Error> {
+    // Like process_parquet_file, but the rows (with contents) come from a jsonl this tool already made
+    let start_main = Instant::now();
+    if config.options.streaming {
+        return Err(Error::msg("reprocess-jsonl doesn't support --streaming/--low-memory/--stdout"));
+    }
+    if config.options.output_json_schema.is_some() {
+        return Err(Error::msg("reprocess-jsonl has no parquet schema to make --output-json-schema from"));
+    }
+    let (language, pqt_number) = parse_output_file_name(input_file)?;
+    let pqt_number = config.output_shard(&pqt_number)?;
+    let rows = read_jsonl_file(input_file)?;
+    let read_msecs = start_main.elapsed().as_millis();
+    log_info!(json!({"input_file": input_file, "msecs": read_msecs}), "Read jsonl in {:?} msecs", read_msecs);
+    let (rows, original_order) = select_rows(rows, config);
 
+    let row_fn = |row| reprocess_row(row, config);
+    let stats = write_rows((rows, original_order), local_jsonl_dir, language, &pqt_number, &row_fn, None, config)?;
 
-fn build_pbar(num_items: usize, units: &str) -> ProgressBar {
-    let mut template = String::from(units);
-    template.push_str(" {human_pos}/{human_len} [{elapsed_precise}/{duration_precise}] [{wide_bar:.cyan/blue}]");
-    let pbar = ProgressBar::new(num_items as u64)
-        .with_style(
-            ProgressStyle::with_template(&template).unwrap()
-        );
-    pbar.inc(0);
-    pbar
+    log_file_summary(input_file, &stats, start_main);
+    config.write_samples()?;
+    Ok(stats)
 }
 
 
+// --row-error-log reasons worth retrying: the blob itself was the problem, so fixing the blob store can fix the row.
+// Skips (empty, binary, ...) would just be skipped again
+const RETRYABLE_REASONS: [&str; 3] = ["missing", "corrupt_blob", "decode_failed"];
 
-fn extract_pqt_locations(pqt: PathBuf) -> Result<(PathBuf, String, String), Error> {
-    /* Given a parquet file of the form 
-    BASE_DIR/the-stack-v2/raw-hf-parquets/<PROGRAMMING_LANGUAGE>/train-XXXX-of-YYYY.parquet
 
-    outputs the (dir where contents live, language, number XXXX above)
-    */
-    let pl_dir = pqt.parent().unwrap();
-    let raw_hf_dir = pl_dir.parent().unwrap();
-    let stack_dir = raw_hf_dir.parent().unwrap();
+fn retry_failed_rows(parquet_file: &Path, failed_rows: &Path, local_jsonl_dir: &Path, mut config: ProcessConfig) -> Result<(), Error> {
+    // The standard pipeline, restricted (like --blob-ids-file) to the previously failed blob_ids
+    if config.options.streaming {
+        return Err(Error::msg("retry-failed doesn't support --streaming/--low-memory/--stdout"));
+    }
+    if config.blob_ids.is_some() {
+        return Err(Error::msg("retry-failed takes its blob_ids from --failed-rows, so it can't also have --blob-ids-file"));
+    }
+    // It always writes one --single-file output, so the flags that conflict with --single-file can't apply either
+    if config.options.resume {
+        return Err(Error::msg("retry-failed writes a single file, so it doesn't support --resume"));
+    }
+    if config.options.verify_roundtrip {
+        return Err(Error::msg("retry-failed writes a single file, so it doesn't support --verify-roundtrip"));
+    }
+    if config.options.compression_unit != CompressionUnit::Row {
+        return Err(Error::msg("retry-failed writes a single zstd frame, so it doesn't support --compression-unit"));
+    }
+    let blob_ids: HashSet<String> = read_jsonl_file(failed_rows)?.iter()
+        .filter(|entry| entry["reason"].as_str().is_some_and(|reason| RETRYABLE_REASONS.contains(&reason)))
+        .filter_map(|entry| entry["blob_id"].as_str().map(str::to_string))
+        .collect();
+    log_info!(json!({"failed_rows": failed_rows, "blob_ids": blob_ids.len()}), "Retrying {:?} failed blob_ids from {:?}", blob_ids.len(), failed_rows);
+    config.blob_ids = Some(blob_ids);
+    config.options.single_file = true;
+    config.options.target_file_bytes = None;
+    process_parquet_file_recorded(parquet_file, local_jsonl_dir, &config).and_then(|_| config.finish_run())
+}
 
-    let language = pl_dir.file_name().unwrap().to_str().unwrap();
-    let number = pqt.file_name().unwrap().to_str().unwrap().split('-').nth(1).unwrap();
-    let blob_dir = stack_dir.join("data").join(language).join(number);
 
-    Ok((blob_dir, language.to_string(), number.to_string())) 
+fn language_selected(language: &str, languages: &[String], skip_languages: &[String]) -> bool {
+    // --languages (all of them when empty), minus --skip-languages, which wins for a language in both
+    (languages.is_empty() || languages.iter().any(|selected| selected == language)) && !skip_languages.iter().any(|skipped| skipped == language)
 }
 
 
-fn get_output_file_loc(local_jsonl_dir: &PathBuf, language: &String, parquet_num: &String, jsonl_num: usize, total_num: usize) -> PathBuf {
-    let filename = format!("{}-{}-{:06}-of-{:06}.jsonl.zstd", language.as_str(), parquet_num.as_str(), jsonl_num, total_num);
-    local_jsonl_dir.join(filename)
+/// One language's parquets in a process-directory run, for its --per-language-manifest
+#[derive(Default)]
+struct LanguageFiles {
+    processed: usize,
+    failed: usize,
+    parquet_bytes: u64,
 }
 
 
+fn process_directory(input_dir: &Path, local_jsonl_dir: &Path, dir_options: &DirectoryOptions, config: &ProcessConfig) -> Result<(), Error> {
+    let start_main = Instant::now();
+    let mut parquet_files = discover_input_files(input_dir, config.options.input_format)?;
+    let discover_msecs = start_main.elapsed().as_millis();
+    log_info!(json!({"input_dir": input_dir, "num_files": parquet_files.len(), "msecs": discover_msecs}), "Found {:?} parquet files in {:?} msecs", parquet_files.len(), discover_msecs);
 
+    if !dir_options.languages.is_empty() || !dir_options.skip_languages.is_empty() {
+        let num_found = parquet_files.len();
+        parquet_files.retain(|pqt| {
+            let language = match config.pqt_locations(pqt) {
+                Ok((_, language, _)) => language,
+                Err(_) => return true, // leave it to fail (and get reported) like any other bad parquet
+            };
+            language_selected(&language, &dir_options.languages, &dir_options.skip_languages)
+        });
+        log_info!(json!({"kept": parquet_files.len(), "num_files": num_found}), "Kept {:?}/{:?} parquet files after language filters", parquet_files.len(), num_found);
+    }
 
+    if let Some(checkpoint_file) = &dir_options.checkpoint_file {
+        if checkpoint_file.exists() {
+            let completed = read_lines_to_set(checkpoint_file)?;
+            let num_found = parquet_files.len();
+            parquet_files.retain(|pqt| !completed.contains(pqt.to_string_lossy().as_ref()));
+            let num_skipped = num_found - parquet_files.len();
+            log_info!(json!({"checkpoint_file": checkpoint_file, "skipped": num_skipped}), "Skipping {:?} parquet files already in checkpoint file", num_skipped);
+        }
+    }
+    if let Some(max_files) = dir_options.max_files.filter(|max_files| parquet_files.len() as u64 > *max_files) {
+        let num_found = parquet_files.len();
+        parquet_files.truncate(max_files as usize);
+        log_info!(json!({"kept": parquet_files.len(), "num_files": num_found}), "Only processing the first {:?}/{:?} parquet files (--max-files)", parquet_files.len(), num_found);
+    }
 
+    if dir_options.fail_on_schema_change && config.options.input_format != InputFormat::Parquet {
+        return Err(Error::msg("--fail-on-schema-change only applies to --input-format parquet"));
+    }
 
-fn process_row(mut row: JsonValue, blob_loc: &PathBuf) -> Result<JsonValue, Error> {
-    let blob_id = row.get("blob_id").unwrap().as_str().unwrap();
-    let blob_file = blob_loc.join(format!("{}{}", blob_id, ".gz"));
-
-    let blob_contents = read_gzip_file(&blob_file);
-    match blob_contents {
-        Ok(contents) => {
-            let utf_str = decode_to_string(&contents, row["src_encoding"].as_str().unwrap()).unwrap();
-            row["contents"] = JsonValue::String(utf_str);     
-            Ok(row)
-        },
-        Err(e) => {
-            Err(e)
+    let num_files = parquet_files.len();
+    let mut failures: Vec<(PathBuf, Error)> = Vec::new();
+    let mut language_stats: BTreeMap<String, ProcessStats> = BTreeMap::new();
+    let mut parquet_bytes: u64 = 0;
+    let mut language_files: BTreeMap<String, LanguageFiles> = BTreeMap::new(); // --per-language-manifest
+    let mut first_schema: Option<(PathBuf, SchemaRef)> = None;
+    for (file_num, pqt) in parquet_files.iter().enumerate() {
+        log_info!(json!({"parquet": pqt, "file_num": file_num + 1, "num_files": num_files}), "Working on file {:?}/{:?}: {:?}", file_num + 1, num_files, pqt);
+        if dir_options.fail_on_schema_change {
+            // A parquet whose footer can't be read is left to fail (and get reported) like any other bad parquet
+            if let Ok(schema) = read_parquet_schema(pqt) {
+                match &first_schema {
+                    None => first_schema = Some((pqt.clone(), schema)),
+                    Some((first_pqt, first)) => {
+                        let differences = schema_differences(first, &schema);
+                        if !differences.is_empty() {
+                            return Err(FileProcessError::SchemaChanged { filename: pqt.clone(), first_filename: first_pqt.clone(), differences }.into());
+                        }
+                    },
+                }
+            }
+        }
+        match process_parquet_file_recorded(pqt, local_jsonl_dir, config) {
+            Ok(stats) => {
+                if dir_options.dataset_manifest.is_some() || dir_options.per_language_manifest {
+                    let (_, language, _) = config.pqt_locations(pqt)?;
+                    let pqt_bytes = fs::metadata(pqt)?.len();
+                    if dir_options.per_language_manifest {
+                        let files = language_files.entry(language.clone()).or_default();
+                        files.processed += 1;
+                        files.parquet_bytes += pqt_bytes;
+                    }
+                    language_stats.entry(language).or_default().merge(&stats);
+                    parquet_bytes += pqt_bytes;
+                }
+                if let Some(checkpoint_file) = &dir_options.checkpoint_file {
+                    append_line(checkpoint_file, &pqt.to_string_lossy())?;
+                }
+            },
+            Err(e) => {
+                if dir_options.fail_fast || is_broken_pipe(&e) || matches!(e.downcast_ref(), Some(FileProcessError::LowDiskSpace { .. })) {
+                    // (With --stdout, nothing after a hung-up reader can succeed either, nor anything on a full disk)
+                    return Err(e);
+                }
+                log_error!(json!({"parquet": pqt, "error": e.to_string()}), "Failed on {:?}: {}", pqt, e);
+                if let (true, Ok((_, language, _))) = (dir_options.per_language_manifest, config.pqt_locations(pqt)) {
+                    language_files.entry(language).or_default().failed += 1;
+                }
+                failures.push((pqt.clone(), e));
+                if dir_options.max_failed_files.is_some_and(|max_failed_files| failures.len() as u64 >= max_failed_files) {
+                    for (pqt, e) in &failures {
+                        log_error!(json!({"parquet": pqt, "error": e.to_string()}), "Failed file {:?}: {}", pqt, e);
+                    }
+                    return Err(FileProcessError::TooManyFailedFiles { failed_files: failures.len(), attempted_files: file_num + 1, total_files: num_files }.into());
+                }
+            },
         }
     }
 
-
+    let num_succeeded = num_files - failures.len();
+    let secs = start_main.elapsed().as_secs();
+    log_info!(json!({"succeeded": num_succeeded, "num_files": num_files, "secs": secs}), "Processed {:?}/{:?} parquet files in {:?} seconds", num_succeeded, num_files, secs);
+    if let Some(manifest_path) = &dir_options.dataset_manifest {
+        let mut totals = ProcessStats::default();
+        for stats in language_stats.values() {
+            totals.merge(stats);
+        }
+        let languages: serde_json::Map<String, JsonValue> = language_stats.iter()
+            .map(|(language, stats)| (language.clone(), stats.to_json()))
+            .collect();
+        let manifest = json!({
+            "manifest_version": DATASET_MANIFEST_VERSION,
+            "packager_version": env!("CARGO_PKG_VERSION"),
+            "argv": std::env::args().collect::<Vec<String>>(),
+            "input_dir": input_dir,
+            "output_dir": local_jsonl_dir,
+            "files_processed": num_succeeded,
+            "files_failed": failures.len(),
+            "parquet_bytes": parquet_bytes,
+            "zstd_dict_id": config.codec.dict_id(),
+            "secs": secs,
+            "totals": totals.to_json(),
+            "languages": languages,
+        });
+        write_json_atomic(manifest_path, &manifest, config.options.fsync)?;
+    }
+    for (language, files) in &language_files {
+        let stats = language_stats.get(language).cloned().unwrap_or_default();
+        let manifest = json!({
+            "manifest_version": LANGUAGE_MANIFEST_VERSION,
+            "packager_version": env!("CARGO_PKG_VERSION"),
+            "argv": std::env::args().collect::<Vec<String>>(),
+            "language": language,
+            "input_dir": input_dir,
+            "output_dir": local_jsonl_dir,
+            "files_processed": files.processed,
+            "files_failed": files.failed,
+            "parquet_bytes": files.parquet_bytes,
+            "zstd_dict_id": config.codec.dict_id(),
+            "stats": stats.to_json(),
+        });
+        let manifest_loc = local_jsonl_dir.join(format!("{}.manifest.json", config.output_language(language)));
+        write_json_atomic(&manifest_loc, &manifest, config.options.fsync)?;
+        log_info!(json!({"language": language, "manifest": manifest_loc}), "Wrote {} manifest to {:?}", language, manifest_loc);
+    }
+    if !failures.is_empty() {
+        for (pqt, e) in &failures {
+            log_error!(json!({"parquet": pqt, "error": e.to_string()}), "Failed file {:?}: {}", pqt, e);
+        }
+        return Err(FileProcessError::BatchFailed { failed_files: failures.len(), total_files: num_files }.into());
+    }
+    Ok(())
 }
 
 
 
+
 /*=============================================
-=                 COLLECT METHOD              =
+=              MAINTENANCE COMMANDS           =
 =============================================*/
 
+fn print_schema(parquet_file: &Path, print_schema_json: bool) -> Result<(), Error> {
+    let schema = read_parquet_schema(parquet_file)?;
+    if print_schema_json {
+        let fields: Vec<JsonValue> = schema.fields().iter()
+            .map(|field| json!({
+                "name": field.name(),
+                "data_type": field.data_type().to_string(),
+                "nullable": field.is_nullable(),
+            }))
+            .collect();
+        println!("{}", json!({"fields": fields}));
+    } else {
+        println!("Schema of {:?}", parquet_file);
+        for field in schema.fields() {
+            let nullable = if field.is_nullable() { " (nullable)" } else { "" };
+            println!("  {}: {}{}", field.name(), field.data_type(), nullable);
+        }
+    }
+    Ok(())
+}
 
-fn process_parquet_file(pqt: &PathBuf, local_jsonl_dir: &PathBuf, max_lines: usize) -> Result<(), Error> {
-    // Step 1: load parquet file into vec of rows 
-    let start_main = Instant::now();    
-    let (blob_loc, language, pqt_number) = extract_pqt_locations(pqt.clone()).unwrap();
-    let rows: Vec<JsonValue> = load_parquet_as_json_parallel(pqt.clone()).unwrap();
-    println!("Read pqt in {:?} msecs", start_main.elapsed().as_millis());
-    // Step 2: loop over chunks of rows 
-    let mut chunk_num = 0; 
 
+fn print_head(parquet_file: &Path, num_rows: usize, with_contents: bool, config: &ProcessConfig) -> Result<(), Error> {
+    // Only decodes as many record batches as it takes to get num_rows rows
+    let mut reader = ParquetChunkReader::new(parquet_file.to_path_buf(), num_rows.max(1), &config.options.read_options())?;
+    let rows = match reader.next() {
+        Some(rows) => rows?.into_iter().take(num_rows).collect(),
+        None => Vec::new(),
+    };
+    let blobs = match with_contents {
+        true => Some(BlobStore::open(config.pqt_locations(parquet_file)?.0, config)?),
+        false => None,
+    };
+    for row in rows {
+        let row = match &blobs {
+            Some(blobs) => match process_row(row.clone(), blobs, config) {
+                Ok(RowOutcome::Keep(row)) => row,
+                Ok(RowOutcome::Skip(reason)) => {
+                    log_info!(json!({"blob_id": row["blob_id"], "reason": reason.as_str()}), "Row would be skipped ({})", reason.as_str());
+                    row
+                },
+                Ok(RowOutcome::DecodeFailed(reason)) => {
+                    log_warn!(json!({"blob_id": row["blob_id"], "error": reason}), "Row would fail to decode: {}", reason);
+                    row
+                },
+                Ok(RowOutcome::Stream(streamed_row, blob)) => {
+                    log_info!(json!({"blob_id": row["blob_id"], "blob_file": blob.blob_file}), "Row's blob is over --stream-blobs-above-bytes, so its contents are left out here");
+                    streamed_row
+                },
+                Ok(RowOutcome::Serialized { .. }) => unreachable!("only write_chunks serializes streamed rows"),
+                Err(e) => {
+                    log_warn!(json!({"blob_id": row["blob_id"], "error": e.to_string()}), "Couldn't fetch contents: {}", e);
+                    row
+                },
+            },
+            None => row,
+        };
+        println!("{}", serde_json::to_string_pretty(&row)?);
+    }
+    Ok(())
+}
 
-    let num_chunks = rows.len().div_ceil(max_lines);
-    let pbar = build_pbar(num_chunks, "Chunks");
-    for chunk in rows.chunks(max_lines) {
-        // and process each row of the chunk (in parallel!)
-        let start_chunk = Instant::now();
-        let chunk_size = chunk.len();
-        let failed_rows = AtomicUsize::new(0);
-        let processed_chunks: Vec<u8> = chunk.into_par_iter()
-            .map(|v| {
-                let proc_output = process_row(v.clone(), &blob_loc);
-                match proc_output {
-                    Ok(value) => {
-                        // File was read correctly
-                        let mut output_str = value.to_string();
-                        output_str.push('\n');
-                        let bytes = output_str.as_bytes();
-                        let out = encode_all(bytes, DEFAULT_COMPRESSION_LEVEL).unwrap();
-                        out
-                    }
-                    Err(e) if matches!(e.downcast_ref(), Some(FileProcessError::FileNotFound { ..})) => {
-                        // File missing, increment counter and proceed
-                        failed_rows.fetch_add(1, Ordering::SeqCst);
-                        let out: Vec<u8> = Vec::new();
-                        out
-                    },
-                    Err(e) => {
-                        panic!("Unexpected error {:?}", e);
-                    }, 
+
+fn train_zstd_dict(parquet_file: &Path, dict_out: &Path, num_samples: usize, max_dict_bytes: usize, config: &ProcessConfig) -> Result<(), Error> {
+    let start_main = Instant::now();
+    let rows = load_parquet_as_json_parallel(parquet_file.to_path_buf(), &config.options.read_options())?;
+    let step = rows.len().div_ceil(num_samples.max(1)).max(1);
+    let sampled: Vec<JsonValue> = rows.into_iter().step_by(step).collect();
+    let blobs = BlobStore::open(config.pqt_locations(parquet_file)?.0, config)?;
+
+    // Rows that would be left out of the outputs (missing, skipped) are left out of the samples too
+    let samples: Vec<Vec<u8>> = sampled.into_par_iter()
+        .filter_map(|row| match process_row(row, &blobs, config) {
+            Ok(RowOutcome::Keep(row)) => config.options.frame_record(config.options.row_json(&row)).ok(),
+            _ => None,
+        })
+        .collect();
+    let dict = train_dict(&samples, max_dict_bytes)?;
+    let dict_bytes = dict.len();
+    write_bytes(dict, dict_out.to_path_buf(), false)?;
+
+    let dict_id = Codec::load(Some(dict_out), false, 0)?.dict_id();
+    let secs = start_main.elapsed().as_secs();
+    log_info!(json!({"dict_out": dict_out, "num_samples": samples.len(), "dict_bytes": dict_bytes, "dict_id": dict_id, "secs": secs}),
+              "Trained a {:?} byte zstd dictionary (id {:?}) on {:?} rows in {:?} seconds", dict_bytes, dict_id, samples.len(), secs);
+    Ok(())
+}
+
+
+/// Running decode totals for one src_encoding (bench-decode)
+#[derive(Default)]
+struct DecodeBench {
+    decoded_with: BTreeSet<&'static str>,
+    rows: usize,
+    failed: usize,
+    bytes: usize,
+    elapsed: Duration,
+}
+
+
+fn bench_decode(parquet_file: &Path, max_rows: Option<usize>, config: &ProcessConfig) -> Result<(), Error> {
+    let rows = load_parquet_as_json_parallel(parquet_file.to_path_buf(), &config.options.read_options())?;
+    let step = match max_rows {
+        Some(max_rows) => rows.len().div_ceil(max_rows.max(1)).max(1),
+        None => 1,
+    };
+    let blobs = BlobStore::open(config.pqt_locations(parquet_file)?.0, config)?;
+    let pbar = build_pbar(rows.len().div_ceil(step), "Rows");
+    let mut benches: BTreeMap<String, DecodeBench> = BTreeMap::new();
+    let mut num_unreadable = 0;
+    for row in rows.iter().step_by(step) {
+        pbar.inc(1);
+        let Some(contents) = row["blob_id"].as_str().and_then(|blob_id| blobs.read(blob_id, config).ok()) else {
+            num_unreadable += 1;
+            continue;
+        };
+        let encoding = config.row_encoding(row["src_encoding"].as_str());
+        let start_decode = Instant::now();
+        let decoded = decode_to_string(&contents, encoding, config.options.is_lossy(encoding));
+        let elapsed = start_decode.elapsed();
+        let bench = benches.entry(encoding.to_string()).or_default();
+        bench.rows += 1;
+        bench.bytes += contents.len();
+        bench.elapsed += elapsed;
+        match decoded {
+            Ok((_, decoded_with)) => { bench.decoded_with.insert(decoded_with); },
+            Err(_) => bench.failed += 1,
+        }
+    }
+    pbar.finish_and_clear();
+
+    // Slowest (by throughput) first, since that's what the benchmark is for
+    let mut benches: Vec<(String, DecodeBench)> = benches.into_iter().collect();
+    let mb_per_sec = |bench: &DecodeBench| bench.bytes as f64 / 1e6 / bench.elapsed.as_secs_f64().max(1e-9);
+    benches.sort_by(|(_, a), (_, b)| mb_per_sec(a).total_cmp(&mb_per_sec(b)));
+    println!("{:<16} {:<16} {:>9} {:>7} {:>11} {:>10} {:>10}", "src_encoding", "decoded_with", "rows", "failed", "MB", "secs", "MB/sec");
+    for (encoding, bench) in &benches {
+        let decoded_with = bench.decoded_with.iter().copied().collect::<Vec<_>>().join(",");
+        println!("{:<16} {:<16} {:>9} {:>7} {:>11.3} {:>10.4} {:>10.1}", encoding, decoded_with, bench.rows, bench.failed,
+                 bench.bytes as f64 / 1e6, bench.elapsed.as_secs_f64(), mb_per_sec(bench));
+    }
+    if num_unreadable > 0 {
+        log_warn!(json!({"unreadable": num_unreadable}), "Left out {:?} rows whose blobs couldn't be read", num_unreadable);
+    }
+    Ok(())
+}
+
+
+fn survey_encodings(input_dir: &Path, languages: &[String], skip_languages: &[String]) -> Result<(), Error> {
+    let start_main = Instant::now();
+    let mut parquet_files = discover_parquet_files(input_dir)?;
+    parquet_files.retain(|pqt| {
+        let language = match extract_pqt_locations(pqt.clone()) {
+            Ok((_, language, _)) => language,
+            Err(_) => return true,
+        };
+        language_selected(&language, languages, skip_languages)
+    });
+
+    let pbar = build_pbar(parquet_files.len(), "Files");
+    let results: Vec<(PathBuf, Result<_, Error>)> = on_reader_pool(|| parquet_files.par_iter().map(|pqt| {
+        let result = count_parquet_column_values(pqt, "src_encoding");
+        pbar.inc(1);
+        (pqt.clone(), result)
+    }).collect());
+    pbar.finish_and_clear();
+
+    let mut counts: HashMap<Option<String>, usize> = HashMap::new();
+    let mut num_failed = 0;
+    for (pqt, result) in results {
+        match result {
+            Ok(file_counts) => file_counts.into_iter().for_each(|(value, count)| *counts.entry(value).or_insert(0) += count),
+            Err(e) => {
+                num_failed += 1;
+                log_warn!(json!({"input_file": pqt, "error": format!("{:?}", e)}), "Couldn't survey {:?}: {:?}", pqt, e);
+            },
+        }
+    }
+
+    // Most common first, ties by name so the table is the same every run
+    let num_rows: usize = counts.values().sum();
+    let mut counts: Vec<(Option<String>, usize)> = counts.into_iter().collect();
+    counts.sort_by(|(a_value, a_count), (b_value, b_count)| b_count.cmp(a_count).then_with(|| a_value.cmp(b_value)));
+    println!("{:<24} {:>12} {:>8} {:>10}", "src_encoding", "rows", "percent", "supported");
+    for (value, count) in &counts {
+        // Supported if decoding an empty blob with it works, the same way rows get decoded (nulls and null
+        // placeholders fall back to the default encoding, so they always are)
+        let supported = decode_to_string(b"", encoding_label(value.as_deref()).unwrap_or("UTF-8"), false).is_ok();
+        println!("{:<24} {:>12} {:>7.3}% {:>10}", value.as_deref().unwrap_or("(null)"), count,
+                 100.0 * *count as f64 / num_rows.max(1) as f64, if supported { "yes" } else { "NO" });
+    }
+    let total_secs = start_main.elapsed().as_secs_f64();
+    log_info!(json!({"input_dir": input_dir, "num_files": parquet_files.len(), "failed": num_failed, "rows": num_rows, "distinct": counts.len(), "secs": total_secs}),
+              "Surveyed {:?} rows ({:?} distinct values) in {:?} parquet files in {:.2} secs", num_rows, counts.len(), parquet_files.len() - num_failed, total_secs);
+    match num_failed {
+        0 => Ok(()),
+        _ => Err(Error::msg(format!("Couldn't survey {} of {} parquet files", num_failed, parquet_files.len()))),
+    }
+}
+
+
+fn validate_outputs(dir: &Path, error_on_empty: bool, zstd_dict: Option<&Path>, record_framing: RecordFraming) -> Result<(), Error> {
+    let start_main = Instant::now();
+    let codec = Codec::load(zstd_dict, false, 0)?; // only decompresses, and decompressors always check frame checksums
+    let gzip_codec = Codec::gzip(); // --gzip-per-record outputs
+    let mut outputs = discover_files(dir, "zstd")?;
+    outputs.extend(discover_files(dir, "gz")?);
+    outputs.retain(|output| output.to_string_lossy().ends_with(".jsonl.zstd") || output.to_string_lossy().ends_with(".jsonl.gz"));
+
+    let pbar = build_pbar(outputs.len(), "Outputs");
+    let results: Vec<(PathBuf, Result<usize, Error>)> = outputs.par_iter().map(|output| {
+        let result = (|| -> Result<usize, Error> {
+            if fs::metadata(output)?.len() == 0 {
+                return Ok(0);
+            }
+            let output_codec = if output.to_string_lossy().ends_with(".gz") { &gzip_codec } else { &codec };
+            let reader = BufReader::new(output_codec.stream_decoder(File::open(output)?)?);
+            let mut num_lines = 0;
+            record_framing.read_records(reader, |record| {
+                num_lines += 1;
+                let row: JsonValue = serde_json::from_slice(record)?;
+                if !row.is_object() {
+                    return Err(Error::msg(format!("Line {} isn't a JSON object", num_lines)));
                 }
-            }).flatten()
-            .collect();
+                Ok(())
+            })
+        })();
+        pbar.inc(1);
+        (output.clone(), result)
+    }).collect();
 
-        let failed_rows = failed_rows.into_inner();
-        if failed_rows > 0 {
-            println!("Had {:?}/{:?} missing rows", failed_rows, chunk_size);
+    let empty: Vec<&PathBuf> = results.iter()
+        .filter(|(output, _)| fs::metadata(output).is_ok_and(|metadata| metadata.len() == 0))
+        .map(|(output, _)| output)
+        .collect();
+    for output in &empty {
+        log_warn!(json!({"output": output}), "Empty output {:?}", output);
+    }
+    let mut num_bad = 0;
+    let mut num_lines = 0;
+    for (output, result) in &results {
+        match result {
+            Ok(lines) => num_lines += lines,
+            Err(e) => {
+                log_error!(json!({"output": output, "error": e.to_string()}), "Invalid output {:?}: {}", output, e);
+                num_bad += 1;
+            },
         }
-        if (failed_rows as f64 / chunk_size as f64) > MISSING_THRESHOLD {
-            return Err(FileProcessError::TooManyMissing { missing_count: failed_rows, total_files: chunk_size }.into());
+    }
+    if error_on_empty {
+        num_bad += empty.len();
+    }
+
+    let secs = start_main.elapsed().as_secs();
+    log_info!(json!({"dir": dir, "num_files": outputs.len(), "lines": num_lines, "empty": empty.len(), "bad": num_bad, "secs": secs}),
+              "Validated {:?} outputs ({:?} lines, {:?} empty) in {:?} seconds, {:?} bad", outputs.len(), num_lines, empty.len(), secs, num_bad);
+    if num_bad > 0 {
+        return Err(Error::msg(format!("{}/{} outputs are invalid", num_bad, outputs.len())));
+    }
+    Ok(())
+}
+
 
+fn verify_checksums(dir: &Path) -> Result<(), Error> {
+    let start_main = Instant::now();
+    let mut sidecars = discover_files(dir, ChecksumAlgorithm::Sha256.extension())?;
+    sidecars.extend(discover_files(dir, ChecksumAlgorithm::Crc32.extension())?);
+
+    let pbar = build_pbar(sidecars.len(), "Checksums");
+    let num_bad = sidecars.par_iter().map(|sidecar| -> Result<usize, Error> {
+        let algorithm = ChecksumAlgorithm::from_extension(&sidecar.extension().unwrap().to_string_lossy()).unwrap();
+        let contents = fs::read_to_string(sidecar)?;
+        let (expected, file_name) = contents.trim_end().split_once("  ")
+            .ok_or_else(|| Error::msg(format!("Malformed checksum sidecar {:?}", sidecar)))?;
+        let file = sidecar.with_file_name(file_name);
+        pbar.inc(1);
+        match algorithm.checksum_file(&file) {
+            Ok(actual) if actual == expected => Ok(0),
+            Ok(actual) => {
+                log_error!(json!({"file": file, "expected": expected, "actual": actual}), "Checksum mismatch for {:?}: expected {}, got {}", file, expected, actual);
+                Ok(1)
+            },
+            Err(e) => {
+                log_error!(json!({"file": file, "error": e.to_string()}), "Couldn't checksum {:?}: {}", file, e);
+                Ok(1)
+            },
         }
+    }).sum::<Result<usize, Error>>()?;
+
+    let secs = start_main.elapsed().as_secs();
+    log_info!(json!({"dir": dir, "num_files": sidecars.len(), "bad": num_bad, "secs": secs}), "Checked {:?} checksums in {:?} seconds, {:?} bad", sidecars.len(), secs, num_bad);
+    if num_bad > 0 {
+        return Err(Error::msg(format!("{}/{} checksums didn't match", num_bad, sidecars.len())));
+    }
+    Ok(())
+}
+
+
+/// What validate-blob-store found when it decompressed one blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlobHealth {
+    Ok,
+    Empty,
+    Uncompressed,
+    Corrupt,
+}
 
-        println!("Processed cuhnk in {:?} msecs", start_chunk.elapsed().as_millis());
-        let output_file_loc = get_output_file_loc(local_jsonl_dir, &language, &pqt_number, chunk_num, num_chunks);
-        let start_save = Instant::now();
 
-        write_bytes(processed_chunks, output_file_loc).unwrap();
-        println!("Saved chunk in {:?} msecs", start_save.elapsed().as_millis());
-        chunk_num += 1;
+fn check_blob(blob: &Path) -> (BlobHealth, Option<String>) {
+    // The blob's health, and for a corrupt one, why
+    let bytes = match fs::read(blob) {
+        Ok(bytes) => bytes,
+        Err(e) => return (BlobHealth::Corrupt, Some(e.to_string())),
+    };
+    let is_compressed = is_compressed_blob(&bytes);
+    match decompress_blob(bytes, blob) {
+        Err(e) => match e.downcast_ref() {
+            Some(FileProcessError::CorruptBlob { reason, .. }) => (BlobHealth::Corrupt, Some(reason.clone())),
+            _ => (BlobHealth::Corrupt, Some(e.to_string())),
+        },
+        Ok(contents) if contents.is_empty() => (BlobHealth::Empty, None),
+        Ok(_) if !is_compressed => (BlobHealth::Uncompressed, None),
+        Ok(_) => (BlobHealth::Ok, None),
+    }
+}
+
+
+fn validate_blob_store(blob_dir: &Path, sample_fraction: f64, seed: u64, max_examples: usize) -> Result<(), Error> {
+    let start_main = Instant::now();
+    let mut blobs = discover_files(blob_dir, "gz")?;
+    let num_gz = blobs.len();
+    blobs.extend(discover_files(blob_dir, "zst")?);
+    let num_zst = blobs.len() - num_gz;
+    blobs.retain(|blob| in_hash_sample(&blob.to_string_lossy(), seed, sample_fraction));
+    log_info!(json!({"blob_dir": blob_dir, "gz": num_gz, "zst": num_zst, "sampled": blobs.len()}),
+              "Found {:?} gz and {:?} zst blobs, checking {:?} of them", num_gz, num_zst, blobs.len());
+
+    let pbar = build_pbar(blobs.len(), "Blobs");
+    let results: Vec<(BlobHealth, Option<String>)> = blobs.par_iter().map(|blob| {
+        let result = check_blob(blob);
         pbar.inc(1);
+        result
+    }).collect();
+    pbar.finish_and_clear();
+
+    let mut summary = json!({"blob_dir": blob_dir, "gz": num_gz, "zst": num_zst, "sampled": blobs.len()});
+    for (health, name, label) in [(BlobHealth::Corrupt, "corrupt", "Corrupt"), (BlobHealth::Empty, "empty", "Empty"), (BlobHealth::Uncompressed, "uncompressed", "Uncompressed")] {
+        let bad: Vec<(&PathBuf, &Option<String>)> = blobs.iter().zip(&results)
+            .filter(|(_, (blob_health, _))| *blob_health == health)
+            .map(|(blob, (_, reason))| (blob, reason))
+            .collect();
+        for (blob, reason) in bad.iter().take(max_examples) {
+            match reason {
+                Some(reason) => log_warn!(json!({"blob": blob, "health": name, "reason": reason}), "{} blob {:?}: {}", label, blob, reason),
+                None => log_warn!(json!({"blob": blob, "health": name}), "{} blob {:?}", label, blob),
+            }
+        }
+        summary[name] = json!(bad.len());
+        summary[format!("{}_examples", name)] = json!(bad.iter().take(max_examples).map(|(blob, _)| blob).collect::<Vec<_>>());
+    }
+    let num_corrupt = summary["corrupt"].as_u64().unwrap();
+    let secs = start_main.elapsed().as_secs_f64();
+    summary["secs"] = json!(secs);
+    log_info!(summary, "Checked {:?} blobs in {:.2} secs: {} corrupt, {} empty, {} uncompressed",
+              blobs.len(), secs, summary["corrupt"], summary["empty"], summary["uncompressed"]);
+    if num_corrupt > 0 {
+        return Err(Error::msg(format!("{}/{} sampled blobs are corrupt", num_corrupt, blobs.len())));
+    }
+    Ok(())
+}
+
+
+fn link_hf_cache(repo_id: &str, revision: &str, hf_cache: Option<&Path>, output_dir: &Path) -> Result<(), Error> {
+    // Parquets are found anywhere in the snapshot; their parent directory names the language (data/<LANGUAGE>/*.parquet on the hub)
+    let hf_cache = match hf_cache {
+        Some(hf_cache) => hf_cache.to_path_buf(),
+        None => default_hf_cache().ok_or_else(|| Error::msg("Couldn't find a HuggingFace cache, pass --hf-cache"))?,
+    };
+    let snapshot = resolve_hf_snapshot(&hf_cache, repo_id, revision)?;
+    let parquet_files = discover_parquet_files(&snapshot)?;
+    let raw_hf_dir = output_dir.join("the-stack-v2").join("raw-hf-parquets");
+    for pqt in &parquet_files {
+        let language = pqt.parent().and_then(|dir| dir.file_name()).unwrap();
+        let link = raw_hf_dir.join(language).join(pqt.file_name().unwrap());
+        fs::create_dir_all(link.parent().unwrap())?;
+        if fs::symlink_metadata(&link).is_ok() {
+            fs::remove_file(&link)?;
+        }
+        // Snapshot files are themselves symlinks into the cache's blobs/, so link straight to the real file
+        std::os::unix::fs::symlink(fs::canonicalize(pqt)?, &link)?;
     }
+    log_info!(json!({"repo_id": repo_id, "snapshot": snapshot, "output_dir": raw_hf_dir, "num_files": parquet_files.len()}),
+              "Linked {:?} parquet files from {:?} into {:?}", parquet_files.len(), snapshot, raw_hf_dir);
+    Ok(())
+}
+
+
+fn recompress_blobs(blob_dir: &Path, level: i32, delete_original: bool) -> Result<(), Error> {
+    let start_main = Instant::now();
+    let gz_files = discover_files(blob_dir, "gz")?;
+    let discover_msecs = start_main.elapsed().as_millis();
+    log_info!(json!({"blob_dir": blob_dir, "num_files": gz_files.len(), "msecs": discover_msecs}), "Found {:?} gz blobs in {:?} msecs", gz_files.len(), discover_msecs);
+
+    let pbar = build_pbar(gz_files.len(), "Blobs");
+    gz_files.par_iter().try_for_each(|gz_file| -> Result<(), Error> {
+        let contents = read_blob_file(gz_file)?;
+        let recompressed = encode_all(contents.as_slice(), level)?;
+        // Durable, and read back before the .gz can go, which with --delete-original leaves the .zst the only copy
+        let zst_file = gz_file.with_extension("zst");
+        write_bytes_atomic_verified(recompressed, &zst_file, None, true, |tmp_path| match read_blob_file(&tmp_path.to_path_buf()) {
+            Ok(decoded) if decoded == contents => Ok(()),
+            Ok(decoded) => Err(Error::msg(format!("Recompressed {:?} decodes to {} bytes, not the original's {}", zst_file, decoded.len(), contents.len()))),
+            Err(e) => Err(Error::msg(format!("Recompressed {:?} doesn't decode: {}", zst_file, e))),
+        })?;
+        if delete_original {
+            std::fs::remove_file(gz_file)?;
+        }
+        pbar.inc(1);
+        Ok(())
+    })?;
 
-    println!("Made {:?} jsonl.gz's in {:?} seconds", num_chunks, start_main.elapsed().as_secs());
+    let secs = start_main.elapsed().as_secs();
+    log_info!(json!({"blob_dir": blob_dir, "num_files": gz_files.len(), "secs": secs}), "Recompressed {:?} blobs in {:?} seconds", gz_files.len(), secs);
     Ok(())
 }
 
@@ -196,19 +3715,340 @@ fn process_parquet_file(pqt: &PathBuf, local_jsonl_dir: &PathBuf, max_lines: usi
 =                 MAIN                    =
 =========================================*/
 
+fn cgroup_cpu_limit() -> Option<usize> {
+    /* CPUs allowed by the cgroup v2 quota of our cgroup or any ancestor (tightest wins), rounded up.
+    cpu.max is "<quota> <period>" in usecs, or "max <period>" for no limit
+    */
+    let cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let cgroup_path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let mut dir = Path::new("/sys/fs/cgroup").join(cgroup_path.trim_start_matches('/'));
+    let mut limit: Option<usize> = None;
+    loop {
+        if let Ok(cpu_max) = fs::read_to_string(dir.join("cpu.max")) {
+            let mut fields = cpu_max.split_whitespace();
+            if let (Some(Ok(quota)), Some(Ok(period))) = (fields.next().map(str::parse::<u64>), fields.next().map(str::parse::<u64>)) {
+                if period > 0 {
+                    let cpus = quota.div_ceil(period).max(1) as usize;
+                    limit = Some(limit.map_or(cpus, |limit| limit.min(cpus)));
+                }
+            }
+        }
+        if dir == Path::new("/sys/fs/cgroup") || !dir.pop() {
+            break;
+        }
+    }
+    limit
+}
+
+
+fn auto_thread_count() -> usize {
+    let num_cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    match cgroup_cpu_limit() {
+        Some(limit) if limit < num_cpus => {
+            log_info!(json!({"threads": limit, "num_cpus": num_cpus}), "Using {:?} threads (cgroup CPU quota) out of {:?} CPUs", limit, num_cpus);
+            limit
+        },
+        _ => num_cpus,
+    }
+}
+
+
+fn error_json(e: &Error, command: &Commands) -> JsonValue {
+    // --error-format json: the FileProcessError's code and fields if it is one, plus what every error has
+    let mut error = match (e.downcast_ref::<FileProcessError>(), e.downcast_ref::<std::io::Error>()) {
+        (Some(e), _) => e.to_json(),
+        (None, Some(e)) => json!({"code": "io_error", "kind": format!("{:?}", e.kind())}),
+        (None, None) => json!({"code": "error"}),
+    };
+    error["message"] = json!(e.to_string());
+    error["causes"] = json!(e.chain().skip(1).map(|cause| cause.to_string()).collect::<Vec<_>>());
+    error["input"] = match command {
+        Commands::ProcessParquet {parquet_file, ..} | Commands::RetryFailed {parquet_file, ..} | Commands::Schema {parquet_file, ..}
+        | Commands::Head {parquet_file, ..} | Commands::TrainDict {parquet_file, ..} | Commands::BenchDecode {parquet_file, ..} => json!(parquet_file),
+        Commands::ProcessDirectory {input_dir, ..} | Commands::SurveyEncodings {input_dir, ..} => json!(input_dir),
+        Commands::ReprocessJsonl {input_file, ..} => json!(input_file),
+        Commands::Validate {dir, ..} | Commands::VerifyChecksums {dir} => json!(dir),
+        Commands::LinkHfCache {repo_id, ..} => json!(repo_id),
+        Commands::Recompress {blob_dir, ..} | Commands::ValidateBlobStore {blob_dir, ..} => json!(blob_dir),
+    };
+    error
+}
+
+
+fn is_broken_pipe(e: &Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+}
+
+
 fn main() {
     let args = ArgParser::parse();
-    let threads = args.threads;
-    if threads != 0 {
-        std::env::set_var("RAYON_NUM_THREADS", threads.to_string());
+    let rows_to_stdout = matches!(&args.command,
+        Commands::ProcessParquet {options, ..} | Commands::ProcessDirectory {options, ..} | Commands::ReprocessJsonl {options, ..} if options.stdout);
+    init_logging(args.log_format, rows_to_stdout);
+    NO_PROGRESS.set(args.no_progress).unwrap();
+    PROGRESS_REFRESH_HZ.set(args.progress_refresh_hz).unwrap();
+    let threads = match args.threads {
+        0 => auto_thread_count(),
+        threads => threads,
+    };
+    std::env::set_var("RAYON_NUM_THREADS", threads.to_string());
+    if let Some(reader_threads) = args.reader_threads {
+        init_reader_pool(reader_threads as usize).unwrap();
     }
     let result = match &args.command {
-        Commands::ProcessParquet {parquet_file, local_jsonl_dir, max_lines} => {
-            process_parquet_file(parquet_file, local_jsonl_dir, *max_lines, )
+        Commands::ProcessParquet {parquet_file, local_jsonl_dir, options} => {
+            ProcessConfig::new(options, local_jsonl_dir).and_then(|config| process_parquet_file_recorded(parquet_file, local_jsonl_dir, &config).and_then(|_| config.finish_run()))
+        },
+        Commands::ProcessDirectory {input_dir, local_jsonl_dir, dir_options, options} => {
+            ProcessConfig::new(options, local_jsonl_dir).and_then(|config| {
+                let result = process_directory(input_dir, local_jsonl_dir, dir_options, &config);
+                if result.is_err() {
+                    // The files that were written keep their keys, so a --checkpoint-file retry dedups against them
+                    if let Err(e) = config.save_dedup_state() {
+                        log_error!(json!({"error": e.to_string()}), "Couldn't save --dedup-state: {}", e);
+                    }
+                }
+                result.and_then(|_| config.finish_run())
+            })
+        },
+        Commands::ReprocessJsonl {input_file, local_jsonl_dir, options} => {
+            ProcessConfig::new(options, local_jsonl_dir).and_then(|config| {
+                let result = reprocess_jsonl_file(input_file, local_jsonl_dir, &config);
+                config.settle_dedup(result.is_ok());
+                result.and_then(|_| config.finish_run())
+            })
+        },
+        Commands::RetryFailed {parquet_file, failed_rows, local_jsonl_dir, options} => {
+            ProcessConfig::new(options, local_jsonl_dir).and_then(|config| retry_failed_rows(parquet_file, failed_rows, local_jsonl_dir, config))
+        },
+        Commands::Schema {parquet_file, print_schema_json} => {
+            print_schema(parquet_file, *print_schema_json)
+        },
+        Commands::Head {parquet_file, num_rows, with_contents, options} => {
+            ProcessConfig::new(options, Path::new(".")).and_then(|config| print_head(parquet_file, *num_rows, *with_contents, &config))
+        },
+        Commands::TrainDict {parquet_file, dict_out, num_samples, max_dict_bytes, options} => {
+            ProcessConfig::new(options, Path::new(".")).and_then(|config| train_zstd_dict(parquet_file, dict_out, *num_samples, *max_dict_bytes, &config))
+        },
+        Commands::BenchDecode {parquet_file, max_rows, options} => {
+            ProcessConfig::new(options, Path::new(".")).and_then(|config| bench_decode(parquet_file, *max_rows, &config))
+        },
+        Commands::SurveyEncodings {input_dir, languages, skip_languages} => {
+            survey_encodings(input_dir, languages, skip_languages)
+        },
+        Commands::Validate {dir, error_on_empty, zstd_dict, record_framing} => {
+            validate_outputs(dir, *error_on_empty, zstd_dict.as_deref(), *record_framing)
+        },
+        Commands::VerifyChecksums {dir} => {
+            verify_checksums(dir)
+        },
+        Commands::LinkHfCache {repo_id, revision, hf_cache, output_dir} => {
+            link_hf_cache(repo_id, revision, hf_cache.as_deref(), output_dir)
+        },
+        Commands::Recompress {blob_dir, level, delete_original} => {
+            recompress_blobs(blob_dir, *level, *delete_original)
+        },
+        Commands::ValidateBlobStore {blob_dir, sample_fraction, seed, max_examples} => {
+            validate_blob_store(blob_dir, *sample_fraction, *seed, *max_examples)
         },
     };
+    if let Err(e) = &result {
+        if rows_to_stdout && is_broken_pipe(e) {
+            // The consumer hung up (e.g. `| head`), which isn't our failure
+            log_warn!(json!({}), "Stopped early: stdout was closed by its reader");
+            return;
+        }
+        if args.error_format == ErrorFormat::Json {
+            eprintln!("{}", error_json(e, &args.command));
+            std::process::exit(1);
+        }
+    }
     result.unwrap();
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_options(args: &[&str]) -> ProcessOptions {
+        // ProcessOptions as process-parquet would parse them from args
+        let argv = ["rust", "process-parquet", "--parquet-file", "test.parquet", "--local-jsonl-dir", "out"].into_iter().chain(args.iter().copied());
+        match ArgParser::parse_from(argv).command {
+            Commands::ProcessParquet { options, .. } => options,
+            _ => unreachable!(),
+        }
+    }
+
+    fn test_config(args: &[&str]) -> ProcessConfig {
+        ProcessConfig::new(&test_options(args), Path::new("out")).unwrap()
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        // A fresh, empty directory of this test's own
+        let dir = std::env::temp_dir().join(format!("packager-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read_output_rows(dir: &Path) -> Vec<JsonValue> {
+        // Every row of every (zstd) output file in dir, in file name order
+        let mut files: Vec<PathBuf> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.sort();
+        files.iter()
+            .flat_map(|file| String::from_utf8(zstd::decode_all(fs::read(file).unwrap().as_slice()).unwrap()).unwrap()
+                .lines().map(|line| serde_json::from_str(line).unwrap()).collect::<Vec<JsonValue>>())
+            .collect()
+    }
+
+    #[test]
+    fn contents_as_lines_round_trips() {
+        let contents = "first\r\nsecond\nlast\n";
+        let lines = contents_to_json(contents.to_string(), &test_options(&["--contents-as-lines"]));
+        // Only the '\n' is stripped, and a trailing newline doesn't make an empty last line
+        assert_eq!(lines, json!(["first\r", "second", "last"]));
+        let lines = contents_to_json(contents.to_string(), &test_options(&["--contents-as-lines", "--keep-line-endings"]));
+        assert_eq!(lines, json!(["first\r\n", "second\n", "last\n"]));
+        let joined: String = lines.as_array().unwrap().iter().map(|line| line.as_str().unwrap()).collect();
+        assert_eq!(joined, contents);
+    }
+
+    #[test]
+    fn skip_empty_contents_skips_empty_blobs() {
+        let blob_dir = test_dir("skip-empty-contents");
+        fs::write(blob_dir.join("empty.gz"), []).unwrap();
+        let row = json!({"blob_id": "empty", "src_encoding": "UTF-8"});
+
+        let config = test_config(&["--skip-empty-contents"]);
+        let blobs = BlobStore::open(blob_dir.clone(), &config).unwrap();
+        assert!(matches!(process_row(row.clone(), &blobs, &config).unwrap(), RowOutcome::Skip(SkipReason::EmptyContents)));
+
+        let config = test_config(&[]);
+        let blobs = BlobStore::open(blob_dir.clone(), &config).unwrap();
+        match process_row(row, &blobs, &config).unwrap() {
+            RowOutcome::Keep(row) => assert_eq!(row["contents"], json!("")),
+            _ => panic!("the empty blob should have been kept"),
+        }
+        fs::remove_dir_all(blob_dir).unwrap();
+    }
+
+    #[test]
+    fn skip_binary_skips_contents_with_a_nul() {
+        let config = test_config(&["--skip-binary"]);
+        let row = json!({"blob_id": "blob"});
+        assert!(matches!(finish_row(row.clone(), "PNG\0\0\0header".to_string(), &config), RowOutcome::Skip(SkipReason::Binary)));
+        match finish_row(row, "fn main() {\n\tprintln!(\"hi\");\r\n}\n".to_string(), &config) {
+            RowOutcome::Keep(row) => assert_eq!(row["contents"], json!("fn main() {\n\tprintln!(\"hi\");\r\n}\n")),
+            _ => panic!("plain text should have been kept"),
+        }
+    }
+
+    #[test]
+    fn on_missing_drops_or_keeps_missing_rows() {
+        let blob_dir = test_dir("on-missing-blobs");
+        fs::write(blob_dir.join("present.gz"), "hello\n").unwrap();
+        let rows = vec![json!({"blob_id": "present", "path": "a.py"}), json!({"blob_id": "gone", "path": "b.py"})];
+        for (policy, expected) in [
+            ("drop", vec![json!({"blob_id": "present", "path": "a.py", "contents": "hello\n"})]),
+            ("keep-null", vec![json!({"blob_id": "present", "path": "a.py", "contents": "hello\n"}),
+                               json!({"blob_id": "gone", "path": "b.py", "contents": null, "missing": true})]),
+        ] {
+            let out_dir = test_dir(&format!("on-missing-{}", policy));
+            let config = test_config(&["--on-missing", policy, "--max-missing-absolute", "1"]);
+            let blobs = BlobStore::open(blob_dir.clone(), &config).unwrap();
+            let row_fn = |row| process_row(row, &blobs, &config);
+            let stats = write_rows((rows.clone(), None), &out_dir, "Python".to_string(), "00000", &row_fn, None, &config).unwrap();
+            assert_eq!(stats.rows_missing, 1, "{}", policy);
+            assert_eq!(read_output_rows(&out_dir), expected, "{}", policy);
+            fs::remove_dir_all(out_dir).unwrap();
+        }
+        fs::remove_dir_all(blob_dir).unwrap();
+    }
+
+    #[test]
+    fn skip_languages_wins_over_languages() {
+        let input_dir = test_dir("language-filters");
+        for language in ["Python", "Rust", "Go"] {
+            let language_dir = input_dir.join("the-stack-v2/raw-hf-parquets").join(language);
+            fs::create_dir_all(&language_dir).unwrap();
+            fs::write(language_dir.join("train-00000-of-00001.parquet"), []).unwrap();
+        }
+        let discovered = discover_parquet_files(&input_dir).unwrap();
+        let selected = |languages: &[&str], skip_languages: &[&str]| -> Vec<String> {
+            let languages: Vec<String> = languages.iter().map(|language| language.to_string()).collect();
+            let skip_languages: Vec<String> = skip_languages.iter().map(|language| language.to_string()).collect();
+            discovered.iter()
+                .map(|pqt| extract_pqt_locations(pqt.clone()).unwrap().1)
+                .filter(|language| language_selected(language, &languages, &skip_languages))
+                .collect()
+        };
+        assert_eq!(selected(&[], &[]), ["Go", "Python", "Rust"]);
+        assert_eq!(selected(&["Python", "Rust"], &[]), ["Python", "Rust"]);
+        assert_eq!(selected(&[], &["Rust"]), ["Go", "Python"]);
+        assert_eq!(selected(&["Python", "Rust"], &["Rust"]), ["Python"]);
+        assert_eq!(selected(&["Rust"], &["Rust"]), Vec::<String>::new());
+        fs::remove_dir_all(input_dir).unwrap();
+    }
+
+    #[test]
+    fn target_file_bytes_renames_to_the_files_written() {
+        // Contents that don't compress away, so a small target rolls a file every few rows
+        let rows: Vec<JsonValue> = (0..20u64)
+            .map(|i| json!({"blob_id": format!("blob{}", i), "contents": format!("{:x}", i.wrapping_mul(0x9e3779b97f4a7c15)).repeat(8)}))
+            .collect();
+        for (max_lines, target_file_bytes) in [("10", "200"), ("2", "1000000")] {
+            let out_dir = test_dir(&format!("fix-file-totals-{}", max_lines));
+            let config = test_config(&["--max-lines", max_lines, "--target-file-bytes", target_file_bytes]);
+            let row_fn = |row| Ok(RowOutcome::Keep(row));
+            write_rows((rows.clone(), None), &out_dir, "Python".to_string(), "00000", &row_fn, None, &config).unwrap();
+
+            let mut names: Vec<String> = fs::read_dir(&out_dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+            names.sort();
+            let num_chunks = 20 / max_lines.parse::<usize>().unwrap();
+            assert_ne!(names.len(), num_chunks, "{:?}", names);
+            let expected: Vec<String> = (0..names.len()).map(|i| format!("Python-00000-{:06}-of-{:06}.jsonl.zstd", i, names.len())).collect();
+            assert_eq!(names, expected);
+            assert_eq!(read_output_rows(&out_dir), rows);
+            fs::remove_dir_all(out_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn partition_slugs() {
+        assert_eq!(partition_slug(&json!("MIT")), "mit");
+        assert_eq!(partition_slug(&json!("C++ / C#")), "cpp_csharp");
+        assert_eq!(partition_slug(&json!("  Apache-2.0 ")), "apache_2_0");
+        assert_eq!(partition_slug(&JsonValue::Null), "null");
+        assert_eq!(partition_slug(&json!("!!!")), "_");
+        assert_eq!(partition_slug(&json!(42)), "42");
+    }
+
+    #[test]
+    fn dedup_by_contents_needs_contents() {
+        let err = ProcessConfig::new(&test_options(&["--dedup-by", "contents", "--emit-raw-bytes", "instead"]), Path::new("out")).err().unwrap();
+        assert!(err.to_string().contains("--dedup-by contents"), "{}", err);
+        assert!(ProcessConfig::new(&test_options(&["--dedup-by", "blob-id", "--emit-raw-bytes", "instead"]), Path::new("out")).is_ok());
+    }
+
+    #[test]
+    fn recompress_replaces_gz_with_an_equivalent_zst() {
+        let blob_dir = test_dir("recompress");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"print('hi')\n").unwrap();
+        fs::write(blob_dir.join("blob.gz"), encoder.finish().unwrap()).unwrap();
+        recompress_blobs(&blob_dir, 3, true).unwrap();
+        assert!(!blob_dir.join("blob.gz").exists());
+        assert_eq!(zstd::decode_all(fs::read(blob_dir.join("blob.zst")).unwrap().as_slice()).unwrap(), b"print('hi')\n");
+        assert_eq!(fs::read_dir(&blob_dir).unwrap().count(), 1, "no temp file left behind");
+        fs::remove_dir_all(blob_dir).unwrap();
+    }
 
+    #[test]
+    fn retry_failed_rejects_flags_that_conflict_with_single_file() {
+        for args in [&["--resume"][..], &["--verify-roundtrip"], &["--compression-unit", "chunk"]] {
+            let err = retry_failed_rows(Path::new("test.parquet"), Path::new("failed.jsonl"), Path::new("out"), test_config(args)).unwrap_err();
+            assert!(err.to_string().contains("retry-failed"), "{:?}: {}", args, err);
+        }
+    }
+}
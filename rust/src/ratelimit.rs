@@ -0,0 +1,43 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+
+/*==============================================================
+=                         RATE LIMITING                        =
+==============================================================*/
+
+/// Token bucket shared by every worker thread. Refills at `rate` tokens/sec and holds at most
+/// one second's worth, so bursts after an idle spell are bounded too.
+/// A caller may take more tokens than are in the bucket; it then sleeps off the debt,
+/// which lets one call pay for a whole (variable-sized) read
+pub(crate) struct RateLimiter {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        RateLimiter { rate, state: Mutex::new(BucketState { tokens: rate, last_refill: Instant::now() }) }
+    }
+
+    pub fn acquire(&self, cost: f64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(state.last_refill).as_secs_f64() * self.rate;
+            state.tokens = (state.tokens + refill).min(self.rate);
+            state.last_refill = now;
+            state.tokens -= cost;
+            if state.tokens < 0.0 { -state.tokens / self.rate } else { 0.0 }
+        };
+        // Sleep outside the lock: our tokens are already reserved, so other threads queue up behind us
+        if wait > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
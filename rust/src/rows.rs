@@ -0,0 +1,237 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::str::FromStr;
+use anyhow::{Result, Error};
+use rayon::prelude::*;
+use serde_json::Value as JsonValue;
+
+
+/*==============================================================
+=                         SORTING ROWS                         =
+==============================================================*/
+
+/// Parsed form of `--sort-by <column>[:asc|desc]`
+#[derive(Debug, Clone)]
+pub(crate) struct SortSpec {
+    pub column: String,
+    pub descending: bool,
+}
+
+impl FromStr for SortSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (column, descending) = match s.rsplit_once(':') {
+            Some((column, "asc")) => (column, false),
+            Some((column, "desc")) => (column, true),
+            Some((_, direction)) => return Err(Error::msg(format!("Unknown sort direction {:?}, expected asc or desc", direction))),
+            None => (s, false),
+        };
+        if column.is_empty() {
+            return Err(Error::msg("Sort column can't be empty"));
+        }
+        Ok(SortSpec { column: column.to_string(), descending })
+    }
+}
+
+
+fn type_rank(value: &JsonValue) -> u8 {
+    // So that values of differing types still have a total (deterministic) order
+    match value {
+        JsonValue::Null => 0,
+        JsonValue::Bool(_) => 1,
+        JsonValue::Number(_) => 2,
+        JsonValue::String(_) => 3,
+        JsonValue::Array(_) => 4,
+        JsonValue::Object(_) => 5,
+    }
+}
+
+
+pub(crate) fn compare_json(a: &JsonValue, b: &JsonValue) -> Ordering {
+    match (a, b) {
+        (JsonValue::Bool(a), JsonValue::Bool(b)) => a.cmp(b),
+        (JsonValue::Number(a), JsonValue::Number(b)) => {
+            a.as_f64().unwrap_or(f64::NAN).total_cmp(&b.as_f64().unwrap_or(f64::NAN))
+        },
+        (JsonValue::String(a), JsonValue::String(b)) => a.cmp(b),
+        _ => type_rank(a).cmp(&type_rank(b)).then_with(|| a.to_string().cmp(&b.to_string())),
+    }
+}
+
+
+pub(crate) fn sort_rows(rows: &mut [JsonValue], spec: &SortSpec) {
+    /* Stable sort of rows by the spec'd column. 
+    Rows where the column is missing or null always go last (regardless of direction)
+    */
+    rows.par_sort_by(|a, b| {
+        let a = a.get(&spec.column).filter(|v| !v.is_null());
+        let b = b.get(&spec.column).filter(|v| !v.is_null());
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                let ordering = compare_json(a, b);
+                if spec.descending { ordering.reverse() } else { ordering }
+            },
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    });
+}
+
+
+
+/*==============================================================
+=                        SHUFFLING ROWS                        =
+==============================================================*/
+
+/// splitmix64: tiny, and (unlike a rand crate's default rng) its output for a seed will never change under us
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+
+pub(crate) fn shuffle_rows(rows: &mut [JsonValue], seed: u64) {
+    // Fisher-Yates, single threaded so the permutation only depends on the seed and the number of rows
+    let mut rng = SplitMix64(seed);
+    for i in (1..rows.len()).rev() {
+        let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+        rows.swap(i, j);
+    }
+}
+
+
+
+/*==============================================================
+=                         GROUPING ROWS                        =
+==============================================================*/
+
+pub(crate) fn group_rows(rows: &mut [JsonValue], column: &str) -> Vec<usize> {
+    /* Stable reorder so that rows with equal values of column are contiguous.
+    Groups appear in the order their first row did (and rows missing the column form the last group).
+    Returns where each row was before, by its new position
+    */
+    let mut group_order: HashMap<String, usize> = HashMap::new();
+    let keys: Vec<usize> = rows.iter()
+        .map(|row| match row.get(column).filter(|v| !v.is_null()) {
+            Some(value) => {
+                let next_group = group_order.len();
+                *group_order.entry(value.to_string()).or_insert(next_group)
+            },
+            None => usize::MAX,
+        })
+        .collect();
+
+    let mut keyed: Vec<(usize, usize, JsonValue)> = keys.into_iter()
+        .zip(rows.iter_mut().map(|row| row.take()).enumerate())
+        .map(|(key, (position, row))| (key, position, row))
+        .collect();
+    keyed.par_sort_by_key(|(key, _, _)| *key);
+    let mut original_order = Vec::with_capacity(keyed.len());
+    for (slot, (_, position, row)) in rows.iter_mut().zip(keyed) {
+        *slot = row;
+        original_order.push(position);
+    }
+    original_order
+}
+
+
+
+/*==============================================================
+=                       PARTITIONING ROWS                      =
+==============================================================*/
+
+pub(crate) fn split_rows_by_column(rows: Vec<JsonValue>, column: &str, default: &str) -> Vec<(String, Vec<JsonValue>)> {
+    /* Splits rows into groups keyed by the (string) value of column, in order of first appearance.
+    Rows where column is missing/null/not a string are keyed by default
+    */
+    split_rows_by(rows, |row| row.get(column).and_then(|v| v.as_str()).unwrap_or(default).to_string())
+}
+
+
+pub(crate) fn split_rows_by<K: Clone + Eq + Hash>(rows: Vec<JsonValue>, key_of: impl Fn(&JsonValue) -> K) -> Vec<(K, Vec<JsonValue>)> {
+    // Splits rows into groups by key_of, in order of first appearance, preserving row order within each group
+    let mut group_index: HashMap<K, usize> = HashMap::new();
+    let mut groups: Vec<(K, Vec<JsonValue>)> = Vec::new();
+    for row in rows {
+        let key = key_of(&row);
+        let index = *group_index.entry(key.clone()).or_insert_with(|| {
+            groups.push((key, Vec::new()));
+            groups.len() - 1
+        });
+        groups[index].1.push(row);
+    }
+    groups
+}
+
+
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    // FNV-1a: unlike std's DefaultHasher, guaranteed not to change between builds/rust versions
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+
+pub(crate) fn partition_rows(rows: Vec<JsonValue>, num_partitions: u64) -> Vec<Vec<JsonValue>> {
+    // Routes each row to partition hash(blob_id) % num_partitions, preserving row order within each partition
+    let mut partitions: Vec<Vec<JsonValue>> = (0..num_partitions).map(|_| Vec::new()).collect();
+    for row in rows {
+        let blob_id = row["blob_id"].as_str().unwrap_or_default();
+        let partition = stable_hash(blob_id.as_bytes()) % num_partitions;
+        partitions[partition as usize].push(row);
+    }
+    partitions
+}
+
+
+
+/*==============================================================
+=                         SAMPLING ROWS                        =
+==============================================================*/
+
+/// Reservoir sample of the serialized rows that made it into the outputs (for --sample-out).
+/// Rows are offered in output order, so for a given seed the sample doesn't depend on --threads
+pub(crate) struct RowSampler {
+    capacity: usize,
+    seen: u64,
+    rng: SplitMix64,
+    samples: Vec<Vec<u8>>,
+}
+
+impl RowSampler {
+    pub fn new(capacity: usize, seed: u64) -> Self {
+        RowSampler { capacity, seen: 0, rng: SplitMix64(seed), samples: Vec::with_capacity(capacity) }
+    }
+
+    pub fn offer(&mut self, row: &[u8]) {
+        // Algorithm R: the n-th row replaces a random sample with probability capacity/n
+        self.seen += 1;
+        if self.samples.len() < self.capacity {
+            self.samples.push(row.to_vec());
+            return;
+        }
+        let j = (self.rng.next_u64() % self.seen) as usize;
+        if j < self.capacity {
+            self.samples[j] = row.to_vec();
+        }
+    }
+
+    pub fn samples(&self) -> Vec<JsonValue> {
+        self.samples.iter()
+            .filter_map(|row| serde_json::from_slice(row).ok())
+            .collect()
+    }
+}
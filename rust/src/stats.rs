@@ -0,0 +1,256 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Result, Error};
+use arrow::array::{ArrayRef, Int64Array, StringArray};
+use arrow::record_batch::RecordBatch;
+use serde_json::{json, Value as JsonValue};
+
+
+/*==============================================================
+=                        PROCESSING STATS                      =
+==============================================================*/
+
+/// Why a row we could read was deliberately left out of the output (as opposed to missing)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum SkipReason {
+    InvalidUtf8,
+    EmptyContents,
+    WhitespaceOnly,
+    Binary,
+    Duplicate,
+    TooShort,
+}
+
+impl SkipReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::InvalidUtf8 => "invalid_utf8",
+            SkipReason::EmptyContents => "empty_contents",
+            SkipReason::WhitespaceOnly => "whitespace_only",
+            SkipReason::Binary => "binary",
+            SkipReason::Duplicate => "duplicate",
+            SkipReason::TooShort => "too_short",
+        }
+    }
+}
+
+
+/// Running totals for one parquet file (or a whole batch of them, via merge)
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ProcessStats {
+    pub rows_written: usize,
+    pub rows_missing: usize,
+    pub rows_corrupt: usize,
+    pub rows_decode_failed: usize,
+    pub rows_skipped: BTreeMap<SkipReason, usize>,
+    pub chunks_written: usize,
+    pub bytes_contents: usize, // decoded (and transformed) contents of the rows written, as utf-8
+    pub bytes_uncompressed: usize,
+    pub bytes_compressed: usize,
+    pub rows_by_partition: BTreeMap<String, usize>, // --partition-by value -> rows written
+    pub rows_declared: usize, // what the parquet metadata says it has
+    pub rows_filtered: usize, // dropped up front by --blob-ids-file/--blob-id-regex
+    pub rows_resumed: usize, // in chunks --resume found already written
+}
+
+impl ProcessStats {
+    pub fn merge(&mut self, other: &ProcessStats) {
+        self.rows_written += other.rows_written;
+        self.rows_missing += other.rows_missing;
+        self.rows_corrupt += other.rows_corrupt;
+        self.rows_decode_failed += other.rows_decode_failed;
+        for (reason, count) in &other.rows_skipped {
+            *self.rows_skipped.entry(*reason).or_insert(0) += count;
+        }
+        self.chunks_written += other.chunks_written;
+        self.bytes_contents += other.bytes_contents;
+        self.bytes_uncompressed += other.bytes_uncompressed;
+        self.bytes_compressed += other.bytes_compressed;
+        for (partition, rows) in &other.rows_by_partition {
+            *self.rows_by_partition.entry(partition.clone()).or_insert(0) += rows;
+        }
+        self.rows_declared += other.rows_declared;
+        self.rows_filtered += other.rows_filtered;
+        self.rows_resumed += other.rows_resumed;
+    }
+
+    pub fn record_skip(&mut self, reason: SkipReason) {
+        *self.rows_skipped.entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn total_skipped(&self) -> usize {
+        self.rows_skipped.values().sum()
+    }
+
+    pub fn rows_accounted(&self, missing_written: bool) -> usize {
+        // Every input row ends up in exactly one of these. With --on-missing keep-null, missing rows are also written
+        let rows_missing = if missing_written { 0 } else { self.rows_missing };
+        self.rows_written + rows_missing + self.rows_corrupt + self.rows_decode_failed + self.total_skipped() + self.rows_filtered + self.rows_resumed
+    }
+
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.bytes_compressed == 0 {
+            None
+        } else {
+            Some(self.bytes_uncompressed as f64 / self.bytes_compressed as f64)
+        }
+    }
+
+    pub fn missing_rate(&self) -> f64 {
+        // Fraction of rows we tried to read that were missing (skipped rows were read fine, so they count as read)
+        let rows_read = self.rows_written + self.rows_missing + self.rows_corrupt + self.rows_decode_failed + self.total_skipped();
+        if rows_read == 0 { 0.0 } else { self.rows_missing as f64 / rows_read as f64 }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let rows_skipped: serde_json::Map<String, JsonValue> = self.rows_skipped.iter()
+            .map(|(reason, count)| (reason.as_str().to_string(), json!(count)))
+            .collect();
+        let mut stats = json!({
+            "rows_written": self.rows_written,
+            "rows_missing": self.rows_missing,
+            "rows_corrupt": self.rows_corrupt,
+            "rows_decode_failed": self.rows_decode_failed,
+            "rows_skipped": rows_skipped,
+            "missing_rate": self.missing_rate(),
+            "chunks_written": self.chunks_written,
+            "bytes_contents": self.bytes_contents,
+            "bytes_uncompressed": self.bytes_uncompressed,
+            "bytes_compressed": self.bytes_compressed,
+            "compression_ratio": self.compression_ratio(),
+            "rows_declared": self.rows_declared,
+            "rows_filtered": self.rows_filtered,
+            "rows_resumed": self.rows_resumed,
+        });
+        if !self.rows_by_partition.is_empty() {
+            stats["rows_by_partition"] = json!(self.rows_by_partition);
+        }
+        stats
+    }
+}
+
+
+
+/// --verify-reencode totals for one src_encoding: of the sampled rows, how many re-encoded back to their exact blob bytes.
+/// unsupported counts rows whose encoding has no encoder to check with
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ReencodeCounts {
+    pub checked: usize,
+    pub matched: usize,
+    pub unsupported: usize,
+}
+
+impl ReencodeCounts {
+    pub fn match_rate(&self) -> Option<f64> {
+        (self.checked > 0).then(|| self.matched as f64 / self.checked as f64)
+    }
+}
+
+
+/// Parsed form of `--max-decode-errors`: a row count (e.g. `50`), or a fraction of the rows read (e.g. `0.01`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ErrorLimit {
+    Count(usize),
+    Fraction(f64),
+}
+
+impl ErrorLimit {
+    pub fn exceeded(&self, errors: usize, rows: usize) -> bool {
+        match *self {
+            ErrorLimit::Count(max_errors) => errors > max_errors,
+            ErrorLimit::Fraction(max_fraction) => rows > 0 && errors as f64 / rows as f64 > max_fraction,
+        }
+    }
+}
+
+impl FromStr for ErrorLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let s = s.trim();
+        if let Ok(count) = s.parse::<usize>() {
+            return Ok(ErrorLimit::Count(count));
+        }
+        match s.parse::<f64>() {
+            Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(ErrorLimit::Fraction(fraction)),
+            _ => Err(Error::msg(format!("Bad error limit {:?}, expected a row count (e.g. 50) or a fraction in [0, 1] (e.g. 0.01)", s))),
+        }
+    }
+}
+
+
+/// The --report-top-slowest-rows slowest rows seen so far, slowest first once read out.
+/// A min-heap on time, so the fastest of the kept rows is the one to evict
+pub(crate) struct SlowestRows {
+    capacity: usize,
+    heap: BinaryHeap<Reverse<(Duration, String)>>,
+}
+
+impl SlowestRows {
+    pub fn new(capacity: usize) -> Self {
+        SlowestRows { capacity, heap: BinaryHeap::with_capacity(capacity + 1) }
+    }
+
+    pub fn offer(&mut self, elapsed: Duration, blob_id: String) {
+        if self.heap.len() == self.capacity && self.heap.peek().is_some_and(|Reverse((fastest, _))| elapsed <= *fastest) {
+            return;
+        }
+        self.heap.push(Reverse((elapsed, blob_id)));
+        if self.heap.len() > self.capacity {
+            self.heap.pop();
+        }
+    }
+
+    pub fn merge(&mut self, other: SlowestRows) {
+        for Reverse((elapsed, blob_id)) in other.heap {
+            self.offer(elapsed, blob_id);
+        }
+    }
+
+    pub fn sorted(&self) -> Vec<(Duration, String)> {
+        let mut rows: Vec<(Duration, String)> = self.heap.iter().map(|Reverse(row)| row.clone()).collect();
+        rows.sort_by(|a, b| b.cmp(a));
+        rows
+    }
+}
+
+
+
+/*==============================================================
+=                        PER-FILE STATS                        =
+==============================================================*/
+
+/// One row of --stats-parquet: how a single parquet file went.
+/// bytes_in is the parquet's size on disk, bytes_out the compressed jsonl bytes written for it
+#[derive(Debug, Clone)]
+pub(crate) struct FileStatsRow {
+    pub parquet: String,
+    pub language: String,
+    pub shard: String,
+    pub rows: usize,
+    pub missing: usize,
+    pub bytes_in: u64,
+    pub bytes_out: usize,
+    pub duration_ms: u128,
+    pub status: &'static str,
+}
+
+
+pub(crate) fn file_stats_batch(rows: &[FileStatsRow]) -> Result<RecordBatch, Error> {
+    let strings = |f: fn(&FileStatsRow) -> &str| Arc::new(StringArray::from_iter_values(rows.iter().map(f))) as ArrayRef;
+    let ints = |f: fn(&FileStatsRow) -> i64| Arc::new(Int64Array::from_iter_values(rows.iter().map(f))) as ArrayRef;
+    Ok(RecordBatch::try_from_iter(vec![
+        ("parquet", strings(|row| &row.parquet)),
+        ("language", strings(|row| &row.language)),
+        ("shard", strings(|row| &row.shard)),
+        ("rows", ints(|row| row.rows as i64)),
+        ("missing", ints(|row| row.missing as i64)),
+        ("bytes_in", ints(|row| row.bytes_in as i64)),
+        ("bytes_out", ints(|row| row.bytes_out as i64)),
+        ("duration_ms", ints(|row| row.duration_ms as i64)),
+        ("status", strings(|row| row.status)),
+    ])?)
+}